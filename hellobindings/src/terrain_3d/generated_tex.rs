@@ -72,6 +72,18 @@ impl GeneratedTex {
         self.dirty = true;
     }
 
+    /**
+     * Replaces one layer of the texture array in place, avoiding a full re-upload. The
+     * image must match the size and format of the existing layers.
+     */
+    pub fn update_layer(&mut self, p_image: Gd<Image>, p_layer: i32) {
+        if !self.rid.is_valid() {
+            return;
+        }
+        log_debug!(Self, "GeneratedTex updating layer {} of {}", p_layer, self.rid);
+        rs().texture_2d_update(self.rid, p_image, p_layer);
+    }
+
     pub fn dirty(&self) -> bool {
         self.dirty
     }
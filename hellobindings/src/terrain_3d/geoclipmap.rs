@@ -1,4 +1,4 @@
-use crate::{log_debug, log_info};
+use crate::{log_debug, log_error, log_info};
 use godot::engine::mesh::ArrayType;
 use godot::engine::rendering_server::PrimitiveType;
 use godot::prelude::*;
@@ -7,6 +7,14 @@ use super::utils::rs;
 
 pub struct GeoClipMap {}
 
+// Geometry of a single clipmap mesh, before it is handed to the RenderingServer.
+// Packed arrays are not Send, so the data is kept in Vecs until then
+struct MeshData {
+    vertices: Vec<Vector3>,
+    indices: Vec<i32>,
+    aabb: Aabb,
+}
+
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var)]
 #[repr(usize)]
 pub enum MeshType {
@@ -15,6 +23,7 @@ pub enum MeshType {
     TRIM = 2,
     CROSS = 3,
     SEAM = 4,
+    SKIRT = 5,
 }
 
 impl MeshType {
@@ -25,6 +34,18 @@ impl MeshType {
 
 impl GeoClipMap {
     const __CLASS__: &'static str = "Terrain3DGeoClipMap";
+    pub const MIN_MESH_SIZE: i32 = 16;
+    // Distance the skirt mesh reaches from its center, past any view distance
+    const SKIRT_EXTENT: f32 = 10_000_000.0;
+    // Builders of the geometry of each clipmap mesh, in MeshType order
+    const BUILDERS: [fn(usize, usize) -> MeshData; 6] = [
+        Self::tile_data,
+        Self::filler_data,
+        Self::trim_data,
+        Self::cross_data,
+        Self::seam_data,
+        Self::skirt_data,
+    ];
 
     #[inline]
     fn patch_2d(x: usize, y: usize, res: usize) -> i32 {
@@ -66,421 +87,558 @@ impl GeoClipMap {
         mesh
     }
 
-    pub fn generate(p_size: i32, p_levels: i32) -> Vec<Rid> {
-        log_info!(
-            Self,
-            "Generating meshes of size: {p_size}, levels: {p_levels}"
-        );
-        let tile_resolution = p_size as usize;
-        let patch_vert_resolution = tile_resolution + 1;
-        let clipmap_resolution = tile_resolution * 4 + 1;
-        let clipmap_vert_resolution = clipmap_resolution + 1;
-        let _num_clipmap_levels = p_levels as usize;
-        let mut n = 0usize;
-
-        // Create a tile mesh
-        // A tile is the main component of terrain panels
-        // LOD0: 4 tiles are placed as a square in each center quadrant, for a total of 16 tiles
-        // LOD1..N 3 tiles make up a corner, 4 corners uses 12 tiles
-
-        let (aabb, tile_mesh) = {
-            let mut vertices = PackedVector3Array::default();
-            vertices.resize(patch_vert_resolution * patch_vert_resolution);
-            let mut indices = PackedInt32Array::default();
-            indices.resize(tile_resolution * tile_resolution * 6);
-            n = 0;
-
-            let vertices_mut = vertices.as_mut_slice();
-            for y in 0..patch_vert_resolution {
-                for x in 0..patch_vert_resolution {
-                    vertices_mut[n].x = x as f32;
-                    vertices_mut[n].y = 0.0;
-                    vertices_mut[n].z = y as f32;
+    /**
+     * Creates a flat grid mesh of p_size quads, one unit apart, starting at the origin.
+     */
+    pub fn generate_plane(p_size: Vector2i) -> Rid {
+        log_info!(Self, "Generating plane mesh of size: {}", p_size);
+        let res_x = p_size.x.max(1) as usize;
+        let res_z = p_size.y.max(1) as usize;
+        let vert_res_x = res_x + 1;
+
+        let mut vertices = PackedVector3Array::default();
+        vertices.resize(vert_res_x * (res_z + 1));
+        let vertices_mut = vertices.as_mut_slice();
+        let mut n = 0;
+        for z in 0..=res_z {
+            for x in 0..=res_x {
+                vertices_mut[n] = Vector3::new(x as f32, 0.0, z as f32);
+                n += 1;
+            }
+        }
+
+        let mut indices = PackedInt32Array::default();
+        indices.resize(res_x * res_z * 6);
+        let indices_mut = indices.as_mut_slice();
+        n = 0;
+        for z in 0..res_z {
+            for x in 0..res_x {
+                for idx in [
+                    Self::patch_2d(x, z, vert_res_x),
+                    Self::patch_2d(x + 1, z + 1, vert_res_x),
+                    Self::patch_2d(x, z + 1, vert_res_x),
+                    Self::patch_2d(x, z, vert_res_x),
+                    Self::patch_2d(x + 1, z, vert_res_x),
+                    Self::patch_2d(x + 1, z + 1, vert_res_x),
+                ] {
+                    indices_mut[n] = idx;
                     n += 1;
                 }
             }
+        }
 
-            n = 0;
-            let indices_mut = indices.as_mut_slice();
-            for y in 0..tile_resolution {
-                for x in 0..tile_resolution {
-                    indices_mut[n] = Self::patch_2d(x, y, patch_vert_resolution);
-                    n += 1;
-                    indices_mut[n] = Self::patch_2d(x + 1, y + 1, patch_vert_resolution);
-                    n += 1;
-                    indices_mut[n] = Self::patch_2d(x, y + 1, patch_vert_resolution);
-                    n += 1;
+        let aabb = Aabb::new(
+            Vector3::ZERO,
+            Vector3::new(vert_res_x as f32, 0.1, (res_z + 1) as f32),
+        );
+        Self::create_mesh(vertices, indices, &aabb)
+    }
 
-                    indices_mut[n] = Self::patch_2d(x, y, patch_vert_resolution);
-                    n += 1;
-                    indices_mut[n] = Self::patch_2d(x + 1, y, patch_vert_resolution);
-                    n += 1;
-                    indices_mut[n] = Self::patch_2d(x + 1, y + 1, patch_vert_resolution);
-                    n += 1;
-                }
-            }
+    /**
+     * Whether p_size can be used as the clipmap tile size. The clipmap places tiles around
+     * a center built from half a tile, so p_size must be even, and sizes below
+     * MIN_MESH_SIZE leave the trim and seam meshes degenerate.
+     */
+    pub fn is_valid_size(p_size: i32) -> bool {
+        p_size >= Self::MIN_MESH_SIZE && p_size % 2 == 0
+    }
 
-            let aabb = Aabb::new(
-                Vector3::ZERO,
-                Vector3::new(
-                    patch_vert_resolution as f32,
-                    0.1,
-                    patch_vert_resolution as f32,
-                ),
+    /**
+     * Generates the clipmap meshes, in MeshType order. Returns no meshes if p_size is not
+     * valid, see is_valid_size(), or p_levels is below 1. The geometry of each mesh is built
+     * on its own thread, the meshes are then created on the calling thread.
+     */
+    pub fn generate(p_size: i32, p_levels: i32) -> Vec<Rid> {
+        if !Self::is_valid_size(p_size) || p_levels < 1 {
+            log_error!(
+                Self,
+                "Invalid mesh size {p_size} or levels {p_levels}. Size must be even and at least {}, levels at least 1",
+                Self::MIN_MESH_SIZE
             );
-            let tile_mesh = Self::create_mesh(vertices, indices, &aabb);
-
-            (aabb, tile_mesh)
-        };
-
-        // Create a filler mesh
-        // These meshes are small strips that fill in the gaps between LOD1+,
-        // but only on the camera X and Z axes, and not on LOD0.
-        let filler_mesh = {
-            let mut vertices = PackedVector3Array::default();
-            vertices.resize(patch_vert_resolution * 8);
-            let mut indices = PackedInt32Array::default();
-            indices.resize(tile_resolution * 24);
-            n = 0;
-            let offset = tile_resolution;
-
-            let vertices_mut = vertices.as_mut_slice();
-            for i in 0..patch_vert_resolution {
-                vertices_mut[n].x = (offset + i + 1) as f32;
-                vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = 0.0;
-                aabb.expand(vertices_mut[n]);
-                n += 1;
+            return Vec::new();
+        }
+        log_info!(
+            Self,
+            "Generating meshes of size: {p_size}, levels: {p_levels}"
+        );
+        let [tile, filler, trim, cross, seam, skirt] = Self::generate_data(p_size as usize, p_levels as usize);
+
+        // The tile, filler, trim, cross and seam meshes share a custom aabb that grows to
+        // cover each mesh in turn
+        let mut aabb = tile.aabb;
+        let mut meshes = Vec::with_capacity(6);
+        for data in [tile, filler, trim, cross, seam] {
+            aabb.expand(data.aabb.position);
+            aabb.expand(data.aabb.position + data.aabb.size);
+            meshes.push(Self::create_mesh_from_data(data, &aabb));
+        }
+        let skirt_aabb = skirt.aabb;
+        meshes.push(Self::create_mesh_from_data(skirt, &skirt_aabb));
+        meshes
+    }
 
-                vertices_mut[n].x = (offset + i + 1) as f32;
-                vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = 1.0;
-                aabb.expand(vertices_mut[n]);
-                n += 1;
-            }
+    // Geometry only uses plain math types, so it is safe to build off the main thread.
+    // Server calls must stay on this one
+    fn generate_data(p_tile_resolution: usize, p_levels: usize) -> [MeshData; 6] {
+        std::thread::scope(|s| {
+            Self::BUILDERS
+                .map(|builder| s.spawn(move || builder(p_tile_resolution, p_levels)))
+                .map(|handle| handle.join().expect("clipmap mesh generation panicked"))
+        })
+    }
 
-            for i in 0..patch_vert_resolution {
-                vertices_mut[n].x = 1.0;
-                vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = (offset + i + 1) as f32;
-                aabb.expand(vertices_mut[n]);
-                n += 1;
+    fn create_mesh_from_data(p_data: MeshData, p_aabb: &Aabb) -> Rid {
+        let vertices = PackedVector3Array::from(p_data.vertices.as_slice());
+        let indices = PackedInt32Array::from(p_data.indices.as_slice());
+        Self::create_mesh(vertices, indices, p_aabb)
+    }
 
-                vertices_mut[n].x = 0.0;
-                vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = (offset + i + 1) as f32;
-                aabb.expand(vertices_mut[n]);
-                n += 1;
-            }
+    /**
+     * Builds the tile mesh, the main component of terrain panels.
+     * LOD0: 4 tiles are placed as a square in each center quadrant, for a total of 16 tiles
+     * LOD1..N 3 tiles make up a corner, 4 corners uses 12 tiles
+     */
+    fn tile_data(p_tile_resolution: usize, _p_levels: usize) -> MeshData {
+        let tile_resolution = p_tile_resolution;
+        let patch_vert_resolution = tile_resolution + 1;
 
-            for i in 0..patch_vert_resolution {
-                vertices_mut[n].x = -((offset + i) as f32);
-                vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = 1.0;
-                aabb.expand(vertices_mut[n]);
-                n += 1;
+        let mut vertices = vec![Vector3::ZERO; patch_vert_resolution * patch_vert_resolution];
+        let mut indices = vec![0; tile_resolution * tile_resolution * 6];
+        let mut n = 0;
 
-                vertices_mut[n].x = -((offset + i) as f32);
+        let vertices_mut = vertices.as_mut_slice();
+        for y in 0..patch_vert_resolution {
+            for x in 0..patch_vert_resolution {
+                vertices_mut[n].x = x as f32;
                 vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = 0.0;
-                aabb.expand(vertices_mut[n]);
+                vertices_mut[n].z = y as f32;
                 n += 1;
             }
+        }
 
-            for i in 0..patch_vert_resolution {
-                vertices_mut[n].x = 0.0;
-                vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = -((offset + i) as f32);
-                aabb.expand(vertices_mut[n]);
+        n = 0;
+        let indices_mut = indices.as_mut_slice();
+        for y in 0..tile_resolution {
+            for x in 0..tile_resolution {
+                indices_mut[n] = Self::patch_2d(x, y, patch_vert_resolution);
                 n += 1;
-
-                vertices_mut[n].x = 1.0;
-                vertices_mut[n].y = 0.0;
-                vertices_mut[n].z = -((offset + i) as f32);
-                aabb.expand(vertices_mut[n]);
+                indices_mut[n] = Self::patch_2d(x + 1, y + 1, patch_vert_resolution);
                 n += 1;
-            }
-
-            n = 0;
-            let indices_mut = indices.as_mut_slice();
-            for i in 0..(tile_resolution * 4) as i32 {
-                let arm = i / tile_resolution as i32;
-                let bl = (arm + i) * 2 + 0;
-                let br = (arm + i) * 2 + 1;
-                let tl = (arm + i) * 2 + 2;
-                let tr = (arm + i) * 2 + 3;
-
-                if arm % 2 == 0 {
-                    indices_mut[n] = br;
-                    n += 1;
-                    indices_mut[n] = bl;
-                    n += 1;
-                    indices_mut[n] = tr;
-                    n += 1;
-                    indices_mut[n] = bl;
-                    n += 1;
-                    indices_mut[n] = tl;
-                    n += 1;
-                    indices_mut[n] = tr;
-                    n += 1;
-                } else {
-                    indices_mut[n] = br;
-                    n += 1;
-                    indices_mut[n] = bl;
-                    n += 1;
-                    indices_mut[n] = tl;
-                    n += 1;
-                    indices_mut[n] = br;
-                    n += 1;
-                    indices_mut[n] = tl;
-                    n += 1;
-                    indices_mut[n] = tr;
-                    n += 1;
-                }
-            }
-
-            // Filler mesh
-            Self::create_mesh(vertices, indices, &aabb)
-        };
-
-        // Create trim mesh
-        // This mesh is a skinny L shape that fills in the gaps between
-        // LOD meshes when they are moving at different speeds and have gaps
-        let trim_mesh = {
-            let mut vertices = PackedVector3Array::default();
-            vertices.resize((clipmap_vert_resolution * 2 + 1) * 2);
-            let mut indices = PackedInt32Array::default();
-            indices.resize((clipmap_vert_resolution * 2 - 1) * 6);
-            n = 0;
-            let offset = Vector3::new(
-                0.5 * (clipmap_vert_resolution + 1) as f32,
-                0.0,
-                0.5 * (clipmap_vert_resolution + 1) as f32,
-            );
-            let vertices_mut = vertices.as_mut_slice();
-
-            for i in 0..(clipmap_vert_resolution + 1) {
-                vertices_mut[n] =
-                    Vector3::new(0.0, 0.0, (clipmap_vert_resolution - i) as f32) - offset;
-                aabb.expand(vertices_mut[n]);
+                indices_mut[n] = Self::patch_2d(x, y + 1, patch_vert_resolution);
                 n += 1;
 
-                vertices_mut[n] =
-                    Vector3::new(1.0, 0.0, (clipmap_vert_resolution - i) as f32) - offset;
-                aabb.expand(vertices_mut[n]);
+                indices_mut[n] = Self::patch_2d(x, y, patch_vert_resolution);
                 n += 1;
-            }
-
-            let start_of_horizontal = n as i32;
-            for i in 0..clipmap_vert_resolution {
-                vertices_mut[n] = Vector3::new((i + 1) as f32, 0.0, 0.0) - offset;
-                aabb.expand(vertices_mut[n]);
+                indices_mut[n] = Self::patch_2d(x + 1, y, patch_vert_resolution);
                 n += 1;
-
-                vertices_mut[n] = Vector3::new((i + 1) as f32, 0.0, 1.0) - offset;
-                aabb.expand(vertices_mut[n]);
+                indices_mut[n] = Self::patch_2d(x + 1, y + 1, patch_vert_resolution);
                 n += 1;
             }
+        }
+
+        let aabb = Aabb::new(
+            Vector3::ZERO,
+            Vector3::new(
+                patch_vert_resolution as f32,
+                0.1,
+                patch_vert_resolution as f32,
+            ),
+        );
+        MeshData { vertices, indices, aabb }
+    }
 
-            n = 0;
-            let indices_mut = indices.as_mut_slice();
-            for i in 0..clipmap_vert_resolution as i32 {
-                indices_mut[n] = (i + 0) * 2 + 1;
-                n += 1;
-                indices_mut[n] = (i + 0) * 2 + 0;
-                n += 1;
-                indices_mut[n] = (i + 1) * 2 + 0;
-                n += 1;
+    /**
+     * Builds the filler mesh, small strips that fill in the gaps between LOD1+,
+     * but only on the camera X and Z axes, and not on LOD0.
+     */
+    fn filler_data(p_tile_resolution: usize, _p_levels: usize) -> MeshData {
+        let tile_resolution = p_tile_resolution;
+        let patch_vert_resolution = tile_resolution + 1;
 
-                indices_mut[n] = (i + 1) * 2 + 1;
-                n += 1;
-                indices_mut[n] = (i + 0) * 2 + 1;
-                n += 1;
-                indices_mut[n] = (i + 1) * 2 + 0;
+        let mut vertices = vec![Vector3::ZERO; patch_vert_resolution * 8];
+        let mut indices = vec![0; tile_resolution * 24];
+        let mut aabb = Aabb::new(Vector3::ZERO, Vector3::ZERO);
+        let mut n = 0;
+        let offset = tile_resolution;
+
+        let vertices_mut = vertices.as_mut_slice();
+        for i in 0..patch_vert_resolution {
+            vertices_mut[n].x = (offset + i + 1) as f32;
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = 0.0;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n].x = (offset + i + 1) as f32;
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = 1.0;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        for i in 0..patch_vert_resolution {
+            vertices_mut[n].x = 1.0;
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = (offset + i + 1) as f32;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n].x = 0.0;
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = (offset + i + 1) as f32;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        for i in 0..patch_vert_resolution {
+            vertices_mut[n].x = -((offset + i) as f32);
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = 1.0;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n].x = -((offset + i) as f32);
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = 0.0;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        for i in 0..patch_vert_resolution {
+            vertices_mut[n].x = 0.0;
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = -((offset + i) as f32);
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n].x = 1.0;
+            vertices_mut[n].y = 0.0;
+            vertices_mut[n].z = -((offset + i) as f32);
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        n = 0;
+        let indices_mut = indices.as_mut_slice();
+        for i in 0..(tile_resolution * 4) as i32 {
+            let arm = i / tile_resolution as i32;
+            let bl = (arm + i) * 2;
+            let br = (arm + i) * 2 + 1;
+            let tl = (arm + i) * 2 + 2;
+            let tr = (arm + i) * 2 + 3;
+
+            let quad = if arm % 2 == 0 {
+                [br, bl, tr, bl, tl, tr]
+            } else {
+                [br, bl, tl, br, tl, tr]
+            };
+            for idx in quad {
+                indices_mut[n] = idx;
                 n += 1;
             }
+        }
 
-            for i in 0..(clipmap_vert_resolution - 1) as i32 {
-                indices_mut[n] = start_of_horizontal + (i + 0) * 2 + 1;
-                n += 1;
-                indices_mut[n] = start_of_horizontal + (i + 0) * 2 + 0;
-                n += 1;
-                indices_mut[n] = start_of_horizontal + (i + 1) * 2 + 0;
-                n += 1;
+        MeshData { vertices, indices, aabb }
+    }
 
-                indices_mut[n] = start_of_horizontal + (i + 1) * 2 + 1;
-                n += 1;
-                indices_mut[n] = start_of_horizontal + (i + 0) * 2 + 1;
-                n += 1;
-                indices_mut[n] = start_of_horizontal + (i + 1) * 2 + 0;
+    /**
+     * Builds the trim mesh, a skinny L shape that fills in the gaps between
+     * LOD meshes when they are moving at different speeds and have gaps.
+     */
+    fn trim_data(p_tile_resolution: usize, _p_levels: usize) -> MeshData {
+        let clipmap_vert_resolution = p_tile_resolution * 4 + 2;
+
+        let mut vertices = vec![Vector3::ZERO; (clipmap_vert_resolution * 2 + 1) * 2];
+        let mut indices = vec![0; (clipmap_vert_resolution * 2 - 1) * 6];
+        let mut aabb = Aabb::new(Vector3::ZERO, Vector3::ZERO);
+        let mut n = 0;
+        let offset = Vector3::new(
+            0.5 * (clipmap_vert_resolution + 1) as f32,
+            0.0,
+            0.5 * (clipmap_vert_resolution + 1) as f32,
+        );
+        let vertices_mut = vertices.as_mut_slice();
+
+        for i in 0..(clipmap_vert_resolution + 1) {
+            vertices_mut[n] =
+                Vector3::new(0.0, 0.0, (clipmap_vert_resolution - i) as f32) - offset;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n] =
+                Vector3::new(1.0, 0.0, (clipmap_vert_resolution - i) as f32) - offset;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        let start_of_horizontal = n as i32;
+        for i in 0..clipmap_vert_resolution {
+            vertices_mut[n] = Vector3::new((i + 1) as f32, 0.0, 0.0) - offset;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n] = Vector3::new((i + 1) as f32, 0.0, 1.0) - offset;
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        n = 0;
+        let indices_mut = indices.as_mut_slice();
+        for i in 0..clipmap_vert_resolution as i32 {
+            for idx in [
+                i * 2 + 1,
+                i * 2,
+                (i + 1) * 2,
+                (i + 1) * 2 + 1,
+                i * 2 + 1,
+                (i + 1) * 2,
+            ] {
+                indices_mut[n] = idx;
                 n += 1;
             }
-
-            // Trim mesh
-            Self::create_mesh(vertices, indices, &aabb)
-        };
-
-        // Create center cross mesh
-        // This mesh is the small cross shape that fills in the gaps along the
-        // X and Z axes between the center quadrants on LOD0.
-        let cross_mesh = {
-            let mut vertices = PackedVector3Array::default();
-            vertices.resize(patch_vert_resolution * 8);
-            let mut indices = PackedInt32Array::default();
-            indices.resize(tile_resolution * 24 + 6);
-            n = 0;
-            let vertices_mut = vertices.as_mut_slice();
-            for i in 0..(patch_vert_resolution * 2) as i32 {
-                vertices_mut[n] = Vector3::new((i - tile_resolution as i32) as f32, 0.0, 0.0);
-                aabb.expand(vertices_mut[n]);
-                n += 1;
-
-                vertices_mut[n] = Vector3::new((i - tile_resolution as i32) as f32, 0.0, 1.0);
-                aabb.expand(vertices_mut[n]);
+        }
+
+        for i in 0..(clipmap_vert_resolution - 1) as i32 {
+            for idx in [
+                i * 2 + 1,
+                i * 2,
+                (i + 1) * 2,
+                (i + 1) * 2 + 1,
+                i * 2 + 1,
+                (i + 1) * 2,
+            ] {
+                indices_mut[n] = start_of_horizontal + idx;
                 n += 1;
             }
+        }
 
-            let start_of_vertical = n as i32;
-            for i in 0..(patch_vert_resolution * 2) as i32 {
-                vertices_mut[n] = Vector3::new(0.0, 0.0, (i - tile_resolution as i32) as f32);
-                aabb.expand(vertices_mut[n]);
-                n += 1;
-
-                vertices_mut[n] = Vector3::new(1.0, 0.0, (i - tile_resolution as i32) as f32);
-                aabb.expand(vertices_mut[n]);
-                n += 1;
-            }
+        MeshData { vertices, indices, aabb }
+    }
 
-            n = 0;
-            let indices_mut = indices.as_mut_slice();
-            for i in 0..(tile_resolution * 2 + 1) as i32 {
-                let bl = i * 2 + 0;
-                let br = i * 2 + 1;
-                let tl = i * 2 + 2;
-                let tr = i * 2 + 3;
+    /**
+     * Builds the center cross mesh, the small cross shape that fills in the gaps along the
+     * X and Z axes between the center quadrants on LOD0.
+     */
+    fn cross_data(p_tile_resolution: usize, _p_levels: usize) -> MeshData {
+        let tile_resolution = p_tile_resolution;
+        let patch_vert_resolution = tile_resolution + 1;
 
-                indices_mut[n] = br;
-                n += 1;
-                indices_mut[n] = bl;
-                n += 1;
-                indices_mut[n] = tr;
-                n += 1;
-                indices_mut[n] = bl;
-                n += 1;
-                indices_mut[n] = tl;
-                n += 1;
-                indices_mut[n] = tr;
+        let mut vertices = vec![Vector3::ZERO; patch_vert_resolution * 8];
+        let mut indices = vec![0; tile_resolution * 24 + 6];
+        let mut aabb = Aabb::new(Vector3::ZERO, Vector3::ZERO);
+        let mut n = 0;
+        let vertices_mut = vertices.as_mut_slice();
+        for i in 0..(patch_vert_resolution * 2) as i32 {
+            vertices_mut[n] = Vector3::new((i - tile_resolution as i32) as f32, 0.0, 0.0);
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n] = Vector3::new((i - tile_resolution as i32) as f32, 0.0, 1.0);
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        let start_of_vertical = n as i32;
+        for i in 0..(patch_vert_resolution * 2) as i32 {
+            vertices_mut[n] = Vector3::new(0.0, 0.0, (i - tile_resolution as i32) as f32);
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+
+            vertices_mut[n] = Vector3::new(1.0, 0.0, (i - tile_resolution as i32) as f32);
+            aabb.expand(vertices_mut[n]);
+            n += 1;
+        }
+
+        n = 0;
+        let indices_mut = indices.as_mut_slice();
+        for i in 0..(tile_resolution * 2 + 1) as i32 {
+            let bl = i * 2;
+            let br = i * 2 + 1;
+            let tl = i * 2 + 2;
+            let tr = i * 2 + 3;
+
+            for idx in [br, bl, tr, bl, tl, tr] {
+                indices_mut[n] = idx;
                 n += 1;
             }
+        }
 
-            for i in 0..(tile_resolution * 2 + 1) as i32 {
-                if i == tile_resolution as i32 {
-                    continue;
-                }
-                let bl = i * 2 + 0;
-                let br = i * 2 + 1;
-                let tl = i * 2 + 2;
-                let tr = i * 2 + 3;
+        for i in 0..(tile_resolution * 2 + 1) as i32 {
+            if i == tile_resolution as i32 {
+                continue;
+            }
+            let bl = i * 2;
+            let br = i * 2 + 1;
+            let tl = i * 2 + 2;
+            let tr = i * 2 + 3;
 
-                indices_mut[n] = start_of_vertical + br;
-                n += 1;
-                indices_mut[n] = start_of_vertical + tr;
-                n += 1;
-                indices_mut[n] = start_of_vertical + bl;
-                n += 1;
-                indices_mut[n] = start_of_vertical + bl;
-                n += 1;
-                indices_mut[n] = start_of_vertical + tr;
-                n += 1;
-                indices_mut[n] = start_of_vertical + tl;
+            for idx in [br, tr, bl, bl, tr, tl] {
+                indices_mut[n] = start_of_vertical + idx;
                 n += 1;
             }
+        }
 
-            // Cross Mesh
-            Self::create_mesh(vertices, indices, &aabb)
-        };
-
-        // Create seam mesh
-        // This is a very thin mesh that is supposed to cover tiny gaps
-        // between tiles and fillers when the vertices do not line up
-        let seam_mesh = {
-            let mut vertices = PackedVector3Array::default();
-            vertices.resize(clipmap_vert_resolution * 4);
-            let mut indices = PackedInt32Array::default();
-            indices.resize(clipmap_vert_resolution * 6);
-            n = 0;
-            let vertices_mut = vertices.as_mut_slice();
-            for i in 0..clipmap_vert_resolution as i32 {
-                n = clipmap_resolution * 0 + i as usize;
-                vertices_mut[n] = Vector3::new(i as f32, 0.0, 0.0);
-                aabb.expand(vertices_mut[n]);
-
-                n = clipmap_resolution * 1 + i as usize;
-                vertices_mut[n] = Vector3::new(clipmap_vert_resolution as f32, 0.0, i as f32);
-                aabb.expand(vertices_mut[n]);
-
-                n = clipmap_resolution * 2 + i as usize;
-                vertices_mut[n] = Vector3::new(
-                    (clipmap_vert_resolution as i32 - i) as f32,
-                    0.0,
-                    clipmap_vert_resolution as f32,
-                );
-                aabb.expand(vertices_mut[n]);
-
-                n = clipmap_resolution * 3 + i as usize;
-                vertices_mut[n] =
-                    Vector3::new(0.0, 0.0, (clipmap_vert_resolution as i32 - i) as f32);
-                aabb.expand(vertices_mut[n]);
-            }
+        MeshData { vertices, indices, aabb }
+    }
 
-            n = 0;
-            let indices_mut = indices.as_mut_slice();
-            for i in (0..(clipmap_vert_resolution * 4) as i32).step_by(2) {
-                indices_mut[n] = i + 1;
-                n += 1;
-                indices_mut[n] = i;
-                n += 1;
-                indices_mut[n] = i + 2;
-                n += 1;
-            }
-            indices_mut[indices_mut.len() - 1] = 0;
-
-            // Seam Mesh
-            Self::create_mesh(vertices, indices, &aabb)
-        };
-
-        // skirt mesh
-        /*{
-            real_t scale = real_t(1 << (NUM_CLIPMAP_LEVELS - 1));
-            real_t fbase = real_t(tile_resolution << NUM_CLIPMAP_LEVELS);
-            Vector2 base = -Vector2(fbase, fbase);
-
-            Vector2 clipmap_tl = base;
-            Vector2 clipmap_br = clipmap_tl + (Vector2(CLIPMAP_RESOLUTION, CLIPMAP_RESOLUTION) * scale);
-
-            real_t big = 10000000.0;
-            Array vertices = Array::make(
-                Vector3(-1, 0, -1) * big,
-                Vector3(+1, 0, -1) * big,
-                Vector3(-1, 0, +1) * big,
-                Vector3(+1, 0, +1) * big,
-                Vector3(clipmap_tl.x, 0, clipmap_tl.y),
-                Vector3(clipmap_br.x, 0, clipmap_tl.y),
-                Vector3(clipmap_tl.x, 0, clipmap_br.y),
-                Vector3(clipmap_br.x, 0, clipmap_br.y)
-            );
+    /**
+     * Builds the seam mesh, a very thin mesh that is supposed to cover tiny gaps
+     * between tiles and fillers when the vertices do not line up.
+     */
+    fn seam_data(p_tile_resolution: usize, _p_levels: usize) -> MeshData {
+        let clipmap_resolution = p_tile_resolution * 4 + 1;
+        let clipmap_vert_resolution = clipmap_resolution + 1;
 
-            Array indices = Array::make(
-                0, 1, 4, 4, 1, 5,
-                1, 3, 5, 5, 3, 7,
-                3, 2, 7, 7, 2, 6,
-                4, 6, 0, 0, 6, 2
+        let mut vertices = vec![Vector3::ZERO; clipmap_vert_resolution * 4];
+        let mut indices = vec![0; clipmap_vert_resolution * 6];
+        let mut aabb = Aabb::new(Vector3::ZERO, Vector3::ZERO);
+        let vertices_mut = vertices.as_mut_slice();
+        for i in 0..clipmap_vert_resolution as i32 {
+            let mut n = i as usize;
+            vertices_mut[n] = Vector3::new(i as f32, 0.0, 0.0);
+            aabb.expand(vertices_mut[n]);
+
+            n = clipmap_resolution + i as usize;
+            vertices_mut[n] = Vector3::new(clipmap_vert_resolution as f32, 0.0, i as f32);
+            aabb.expand(vertices_mut[n]);
+
+            n = clipmap_resolution * 2 + i as usize;
+            vertices_mut[n] = Vector3::new(
+                (clipmap_vert_resolution as i32 - i) as f32,
+                0.0,
+                clipmap_vert_resolution as f32,
             );
+            aabb.expand(vertices_mut[n]);
+
+            n = clipmap_resolution * 3 + i as usize;
+            vertices_mut[n] =
+                Vector3::new(0.0, 0.0, (clipmap_vert_resolution as i32 - i) as f32);
+            aabb.expand(vertices_mut[n]);
+        }
+
+        let mut n = 0;
+        let indices_mut = indices.as_mut_slice();
+        for i in (0..(clipmap_vert_resolution * 4) as i32).step_by(2) {
+            indices_mut[n] = i + 1;
+            n += 1;
+            indices_mut[n] = i;
+            n += 1;
+            indices_mut[n] = i + 2;
+            n += 1;
+        }
+        indices_mut[indices_mut.len() - 1] = 0;
+
+        MeshData { vertices, indices, aabb }
+    }
 
-            skirt_mesh = _create_mesh(PackedVector3Array(vertices), PackedInt32Array(indices));
+    /**
+     * Builds the skirt mesh, a flat ring from the outer edge of the last clipmap level out
+     * to the horizon, hiding the gap beyond the clipmap. Sized for the largest LOD scale,
+     * so it is only translated when snapped.
+     */
+    fn skirt_data(p_tile_resolution: usize, p_levels: usize) -> MeshData {
+        let clipmap_resolution = p_tile_resolution * 4 + 1;
+        let scale = (1usize << (p_levels - 1)) as f32;
+        let fbase = (p_tile_resolution << p_levels) as f32;
+        let clipmap_tl = Vector2::new(-fbase, -fbase);
+        let clipmap_br = clipmap_tl + Vector2::new(clipmap_resolution as f32, clipmap_resolution as f32) * scale;
+
+        let big = Self::SKIRT_EXTENT;
+        let vertices = vec![
+            Vector3::new(-1.0, 0.0, -1.0) * big,
+            Vector3::new(1.0, 0.0, -1.0) * big,
+            Vector3::new(-1.0, 0.0, 1.0) * big,
+            Vector3::new(1.0, 0.0, 1.0) * big,
+            Vector3::new(clipmap_tl.x, 0.0, clipmap_tl.y),
+            Vector3::new(clipmap_br.x, 0.0, clipmap_tl.y),
+            Vector3::new(clipmap_tl.x, 0.0, clipmap_br.y),
+            Vector3::new(clipmap_br.x, 0.0, clipmap_br.y),
+        ];
+
+        let indices = vec![
+            0, 1, 4, 4, 1, 5,
+            1, 3, 5, 5, 3, 7,
+            3, 2, 7, 7, 2, 6,
+            4, 6, 0, 0, 6, 2,
+        ];
+
+        let aabb = Aabb::new(
+            Vector3::new(-big, 0.0, -big),
+            Vector3::new(big * 2.0, 0.1, big * 2.0),
+        );
+        MeshData { vertices, indices, aabb }
+    }
+}
 
-        }*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // FNV-1a over the little endian bytes of each word
+    fn fnv1a(p_words: impl Iterator<Item = u32>) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in p_words.flat_map(u32::to_le_bytes) {
+            hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
 
-        vec![tile_mesh, filler_mesh, trim_mesh, cross_mesh, seam_mesh]
+    // Hashes vertex bit patterns, so -0.0 and 0.0 count as different
+    fn digest(p_data: &MeshData) -> (usize, usize, u64, u64) {
+        let vertex_bits = p_data.vertices.iter().flat_map(|v| [v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]);
+        (
+            p_data.vertices.len(),
+            p_data.indices.len(),
+            fnv1a(vertex_bits),
+            fnv1a(p_data.indices.iter().map(|&i| i as u32)),
+        )
+    }
+
+    #[test]
+    fn generation_matches_the_serial_meshes_byte_for_byte() {
+        // Vertex and index counts and hashes of each mesh, in MeshType order, as built by
+        // the serial generate() before the builders moved onto threads, at size 16 and 3 levels
+        let golden: [(usize, usize, u64, u64); 6] = [
+            (289, 1536, 0x261d77c8df017215, 0x05a39343597d00fa),
+            (136, 384, 0xe16edcc05abe8b85, 0x21f40a31ac218525),
+            (266, 786, 0x45dc2b759fad2021, 0xcacb139a02f34b9d),
+            (136, 390, 0x77d604694318e9d5, 0x0f79f357a89e64f6),
+            (264, 396, 0xbb9f156d914b81a5, 0x0de1e5b0318dfcb5),
+            (8, 24, 0xd5e416c9833a4865, 0xbd84fa0b252f9d85),
+        ];
+        let meshes = GeoClipMap::generate_data(16, 3);
+        for (ty, (data, expected)) in meshes.iter().zip(golden).enumerate() {
+            assert_eq!(digest(data), expected, "mesh {ty}");
+        }
+    }
+
+    #[test]
+    fn odd_and_too_small_sizes_are_invalid() {
+        let min = GeoClipMap::MIN_MESH_SIZE;
+        assert!(GeoClipMap::is_valid_size(min));
+        assert!(GeoClipMap::is_valid_size(min + 2));
+        assert!(GeoClipMap::is_valid_size(256));
+        assert!(!GeoClipMap::is_valid_size(min + 1));
+        assert!(!GeoClipMap::is_valid_size(min - 2));
+        assert!(!GeoClipMap::is_valid_size(0));
+        assert!(!GeoClipMap::is_valid_size(-min));
+    }
+
+    #[test]
+    fn generation_yields_six_valid_meshes() {
+        let meshes = GeoClipMap::generate_data(16, 4);
+        assert_eq!(meshes.len(), 6);
+        for (ty, data) in meshes.iter().enumerate() {
+            assert!(!data.vertices.is_empty(), "mesh {ty} has no vertices");
+            assert!(!data.indices.is_empty() && data.indices.len() % 3 == 0, "mesh {ty} has partial triangles");
+            assert!(
+                data.indices.iter().all(|&i| i >= 0 && (i as usize) < data.vertices.len()),
+                "mesh {ty} indexes past its vertices"
+            );
+        }
+        // The skirt is the sixth mesh, reaching out to the horizon
+        let skirt = &meshes[MeshType::SKIRT.ord()];
+        assert_eq!(skirt.indices.len(), 24);
+        assert!(skirt.aabb.size.x >= GeoClipMap::SKIRT_EXTENT);
     }
 }
@@ -0,0 +1,16 @@
+// Copyright © 2023 Cory Petkovsek, Roope Palmroos, and Contributors.
+
+R"(
+
+//INSERT: AO_UNIFORMS
+uniform sampler2DArray _ao_maps : hint_default_white, filter_linear, repeat_disable;
+uniform float ao_light_affect : hint_range(0, 1) = 0.5;
+
+//INSERT: AO
+	// Baked ambient occlusion, see Terrain3DStorage::bake_ambient_occlusion()
+	if (region_uv.z >= 0.) {
+		AO = texture(_ao_maps, region_uv).r;
+		AO_LIGHT_AFFECT = ao_light_affect;
+	}
+
+)"
@@ -0,0 +1,39 @@
+// Copyright © 2023 Cory Petkovsek, Roope Palmroos, and Contributors.
+
+R"(
+
+//INSERT: TEXTURE_ID_4WAY
+	// Splat style control: 4 texture ids of 5 bits at 27, 22, 17 and 12, and 3 bit
+	// weights for textures 1-3 at 9, 6 and 3. Texture 0 takes what remains of 7
+	out_mat.base = int(control >>27u & 0x1Fu);
+	out_mat.over = int(control >> 22u & 0x1Fu);
+	int __tex2 = int(control >> 17u & 0x1Fu);
+	int __tex3 = int(control >> 12u & 0x1Fu);
+	float __w1 = float(control >> 9u & 0x7u);
+	float __w2 = float(control >> 6u & 0x7u);
+	float __w3 = float(control >> 3u & 0x7u);
+	float __w0 = max(7. - __w1 - __w2 - __w3, 0.);
+	out_mat.blend = __w1 / max(__w0 + __w1, 1.);
+
+//INSERT: BLEND_4WAY
+	// Mix in textures 2 and 3 by their share of the accumulated weight
+	float __w_sum = __w0 + __w1;
+	for (int i = 0; i < 2; i++) {
+		int __tex = i == 0 ? __tex2 : __tex3;
+		float __w = i == 0 ? __w2 : __w3;
+		if (__w > 0.) {
+			vec2 __uv = uv * _texture_uv_scale_array[__tex];
+			vec4 __alb = texture(_texture_array_albedo, vec3(__uv, float(__tex)));
+			vec4 __nrm = texture(_texture_array_normal, vec3(__uv, float(__tex)));
+			if (_texture_normal_flip_y_array[__tex] != 0) {
+				__nrm.g = 1.0 - __nrm.g;
+			}
+			__alb.rgb *= _texture_color_array[__tex].rgb;
+			__nrm.xz = unpack_normal(__nrm).xz;
+			__w_sum += __w;
+			albedo_ht = mix(albedo_ht, __alb, __w / __w_sum);
+			normal_rg = mix(normal_rg, __nrm, __w / __w_sum);
+		}
+	}
+
+)"
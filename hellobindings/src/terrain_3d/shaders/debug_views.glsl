@@ -4,6 +4,14 @@
 // which is assumed to belong to fragment()
 
 R"(
+//INSERT: DEBUG_LOD_UNIFORMS
+// Set per mesh instance by Terrain3D::set_debug_lod_colors()
+instance uniform vec4 _debug_lod_color : source_color = vec4(1.0);
+
+//INSERT: DEBUG_LOD
+	// Tint each clipmap LOD ring with its instance color
+	ALBEDO *= _debug_lod_color.rgb;
+
 //INSERT: DEBUG_CHECKERED
 	// Show a checkered grid
 	vec2 __p = UV * 1.0; // scale
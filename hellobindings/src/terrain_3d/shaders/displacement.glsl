@@ -0,0 +1,14 @@
+// Copyright © 2023 Cory Petkovsek, Roope Palmroos, and Contributors.
+
+R"(
+
+//INSERT: VERTEX_DISPLACEMENT_UNIFORMS
+uniform float displacement_amplitude : hint_range(0, 10) = 0.25;
+uniform float displacement_speed : hint_range(0, 10) = 0.5;
+
+//INSERT: VERTEX_DISPLACEMENT
+		// Animated vertex displacement, eg. heat haze or shifting sand
+		float __disp = textureLod(noise_texture, UV * .01 + vec2(TIME * displacement_speed * .01), 0.).r;
+		VERTEX.y += (__disp - 0.5) * 2.0 * displacement_amplitude;
+
+)"
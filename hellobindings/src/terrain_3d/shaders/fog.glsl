@@ -0,0 +1,15 @@
+// Copyright © 2023 Cory Petkovsek, Roope Palmroos, and Contributors.
+
+R"(
+
+//INSERT: FOG_UNIFORMS
+uniform vec4 fog_color : source_color = vec4(0.5, 0.6, 0.7, 1.0);
+uniform float fog_start : hint_range(0, 10000) = 100.0;
+uniform float fog_density : hint_range(0, 0.1) = 0.002;
+
+//INSERT: FOG
+	// Distance fog applied alike to near terrain and the background
+	float __fog_amount = 1.0 - exp(-max(v_vertex_dist - fog_start, 0.0) * fog_density);
+	FOG = vec4(fog_color.rgb, clamp(__fog_amount, 0.0, 1.0));
+
+)"
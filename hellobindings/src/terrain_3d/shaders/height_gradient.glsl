@@ -0,0 +1,14 @@
+// Copyright © 2023 Cory Petkovsek, Roope Palmroos, and Contributors.
+
+R"(
+
+//INSERT: HEIGHT_GRADIENT_UNIFORMS
+uniform sampler2D _height_gradient : source_color, filter_linear, repeat_disable;
+uniform vec2 _height_gradient_range = vec2(0.0, 100.0);
+
+//INSERT: HEIGHT_GRADIENT
+	// Tint by altitude, from the min to the max of the gradient range
+	float __hg = clamp((v_vertex.y - _height_gradient_range.x) / max(_height_gradient_range.y - _height_gradient_range.x, 0.001), 0.0, 1.0);
+	ALBEDO *= texture(_height_gradient, vec2(__hg, 0.5)).rgb;
+
+)"
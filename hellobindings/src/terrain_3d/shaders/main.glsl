@@ -21,11 +21,17 @@ render_mode blend_mix,depth_draw_opaque,cull_back,diffuse_burley,specular_schlic
  */
 
 // Private uniforms
+// Terrain3D::update_storage_params() sets the storage data whenever it changes:
+// _region_map, _region_offsets, _region_height_offsets, _region_map_size,
+// _region_visible_mask, and the texture arrays _height_maps, _control_maps, _color_maps
+// and _ao_maps. The material sets the rest.
 uniform float _region_size = 1024.0;
 uniform float _region_texel_size = 0.0009765625; // = 1/1024
 uniform int _region_map_size = 16;
 uniform int _region_map[256];
 uniform vec2 _region_offsets[256];
+uniform float _region_height_offsets[256];
+uniform int _region_visible_mask[8] = {-1, -1, -1, -1, -1, -1, -1, -1}; // 1 bit per region_map cell
 uniform sampler2DArray _height_maps : repeat_disable;
 uniform usampler2DArray _control_maps : repeat_disable;
 //INSERT: TEXTURE_SAMPLERS_NEAREST
@@ -33,6 +39,7 @@ uniform usampler2DArray _control_maps : repeat_disable;
 uniform float _texture_uv_scale_array[32];
 uniform float _texture_uv_rotation_array[32];
 uniform vec4 _texture_color_array[32];
+uniform int _texture_normal_flip_y_array[32]; // 1 to invert the green channel of a normal map
 uniform int _background_mode = 1;  // NONE = 0, FLAT = 1, NOISE = 2
 
 // Public uniforms
@@ -41,6 +48,12 @@ uniform bool height_blending = true;
 uniform float blend_sharpness : hint_range(0, 1) = 0.87;
 //INSERT: AUTO_SHADER_UNIFORMS
 //INSERT: DUAL_SCALING_UNIFORMS
+//INSERT: VERTEX_DISPLACEMENT_UNIFORMS
+//INSERT: PARALLAX_UNIFORMS
+//INSERT: FOG_UNIFORMS
+//INSERT: DEBUG_LOD_UNIFORMS
+//INSERT: AO_UNIFORMS
+//INSERT: HEIGHT_GRADIENT_UNIFORMS
 uniform vec3 macro_variation1 : source_color = vec3(1.);
 uniform vec3 macro_variation2 : source_color = vec3(1.);
 // Generic noise at 3 scales, which can be used for anything 
@@ -90,13 +103,23 @@ vec3 get_region_uv2(vec2 uv) {
 	return vec3(uv - _region_offsets[layer_index], float(layer_index));
 }
 
+// Takes in UV2 region space coordinates, returns false if the region there was hidden
+bool is_region_visible(vec2 uv) {
+	ivec2 pos = ivec2(floor(uv)) + (_region_map_size / 2);
+	if (pos.x < 0 || pos.x >= _region_map_size || pos.y < 0 || pos.y >= _region_map_size) {
+		return true;
+	}
+	int cell = pos.y * _region_map_size + pos.x;
+	return ((_region_visible_mask[cell / 32] >> (cell % 32)) & 1) != 0;
+}
+
 //INSERT: WORLD_NOISE1
 // 1 lookup
 float get_height(vec2 uv) {
 	highp float height = 0.0;
 	vec3 region = get_region_uv2(uv);
 	if (region.z >= 0.) {
-		height = texture(_height_maps, region).r;
+		height = texture(_height_maps, region).r + _region_height_offsets[int(region.z)];
 	}
 //INSERT: WORLD_NOISE2
  	return height;
@@ -124,6 +147,7 @@ void vertex() {
 
 		// Get final vertex location and save it
 		VERTEX.y = get_height(UV2);
+//INSERT: VERTEX_DISPLACEMENT
 		v_vertex = (MODEL_MATRIX * vec4(VERTEX, 1.0)).xyz;
 		v_vertex_dist = length(v_vertex - v_camera_pos);
 //INSERT: DUAL_SCALING_VERTEX
@@ -189,11 +213,13 @@ void get_material(vec2 uv, uint control, ivec3 iuv_center, vec3 normal, out Mate
 
 //INSERT: AUTO_SHADER_TEXTURE_ID
 //INSERT: TEXTURE_ID
+//INSERT: TEXTURE_ID_4WAY
 	float r = random(uv_center) * PI;
 	float rand = r * _texture_uv_rotation_array[out_mat.base];
 	vec2 rot = vec2(cos(rand), sin(rand));
 	uv *= .5; // Allow larger numbers on uv scale array - move to C++
 	vec2 matUV = rotate(uv, rot.x, rot.y) * _texture_uv_scale_array[out_mat.base];
+//INSERT: PARALLAX
 
 	vec4 albedo_ht = vec4(0.);
 	vec4 normal_rg = vec4(0.5f, 0.5f, 1.0f, 1.0f);
@@ -205,6 +231,7 @@ void get_material(vec2 uv, uint control, ivec3 iuv_center, vec3 normal, out Mate
 	// Apply color to base
 	albedo_ht.rgb *= _texture_color_array[out_mat.base].rgb;
 
+//INSERT: NORMAL_FLIP_Y_BASE
 	// Unpack base normal for blending
 	vec3 n = unpack_normal(normal_rg);
 	normal_rg.xz = rotate(n.xz, rot.x, -rot.y);
@@ -225,6 +252,7 @@ void get_material(vec2 uv, uint control, ivec3 iuv_center, vec3 normal, out Mate
 		// Apply color to overlay
 		albedo_ht2.rgb *= _texture_color_array[out_mat.over].rgb;
 		
+//INSERT: NORMAL_FLIP_Y_OVERLAY
 		// Unpack overlay normal for blending
 		n = unpack_normal(normal_rg2);
 		normal_rg2.xz = rotate(n.xz, rot2.x, -rot2.y);
@@ -233,6 +261,7 @@ void get_material(vec2 uv, uint control, ivec3 iuv_center, vec3 normal, out Mate
 		albedo_ht = height_blend(albedo_ht, albedo_ht.a, albedo_ht2, albedo_ht2.a, out_mat.blend);
 		normal_rg = height_blend(normal_rg, albedo_ht.a, normal_rg2, albedo_ht2.a, out_mat.blend);
 	}
+//INSERT: BLEND_4WAY
 	
 	// Repack normals and return material
 	normal_rg = pack_normal(normal_rg.xyz, normal_rg.a);
@@ -248,6 +277,10 @@ float blend_weights(float weight, float detail) {
 }
 
 void fragment() {
+	if (!is_region_visible(UV2)) {
+		discard;
+	}
+
 	// Calculate Terrain Normals. 4 lookups
 	vec3 w_tangent, w_binormal;
 	vec3 w_normal = get_normal(UV2, w_tangent, w_binormal);
@@ -321,7 +354,9 @@ void fragment() {
 		float lod = textureQueryLod(_color_maps, region_uv.xy).y;
 		lod = clamp(lod, 0., v_vertex_dist * .0006510416); // 1/1536. See #284
 		color_map = textureLod(_color_maps, region_uv, lod);
+//INSERT: COLOR_SRGB
 	}
+//INSERT: AO
 
 	// Macro variation. 2 Lookups
 	float noise1 = texture(noise_texture, rotate(UV*noise1_scale*.1, cos(noise1_angle), sin(noise1_angle)) + noise1_offset).r;
@@ -334,10 +369,12 @@ void fragment() {
 
 	// Apply PBR
 	ALBEDO = albedo_height.rgb * color_map.rgb * macrov;
+//INSERT: HEIGHT_GRADIENT
 	ROUGHNESS = roughness;
 	SPECULAR = 1.-normal_rough.a;
 	NORMAL_MAP = normal_rough.rgb;
 	NORMAL_MAP_DEPTH = 1.0;
+//INSERT: FOG
 
 }
 
@@ -0,0 +1,16 @@
+// Copyright © 2023 Cory Petkovsek, Roope Palmroos, and Contributors.
+
+R"(
+
+//INSERT: NORMAL_FLIP_Y_BASE
+	// DirectX style normal maps store green inverted
+	if (_texture_normal_flip_y_array[out_mat.base] != 0) {
+		normal_rg.g = 1.0 - normal_rg.g;
+	}
+
+//INSERT: NORMAL_FLIP_Y_OVERLAY
+		if (_texture_normal_flip_y_array[out_mat.over] != 0) {
+			normal_rg2.g = 1.0 - normal_rg2.g;
+		}
+
+)"
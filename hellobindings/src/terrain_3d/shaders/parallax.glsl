@@ -0,0 +1,26 @@
+// Copyright © 2023 Cory Petkovsek, Roope Palmroos, and Contributors.
+
+R"(
+
+//INSERT: PARALLAX_UNIFORMS
+uniform float parallax_depth : hint_range(0, 0.5) = 0.05;
+uniform int parallax_steps : hint_range(1, 64) = 16;
+uniform float parallax_distance : hint_range(0, 1000) = 50.0;
+
+//INSERT: PARALLAX
+	// Parallax occlusion using the texture height channel, fading out with distance
+	float __px_fade = 1.0 - clamp(v_vertex_dist / parallax_distance, 0.0, 1.0);
+	if (__px_fade > 0.0) {
+		vec3 __px_view = normalize(v_camera_pos - v_vertex);
+		vec2 __px_step = -__px_view.xz / max(__px_view.y, 0.1) * parallax_depth * __px_fade / float(parallax_steps);
+		float __px_layer = 1.0 / float(parallax_steps);
+		float __px_depth = 0.0;
+		float __px_height = textureLod(_texture_array_albedo, vec3(matUV, float(out_mat.base)), 0.).a;
+		for (int i = 0; i < parallax_steps && __px_depth < 1.0 - __px_height; i++) {
+			matUV += __px_step;
+			__px_depth += __px_layer;
+			__px_height = textureLod(_texture_array_albedo, vec3(matUV, float(out_mat.base)), 0.).a;
+		}
+	}
+
+)"
@@ -3,15 +3,20 @@
 R"(
 
 //INSERT: TEXTURE_SAMPLERS_LINEAR
-uniform sampler2DArray _color_maps : source_color, filter_linear_mipmap_anisotropic, repeat_disable;
+uniform sampler2DArray _color_maps : filter_linear_mipmap_anisotropic, repeat_disable;
 uniform sampler2DArray _texture_array_albedo : source_color, filter_linear_mipmap_anisotropic, repeat_enable;
 uniform sampler2DArray _texture_array_normal : hint_normal, filter_linear_mipmap_anisotropic, repeat_enable;
 uniform sampler2D noise_texture : source_color, filter_linear_mipmap_anisotropic, repeat_enable;
 
 //INSERT: TEXTURE_SAMPLERS_NEAREST
-uniform sampler2DArray _color_maps : source_color, filter_nearest_mipmap_anisotropic, repeat_disable;
+uniform sampler2DArray _color_maps : filter_nearest_mipmap_anisotropic, repeat_disable;
 uniform sampler2DArray _texture_array_albedo : source_color, filter_nearest_mipmap_anisotropic, repeat_enable;
 uniform sampler2DArray _texture_array_normal : hint_normal, filter_nearest_mipmap_anisotropic, repeat_enable;
 uniform sampler2D noise_texture : source_color, filter_nearest_mipmap_anisotropic, repeat_enable;
 
+//INSERT: COLOR_SRGB
+		// Color maps imported as sRGB are converted to linear here rather than by the sampler
+		color_map.rgb = mix(pow((color_map.rgb + vec3(0.055)) * (1.0 / 1.055), vec3(2.4)),
+			color_map.rgb * (1.0 / 12.92), lessThan(color_map.rgb, vec3(0.04045)));
+
 )"
\ No newline at end of file
@@ -1,15 +1,23 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI32, Ordering};
+
 use anyhow::{anyhow, Result};
+use godot::engine::object::ConnectFlags;
+use godot::engine::physics_server_3d::{BodyMode, BodyState};
 use godot::engine::rendering_server::ShadowCastingSetting;
 use godot::engine::utilities::printerr;
-use godot::engine::{EditorScript, Engine, INode3D, Node, Node3D, Sprite2D, StaticBody3D};
+use godot::engine::{
+    CollisionShape3D, ConcavePolygonShape3D, EditorScript, Engine, HeightMapShape3D, INode3D,
+    Material, Node, Node3D, Sprite2D, StaticBody3D,
+};
 use godot::prelude::*;
 
 use crate::terrain_3d::geoclipmap::*;
 use crate::terrain_3d::terrain_3d_material::Terrain3DMaterial;
-use crate::terrain_3d::utils::rs;
-use crate::{log_debug, log_error, log_info};
+use crate::terrain_3d::utils::{ps, rs, servers_available};
+use crate::{log_debug, log_error, log_info, log_warn};
 
-use super::terrain_3d_storage::Terrain3DStorage;
+use super::terrain_3d_storage::{control_is_hole, Terrain3DStorage};
 
 #[derive(Default)]
 struct Instances {
@@ -18,6 +26,37 @@ struct Instances {
     fillers: Vec<Rid>,
     trims: Vec<Rid>,
     seams: Vec<Rid>,
+    skirt: Option<Rid>,
+    // Single static plane used instead of the clipmap in simple_mesh_mode
+    plane: Option<Rid>,
+    // Last transform snap() gave each instance, the RenderingServer can't be queried
+    transforms: HashMap<Rid, Transform3D>,
+    // Transforms the last snap() actually sent to the RenderingServer
+    transform_updates: usize,
+}
+
+impl Instances {
+    fn iter(&self) -> impl Iterator<Item = &Rid> {
+        self.cross
+            .iter()
+            .chain(self.tiles.iter())
+            .chain(self.fillers.iter())
+            .chain(self.trims.iter())
+            .chain(self.seams.iter())
+            .chain(self.skirt.iter())
+            .chain(self.plane.iter())
+    }
+
+    // Caches p_transform for p_instance and counts it as an update. Returns false if the
+    // instance already had it, so the RenderingServer call can be skipped
+    fn record_transform(&mut self, p_instance: Rid, p_transform: Transform3D) -> bool {
+        if self.transforms.get(&p_instance) == Some(&p_transform) {
+            return false;
+        }
+        self.transforms.insert(p_instance, p_transform);
+        self.transform_updates += 1;
+        true
+    }
 }
 
 #[derive(GodotClass)]
@@ -27,36 +66,126 @@ pub struct Terrain3D {
 
     version: GString,
     is_inside_world: bool,
-    initialized: bool,
+    // How far initialize() got, so a failed build never leaves half built instances in use
+    init_state: InitState,
+    // Tile size of the clipmap, even and in GeoClipMap::MIN_MESH_SIZE..=MAX_MESH_SIZE
+    #[var(get, set = set_mesh_size)]
+    #[export(range = (16.0, 256.0, 2.0))]
     mesh_size: i32,
+    // Number of clipmap levels, each doubling the covered area, in 1..=MAX_MESH_LODS
+    #[var(get, set = set_mesh_lods)]
+    #[export]
     mesh_lods: i32,
+    // Build one static plane over the loaded regions instead of the clipmap
+    #[var(get, set = set_simple_mesh_mode)]
+    #[export]
+    simple_mesh_mode: bool,
+    // Follow the camera automatically. When off, call recenter() to move the terrain
+    #[var(get, set = set_auto_snap)]
+    #[export]
+    auto_snap: bool,
+    // Distance the camera must move before the terrain snaps again
+    #[var]
+    #[export]
+    snap_threshold: real,
+    // Whether the terrain follows the camera closely or in mesh_size jumps, see set_snap_mode()
+    #[var(get, set = set_snap_mode)]
+    #[export]
+    snap_mode: SnapMode,
+    // Follow the camera from physics_process() instead of process()
+    #[var(get, set = set_snap_in_physics)]
+    #[export]
+    snap_in_physics: bool,
+    // Editor only: add a region at the origin when storage has none, so a new terrain
+    // isn't invisible. Turn off to start from an empty storage
+    #[var]
+    #[export]
+    auto_create_region: bool,
 
     storage: Option<Gd<Terrain3DStorage>>,
+    // May be shared by several terrains, see set_material()
+    #[var(get = get_material, set = set_material)]
+    #[export]
     material: Option<Gd<Terrain3DMaterial>>,
+    // Optional separate material for the skirt filling beyond the clipmap
+    background_material: Option<Gd<Material>>,
     texture_list: Option<Gd<Sprite2D>>,
 
     // Current editor or gameplay camera we are centering the terrain on.
     camera: Option<Gd<Camera3D>>,
     // X,Z Position of the camera during the previous snapping. Set to max real_t value to force a snap update.
     camera_last_position: Vector2,
+    // LOD whose detail the innermost ring renders at, set by set_forced_lod(). -1 selects
+    // LODs by distance, the innermost ring at LOD 0
+    forced_lod: i32,
+    // Nodes followed instead of the camera with their priority, see add_tracking_target()
+    tracking_targets: Vec<(Gd<Node3D>, i32)>,
+    active_tracking_target: Option<Gd<Node3D>>,
+    // Floored origin applied to the cross by the last snap()
+    snap_offset: Vector3,
+    // Seconds until the next attempt at finding a camera, and whether its absence was logged
+    camera_retry_time: f64,
+    camera_missing_logged: bool,
+    // Set by set_camera(), the camera is then never replaced automatically while valid
+    camera_override: bool,
+    // Whether the missing storage was logged, so process() reports it once
+    storage_missing_logged: bool,
+
+    // Tint clipmap LODs for debugging, see set_debug_lod_colors()
+    debug_lod_colors: bool,
+
+    // Visibility bit per region_map cell, pushed to the shader as _region_visible_mask
+    region_visible_mask: PackedInt32Array,
+
+    // Region grid cells under the clipmap footprint after the last snap, and a scratch
+    // buffer reused to compute the next set
+    regions_in_view: Vec<Vector2i>,
+    regions_in_view_next: Vec<Vector2i>,
 
     // Meshes and Mesh instances
     meshes: Vec<Rid>,
     data: Instances,
 
     // Renderer settings
+    // Scenario to create instances in instead of the world's, see set_scenario_override()
+    scenario_override: Rid,
+    // VisualInstance3D layers the terrain renders on
+    #[var(get, set = set_render_layers)]
+    #[export]
     render_layers: u32,
     shadow_casting: ShadowCastingSetting,
+    // Extra height added to the mesh AABBs, see update_aabbs()
+    #[var(get, set = set_cull_margin)]
+    #[export]
     cull_margin: real,
 
     // Physics body and settings
+    // PhysicsServer3D body with a HeightMapShape3D per region, see build_collision()
     static_body: Rid,
     debug_static_body: Option<Gd<StaticBody3D>>,
+    // Body holding the shape from bake_static_collision(), replacing dynamic collision
+    static_collision: Option<Gd<StaticBody3D>>,
     collision_enabled: bool,
     show_debug_collision: bool,
     collision_layer: u32,
     collision_mask: u32,
     collision_priority: real,
+    // Body holding the HeightMapShape3D around the camera, rebuilt by update_collision()
+    dynamic_collision: Option<Gd<StaticBody3D>>,
+    // Which collision is built, in order of precedence: a shape baked with
+    // bake_static_collision() replaces both modes, REGIONS builds one shape per region in
+    // build_collision(), and DYNAMIC, or REGIONS on terrains too large for it, rebuilds a
+    // shape around the camera every collision_snap_distance. See collision_source()
+    #[var(get, set = set_collision_mode)]
+    #[export]
+    collision_mode: CollisionMode,
+    // Distance the camera must move before the DYNAMIC collision shape is rebuilt. Coarser
+    // than visual snapping, as rebuilding the shape costs far more than moving instances
+    #[var(get, set = set_collision_snap_distance)]
+    #[export]
+    collision_snap_distance: real,
+    // X,Z Position of the camera at the last collision rebuild. Set to max real_t value to force a rebuild.
+    collision_last_position: Vector2,
 }
 
 #[godot_api]
@@ -66,110 +195,1530 @@ impl INode3D for Terrain3D {
             base,
             version: "0.0.1-dev".into(),
             is_inside_world: false,
-            initialized: false,
+            init_state: InitState::Uninitialized,
             mesh_size: 48,
             mesh_lods: 7,
+            simple_mesh_mode: false,
+            auto_snap: true,
+            snap_threshold: Self::SNAP_DISTANCE,
+            snap_mode: SnapMode::CENTERED,
+            snap_in_physics: false,
+            auto_create_region: true,
             storage: None,
             material: None,
+            background_material: None,
             texture_list: None,
             camera: None,
             camera_last_position: Vector2::new(f32::MAX, f32::MAX),
+            forced_lod: -1,
+            tracking_targets: Vec::new(),
+            active_tracking_target: None,
+            snap_offset: Vector3::ZERO,
+            camera_retry_time: 0.0,
+            camera_missing_logged: false,
+            camera_override: false,
+            storage_missing_logged: false,
+            debug_lod_colors: false,
+            region_visible_mask: PackedInt32Array::from(&[-1; Self::REGION_VISIBLE_MASK_SIZE][..]),
+            regions_in_view: Vec::new(),
+            regions_in_view_next: Vec::new(),
             meshes: Vec::new(),
             data: Instances::default(),
+            scenario_override: Rid::Invalid,
             render_layers: 1,
             shadow_casting: ShadowCastingSetting::ON,
             cull_margin: 0.0,
             static_body: Rid::Invalid,
             debug_static_body: None,
+            static_collision: None,
             collision_enabled: true,
             show_debug_collision: false,
             collision_layer: 1,
             collision_mask: 1,
             collision_priority: 1.0,
+            dynamic_collision: None,
+            collision_mode: CollisionMode::DYNAMIC,
+            collision_snap_distance: Self::COLLISION_SNAP_DISTANCE,
+            collision_last_position: Vector2::new(real::MAX, real::MAX),
         }
     }
 
     fn ready(&mut self) {
-        match self.initialize() {
-            Ok(_) => self.base_mut().set_process(true),
-            Err(err) => {
-                log_error!(Self, "{}", err);
+        if let Err(err) = self.initialize() {
+            log_error!(Self, "{}", err);
+        }
+        // Only processes once built
+        self.update_process_callbacks();
+    }
+
+    /**
+     * Tears down in a fixed order so the RenderingServer never sees a freed RID still in use:
+     * mesh instances are freed first as they reference the material, then the material drops
+     * its texture params as they reference storage textures freed by Terrain3DStorage's Drop,
+     * then the references to storage and material are released if the node is being freed.
+     * Otherwise they stay assigned so the terrain rebuilds if re-added to the tree.
+     */
+    fn exit_tree(&mut self) {
+        if !self.is_built() {
+            return;
+        }
+        log_info!(Self, "Tearing down terrain");
+        self.free_resources();
+        if self.base().is_queued_for_deletion() {
+            self.release_references();
+        } else {
+            // Material and storage are still initialized, re-entering the tree only rebuilds
+            self.base_mut().request_ready();
+        }
+    }
+
+    fn process(&mut self, delta: f64) {
+        if !self.snap_in_physics {
+            self.update_snapping(delta);
+        }
+    }
+
+    fn physics_process(&mut self, delta: f64) {
+        if self.snap_in_physics {
+            self.update_snapping(delta);
+        }
+    }
+}
+
+// Frees the instances, meshes and collision body if the node is freed while still built,
+// in the order of exit_tree(). Child nodes are freed with the node, and the material and
+// storage are refcounted and free their own resources once released.
+impl Drop for Terrain3D {
+    fn drop(&mut self) {
+        if servers_available() {
+            self.free_rids();
+        }
+        self.release_references();
+    }
+}
+
+// LogLevel as i32, shared by every terrain and read by the log_* macros
+static S_DEBUG_LEVEL: AtomicI32 = AtomicI32::new(LogLevel::DEBUG as i32);
+
+#[godot_api]
+impl Terrain3D {
+    const __CLASS__: &'static str = "Terrain3DNode";
+    const CAMERA_RETRY_INTERVAL: f64 = 1.0;
+    // Default snap_threshold, and the finest collision_snap_distance
+    const SNAP_DISTANCE: real = 0.2;
+    // Default collision_snap_distance
+    const COLLISION_SNAP_DISTANCE: real = 8.0;
+    // Width and depth of the collision shape following the camera
+    const COLLISION_SIZE: real = 64.0;
+    // Ray marching of get_intersection()
+    const MAX_INTERSECTION_DISTANCE: real = 4096.0;
+    const INTERSECTION_STEP: real = 0.5;
+    const INTERSECTION_REFINE_STEPS: i32 = 8;
+    // Coarsest height pyramid level, 64 texel blocks, ray marching skips empty space with
+    const INTERSECTION_MAX_LOD: i32 = 6;
+    // Smallest jump over empty space, and the height kept above a skipped block
+    const INTERSECTION_SKIP_MARGIN: real = 0.01;
+    // Fraction of the distance travelled added to each step of intersect_ray()
+    const INTERSECTION_STEP_GROWTH: real = 0.01;
+    const MAX_RECOMMENDED_LODS: i32 = 10;
+    const MAX_MESH_SIZE: i32 = 256;
+    const MAX_MESH_LODS: i32 = 10;
+    const MAX_STATIC_COLLISION_TEXELS: i64 = 2048 * 2048;
+    const REGION_VISIBLE_MASK_SIZE: usize = 8;
+    // Storage signals after which update_storage_params() pushes the maps again
+    const STORAGE_SIGNALS: [&'static str; 5] = [
+        "region_map_changed",
+        "height_maps_changed",
+        "control_maps_changed",
+        "color_maps_changed",
+        "maps_changed",
+    ];
+    pub fn debug_level() -> &'static LogLevel {
+        LogLevel::from_i32(S_DEBUG_LEVEL.load(Ordering::Relaxed))
+    }
+
+    /**
+     * Sets the verbosity of all terrains: 0 errors and warnings only, 1 adds info, 2 adds
+     * debug messages. Out of range levels are clamped.
+     */
+    #[func]
+    pub fn set_debug_level(&self, level: i32) {
+        let clamped = level.clamp(LogLevel::ERROR as i32, LogLevel::DEBUG as i32);
+        if clamped != level {
+            log_warn!(Self, "Debug level {} is out of range, using {}", level, clamped);
+        }
+        S_DEBUG_LEVEL.store(clamped, Ordering::Relaxed);
+        log_info!(Self, "Setting debug level: {:?}", Self::debug_level());
+    }
+
+    #[func]
+    pub fn get_debug_level(&self) -> i32 {
+        S_DEBUG_LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn initialize(&mut self) -> Result<()> {
+        log_info!(
+            Self,
+            "Checking material, storage, texture_list, signal, and mesh initialization"
+        );
+
+        // Make blank objects if needed
+        if self.material.is_none() {
+            log_debug!(Self, "Creating blank material");
+            self.material = Some(
+                Terrain3DMaterial::new_gd()
+            );
+        }
+        if self.storage.is_none() {
+            log_debug!(Self, "Creating blank storage");
+            let mut st = Terrain3DStorage::new_gd();
+            st.bind_mut().set_version(Terrain3DStorage::CURRENT_VERSION);
+            self.storage = Some(
+                st
+            );
+        }
+
+        if /*!self.is_inside_world ||*/ !self.base().is_inside_tree() {
+            return Ok(());
+        }
+
+        // Initialize the system
+        if self.init_state == InitState::Uninitialized {
+            log_debug!(Self, "inite");
+            self.check_storage_version();
+            self.check_regions();
+            match (self.storage.as_mut(), self.material.as_mut()) {
+                (Some(storage), Some(material)) => {
+                    material.bind_mut().initialize(storage.bind().get_region_size());
+                    storage.bind_mut().update_regions(true); // generate map arrays
+                },
+                _ => {
+                    return Err(anyhow!("Storage or material not valid"));
+                }
+            }
+            self.init_state = InitState::Resources;
+        }
+        if self.init_state == InitState::Resources {
+            if let Err(err) = self.build() {
+                // Don't leave part of the meshes and instances around
+                self.free_instances();
+                return Err(err);
+            }
+            let id = self.base().instance_id();
+            if let Some(material) = self.material.as_mut() {
+                material.bind_mut().add_user(id);
             }
+            self.connect_storage();
+            self.update_storage_params();
+            self.init_state = InitState::Built;
         }
+        Ok(())
+    }
+
+    fn is_built(&self) -> bool {
+        self.init_state == InitState::Built
     }
 
-    fn process(&mut self, _delta: f64) {
-        if !self.initialized {
+    /**
+     * Frees and rebuilds the meshes and instances after a setting that shapes them changed.
+     * On failure the node falls back to InitState::Resources and stops processing.
+     */
+    fn rebuild(&mut self) {
+        if !self.is_built() {
             return;
         }
+        self.free_instances();
+        if let Err(err) = self.build() {
+            log_error!(Self, "{}", err);
+            self.free_instances();
+            self.init_state = InitState::Resources;
+            self.update_process_callbacks();
+        }
+    }
 
-        // If the game/editor camera is not set, find it
-        if self.camera().is_none() {
-            log_debug!(Self, "camera is null, getting the current one");
-            self.grab_camera();
+    /**
+     * Follows the camera, snapping the terrain when it moved far enough. Runs from
+     * process() or physics_process() depending on snap_in_physics. The dynamic collision
+     * keeps following while snapping is off through auto_snap or simple_mesh_mode.
+     */
+    fn update_snapping(&mut self, delta: f64) {
+        if !self.is_built() {
+            return;
         }
+        if !self.is_storage_valid() {
+            if !self.storage_missing_logged {
+                log_error!(Self, "Storage is missing, skipping snapping until it is set");
+                self.storage_missing_logged = true;
+            }
+            return;
+        }
+        self.storage_missing_logged = false;
+
+        let cam_pos = match self.get_follow_position(delta) {
+            Some(pos) => pos,
+            None => return,
+        };
 
         // If camera has moved enough, re-center the terrain on it.
-        if let Some(camera) = self.camera() {
-            if camera.is_inside_tree() {
-                let cam_pos = camera.get_global_position();
-                let cam_pos_2d = Vector2::new(cam_pos.x, cam_pos.z);
-                if self.camera_last_position.distance_to(cam_pos_2d) > 0.2 {
-                    self.snap(cam_pos);
-                    self.camera_last_position = cam_pos_2d;
+        // Transiently NaN while some parent transforms are set up. Skip the frame
+        // and force a snap on the next valid position
+        if !cam_pos.is_finite() {
+            self.camera_last_position = Vector2::new(real::MAX, real::MAX);
+            return;
+        }
+        let cam_pos_2d = Vector2::new(cam_pos.x, cam_pos.z);
+        if self.auto_snap && !self.simple_mesh_mode {
+            let snap_pos = match self.snap_mode {
+                SnapMode::CENTERED => cam_pos,
+                SnapMode::GROUPED => {
+                    let group = self.mesh_size as real;
+                    Vector3::new((cam_pos.x / group).round() * group, 0.0, (cam_pos.z / group).round() * group)
+                }
+            };
+            let snap_pos_2d = Vector2::new(snap_pos.x, snap_pos.z);
+            if Self::moved_beyond(self.camera_last_position, snap_pos_2d, self.snap_threshold) {
+                self.snap(snap_pos);
+                self.camera_last_position = snap_pos_2d;
+            }
+        }
+        if Self::moved_beyond(self.collision_last_position, cam_pos_2d, self.collision_snap_distance) {
+            self.update_collision(cam_pos);
+            self.collision_last_position = cam_pos_2d;
+        }
+    }
+
+    // Whether the camera moved from p_last to p_now by more than p_threshold. Visual
+    // snapping and collision each test it against their own threshold
+    fn moved_beyond(p_last: Vector2, p_now: Vector2, p_threshold: real) -> bool {
+        p_last.distance_to(p_now) > p_threshold
+    }
+
+    // Position of the tracking target or camera to follow, None while there is none
+    fn get_follow_position(&mut self, delta: f64) -> Option<Vector3> {
+        // Tracking targets take precedence over the camera
+        let target = self.update_tracking_target();
+        if let Some(target) = target {
+            Some(target.get_global_position())
+        } else {
+            if self.camera_override && self.camera().is_none() {
+                log_warn!(Self, "The camera set with set_camera() is no longer valid, finding the current one");
+                self.camera_override = false;
+                self.camera_retry_time = 0.0;
+            }
+            if self.camera_override {
+                // Left alone, even when the viewport switches to another camera
+            } else if self.camera().is_none() {
+                // If the game/editor camera is not set, find it
+                self.camera_retry_time -= delta;
+                if self.camera_retry_time > 0.0 {
+                    return None;
+                }
+                self.camera_retry_time = Self::CAMERA_RETRY_INTERVAL;
+                if !self.camera_missing_logged {
+                    log_debug!(Self, "camera is null, getting the current one");
                 }
+                self.grab_camera();
+            } else if !self.is_editor() {
+                self.update_active_camera();
+            }
+            match self.camera() {
+                Some(camera) if camera.is_inside_tree() => Some(camera.get_global_position()),
+                _ => None,
             }
         }
     }
-}
-
-static mut S_DEBUG_LEVEL: LogLevel = LogLevel::DEBUG;
 
-#[godot_api]
-impl Terrain3D {
-    const __CLASS__: &'static str = "Terrain3DNode";
-    pub fn debug_level() -> &'static LogLevel {
-        unsafe { &S_DEBUG_LEVEL }
+    /**
+     * Follows node instead of the camera while it is the highest priority valid target
+     * inside the tree, for split screen or spectator setups. Ties go to the target added
+     * first. Adding a node again updates its priority.
+     */
+    #[func]
+    pub fn add_tracking_target(&mut self, node: Gd<Node3D>, priority: i32) {
+        match self.tracking_targets.iter_mut().find(|(target, _)| *target == node) {
+            Some(entry) => entry.1 = priority,
+            None => self.tracking_targets.push((node, priority)),
+        }
+    }
+
+    /**
+     * Stops following node. The next priority target, or the camera, takes over.
+     */
+    #[func]
+    pub fn remove_tracking_target(&mut self, node: Gd<Node3D>) {
+        self.tracking_targets.retain(|(target, _)| *target != node);
+    }
+
+    /**
+     * Drops freed targets and returns the one to follow, forcing a snap when it changed.
+     */
+    fn update_tracking_target(&mut self) -> Option<Gd<Node3D>> {
+        self.tracking_targets.retain(|(target, _)| target.is_instance_valid());
+        let mut active: Option<&(Gd<Node3D>, i32)> = None;
+        for entry in self.tracking_targets.iter().filter(|(target, _)| target.is_inside_tree()) {
+            if active.map_or(true, |(_, priority)| entry.1 > *priority) {
+                active = Some(entry);
+            }
+        }
+        let active = active.map(|(target, _)| target.clone());
+        if active != self.active_tracking_target {
+            log_debug!(Self, "Tracking target changed to {:?}", active);
+            self.active_tracking_target = active.clone();
+            self.camera_last_position = Vector2::new(real::MAX, real::MAX);
+        }
+        active
+    }
+
+    /**
+     * Warns when storage was saved by a newer version, whose data this one may misread,
+     * or is older and wasn't flagged for upgrade by set_version().
+     */
+    fn check_storage_version(&self) {
+        let storage = match &self.storage {
+            Some(storage) => storage.bind(),
+            None => return,
+        };
+        let version = storage.get_version();
+        if version > Terrain3DStorage::CURRENT_VERSION {
+            log_warn!(
+                Self,
+                "Storage version {} is newer than the supported {}. It may load incorrectly, and saving will downgrade it",
+                version,
+                Terrain3DStorage::CURRENT_VERSION
+            );
+        } else if version < Terrain3DStorage::CURRENT_VERSION && !storage.is_modified() {
+            log_warn!(
+                Self,
+                "Storage version {} is older than {} and wasn't migrated",
+                version,
+                Terrain3DStorage::CURRENT_VERSION
+            );
+        }
+    }
+
+    /**
+     * Warns when storage has no regions, as nothing would render. In the editor, adds one
+     * at the origin unless auto_create_region is off.
+     */
+    fn check_regions(&mut self) {
+        let is_editor = self.is_editor();
+        let auto_create = self.auto_create_region;
+        let storage = match self.storage.as_mut() {
+            Some(storage) => storage,
+            None => return,
+        };
+        if storage.bind().get_region_count() > 0 {
+            return;
+        }
+        if is_editor && auto_create {
+            log_info!(Self, "Terrain has no regions, adding one at the origin. Disable auto_create_region to prevent this");
+            storage.bind_mut().add_region(Vector3::ZERO);
+        } else {
+            log_warn!(Self, "Terrain has no regions; call add_region or import a heightmap");
+        }
+    }
+
+    fn is_editor(&self) -> bool {
+        Engine::singleton().is_editor_hint()
+    }
+
+    /**
+     * Follows a switch to another current camera in the viewport, such as a first/third
+     * person toggle, emitting camera_changed and forcing a snap on the new camera.
+     */
+    fn update_active_camera(&mut self) {
+        let active = match self.base().get_viewport().and_then(|v| v.get_camera_3d()) {
+            Some(active) => active,
+            None => return,
+        };
+        if self.camera.as_ref() == Some(&active) {
+            return;
+        }
+        log_debug!(Self, "Active camera changed to {}", active);
+        self.camera = Some(active.clone());
+        self.camera_last_position = Vector2::new(real::MAX, real::MAX);
+        self.check_camera_coverage();
+        self.base_mut().emit_signal("camera_changed".into(), &[active.to_variant()]);
+    }
+
+    #[signal]
+    fn camera_changed(camera: Gd<Camera3D>);
+
+    /**
+     * Follows camera instead of grabbing the viewport's current camera, for split screen
+     * and cinematics. If camera is freed, the terrain goes back to finding the current
+     * camera automatically.
+     */
+    #[func]
+    pub fn set_camera(&mut self, camera: Gd<Camera3D>) {
+        if !camera.is_instance_valid() {
+            log_error!(Self, "Cannot follow an invalid camera");
+            return;
+        }
+        self.camera_override = true;
+        if self.camera.as_ref() == Some(&camera) {
+            return;
+        }
+        log_info!(Self, "Following camera {}", camera);
+        self.camera = Some(camera.clone());
+        self.camera_missing_logged = false;
+        self.camera_last_position = Vector2::new(real::MAX, real::MAX);
+        self.check_camera_coverage();
+        self.base_mut().emit_signal("camera_changed".into(), &[camera.to_variant()]);
+    }
+
+    #[func]
+    pub fn get_camera(&self) -> Option<Gd<Camera3D>> {
+        self.camera().cloned()
+    }
+
+    /**
+     * Renders the storage and material of other, e.g. for a minimap terrain in a
+     * SubViewport. The resources are referenced rather than initialized again, and
+     * RIDs they own are only freed once no terrain references them.
+     */
+    #[func]
+    pub fn share_storage_from(&mut self, other: Gd<Terrain3D>) {
+        if other.instance_id() == self.base().instance_id() {
+            log_error!(Self, "Cannot share storage from itself");
+            return;
+        }
+        let (storage, material) = {
+            let other = other.bind();
+            (other.storage.clone(), other.material.clone())
+        };
+        if storage.is_none() || material.is_none() {
+            log_error!(Self, "{} has no storage or material to share yet", other);
+            return;
+        }
+        log_info!(Self, "Sharing storage and material from {}", other);
+        let built = self.is_built();
+        if built {
+            self.free_instances();
+            self.release_material();
+            self.disconnect_storage();
+        }
+        self.storage = storage;
+        self.material = material;
+        self.storage_missing_logged = false;
+        if built {
+            self.init_state = InitState::Resources;
+            if let Err(err) = self.initialize() {
+                log_error!(Self, "{}", err);
+            }
+            self.update_process_callbacks();
+        }
+    }
+
+    /**
+     * Replaces the terrain material, e.g. with a resource shared by several terrains. A
+     * built terrain initializes it, applies it to its meshes and pushes the storage data
+     * to it. Null is rejected, a blank material is created on initialize() instead.
+     */
+    #[func]
+    pub fn set_material(&mut self, material: Option<Gd<Terrain3DMaterial>>) {
+        let mut material = match material {
+            Some(material) => material,
+            None => {
+                log_error!(Self, "Cannot set a null material");
+                return;
+            }
+        };
+        if self.material.as_ref() == Some(&material) {
+            return;
+        }
+        log_info!(Self, "Setting material: {}", material);
+        if self.init_state == InitState::Uninitialized {
+            self.material = Some(material);
+            return;
+        }
+        if let Some(region_size) = self.storage.as_ref().map(|storage| storage.bind().get_region_size()) {
+            material.bind_mut().initialize(region_size);
+        }
+        if self.is_built() {
+            // The old material may still be rendering another terrain
+            self.release_material();
+            material.bind_mut().add_user(self.base().instance_id());
+        }
+        let material_rid = material.bind().get_material_rid();
+        self.material = Some(material);
+        for mesh in &self.meshes {
+            rs().mesh_surface_set_material(*mesh, 0, material_rid);
+        }
+        if self.is_built() {
+            self.disconnect_storage();
+            self.connect_storage();
+            self.update_storage_params();
+        }
+    }
+
+    #[func]
+    pub fn get_material(&self) -> Option<Gd<Terrain3DMaterial>> {
+        self.material.clone()
+    }
+
+    #[func]
+    pub fn is_storage_valid(&self) -> bool {
+        self.storage
+            .as_ref()
+            .map_or(false, |storage| storage.is_instance_valid())
+    }
+
+    /**
+     * Enables whichever of process() / physics_process() should drive snapping, or
+     * neither when auto_snap and collision are both off.
+     */
+    fn update_process_callbacks(&mut self) {
+        let active = self.is_built() && (self.auto_snap || self.collision_enabled);
+        let physics = self.snap_in_physics;
+        self.base_mut().set_process(active && !physics);
+        self.base_mut().set_physics_process(active && physics);
+    }
+
+    #[func]
+    pub fn set_auto_snap(&mut self, enabled: bool) {
+        log_info!(Self, "Setting auto snap: {enabled}");
+        self.auto_snap = enabled;
+        self.update_process_callbacks();
+    }
+
+    /**
+     * Creates the terrain instances in scenario, a RenderingServer scenario managed by the
+     * caller, instead of the world's, for custom pipelines or off-screen capture. The
+     * terrain is rebuilt. Pass an invalid Rid to go back to the world scenario.
+     */
+    #[func]
+    pub fn set_scenario_override(&mut self, scenario: Rid) {
+        if scenario.is_invalid() {
+            log_info!(Self, "Using the world scenario");
+        } else {
+            log_info!(Self, "Using scenario override {}", scenario);
+        }
+        if scenario == self.scenario_override {
+            return;
+        }
+        self.scenario_override = scenario;
+        self.rebuild();
+    }
+
+    #[func]
+    pub fn get_scenario_override(&self) -> Rid {
+        self.scenario_override
+    }
+
+    #[func]
+    pub fn set_render_layers(&mut self, layers: u32) {
+        log_info!(Self, "Setting render layers: {layers}");
+        self.render_layers = layers;
+        for rid in self.data.iter() {
+            rs().instance_set_layer_mask(*rid, layers);
+        }
+    }
+
+    /**
+     * Applied to every terrain instance at once, the terrain isn't rebuilt.
+     */
+    #[func]
+    pub fn set_shadow_casting(&mut self, shadow_casting: ShadowCastingSetting) {
+        log_info!(Self, "Setting shadow casting: {:?}", shadow_casting);
+        self.shadow_casting = shadow_casting;
+        for rid in self.data.iter() {
+            rs().instance_geometry_set_cast_shadows_setting(*rid, shadow_casting);
+        }
+    }
+
+    #[func]
+    pub fn get_shadow_casting(&self) -> ShadowCastingSetting {
+        self.shadow_casting
+    }
+
+    /**
+     * Pads the mesh AABBs by margin above and below the terrain height range, for shaders
+     * that displace vertices past it, and is applied live to every instance as its extra
+     * visibility margin. Negative margins are clamped to 0.
+     */
+    #[func]
+    pub fn set_cull_margin(&mut self, margin: real) {
+        log_info!(Self, "Setting cull margin: {margin}");
+        self.cull_margin = margin.max(0.0);
+        self.update_aabbs();
+        self.update_cull_margins();
+    }
+
+    #[func]
+    pub fn set_snap_in_physics(&mut self, enabled: bool) {
+        log_info!(Self, "Snapping in physics process: {enabled}");
+        self.snap_in_physics = enabled;
+        self.update_process_callbacks();
+    }
+
+    /**
+     * Pins the detail of the terrain for cinematics or screenshots: the ring around the
+     * camera always renders at lod's detail and each ring further out one LOD coarser,
+     * instead of selecting the LOD by distance with LOD 0 around the camera. The clipmap
+     * still follows the camera. -1 restores the dynamic LODs.
+     */
+    #[func]
+    pub fn set_forced_lod(&mut self, lod: i32) {
+        if !(-1..self.mesh_lods).contains(&lod) {
+            log_error!(Self, "Forced LOD {} out of range -1..{}", lod, self.mesh_lods);
+            return;
+        }
+        if lod == self.forced_lod {
+            return;
+        }
+        if lod < 0 {
+            log_info!(Self, "Restoring dynamic LOD selection");
+        } else {
+            log_warn!(
+                Self,
+                "Forcing LOD {}: the clipmap covers {}x the area, which is expensive to stream, cull and shade over large terrains",
+                lod,
+                1 << (lod * 2)
+            );
+        }
+        self.forced_lod = lod;
+        // Force a snap update with the new levels
+        self.camera_last_position = Vector2::new(real::MAX, real::MAX);
+    }
+
+    #[func]
+    pub fn get_forced_lod(&self) -> i32 {
+        self.forced_lod
+    }
+
+    /**
+     * Centers the terrain on p_position right away. Lets users drive snapping
+     * themselves when auto_snap is off.
+     */
+    #[func]
+    pub fn recenter(&mut self, p_position: Vector3) {
+        if !self.is_built() || self.simple_mesh_mode || !self.is_storage_valid() {
+            log_debug!(Self, "Terrain not built, skipping recenter");
+            return;
+        }
+        self.snap(p_position);
+        self.camera_last_position = Vector2::new(p_position.x, p_position.z);
+    }
+
+    #[func]
+    pub fn get_version(&self) -> GString {
+        self.version.clone()
+    }
+
+    /**
+     * Returns the integer grid origin the terrain was last snapped to, with Y at 0. Props
+     * aligned to it stay aligned with the clipmap vertices as the terrain follows the camera.
+     */
+    #[func]
+    pub fn get_snap_offset(&self) -> Vector3 {
+        self.snap_offset
+    }
+
+    /**
+     * Listens to storage changes. Deferred, since storage emits while it is bound.
+     */
+    fn connect_storage(&mut self) {
+        let callable = self.base().callable("update_storage_params");
+        if let Some(storage) = self.storage.as_mut() {
+            for signal in Self::STORAGE_SIGNALS {
+                if !storage.is_connected(signal.into(), callable.clone()) {
+                    log_debug!(Self, "Connecting {} signal to update_storage_params()", signal);
+                    storage
+                        .connect_ex(signal.into(), callable.clone())
+                        .flags(ConnectFlags::DEFERRED.ord() as u32)
+                        .done();
+                }
+            }
+        }
+    }
+
+    fn disconnect_storage(&mut self) {
+        let callable = self.base().callable("update_storage_params");
+        if let Some(storage) = self.storage.as_mut() {
+            for signal in Self::STORAGE_SIGNALS {
+                if storage.is_connected(signal.into(), callable.clone()) {
+                    log_debug!(Self, "Disconnecting {} signal from update_storage_params()", signal);
+                    storage.disconnect(signal.into(), callable.clone());
+                }
+            }
+        }
+    }
+
+    // Stops rendering with the material, which clears its texture params if no other
+    // terrain uses it
+    fn release_material(&mut self) {
+        let id = self.base().instance_id();
+        if let Some(material) = self.material.as_mut() {
+            material.bind_mut().remove_user(id);
+        }
+    }
+
+    // Drops the material before the storage, as its params reference the storage textures
+    fn release_references(&mut self) {
+        self.material = None;
+        self.storage = None;
+    }
+
+    #[signal]
+    fn region_entered_view(offset: Vector2i);
+
+    #[signal]
+    fn region_exited_view(offset: Vector2i);
+
+    /**
+     * Diffs the region grid cells overlapped by the clipmap centered on p_cam_pos against
+     * the previous snap, emitting region_entered_view / region_exited_view.
+     */
+    fn update_regions_in_view(&mut self, p_cam_pos: Vector3) {
+        let region_size = match &self.storage {
+            Some(storage) => storage.bind().get_region_size() as real,
+            None => return,
+        };
+        let extent = (self.mesh_size << self.mesh_lods) as real;
+        let min = Vector2i::new(
+            ((p_cam_pos.x - extent) / region_size).floor() as i32,
+            ((p_cam_pos.z - extent) / region_size).floor() as i32,
+        );
+        let max = Vector2i::new(
+            ((p_cam_pos.x + extent) / region_size).floor() as i32,
+            ((p_cam_pos.z + extent) / region_size).floor() as i32,
+        );
+
+        let mut next = std::mem::take(&mut self.regions_in_view_next);
+        next.clear();
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                next.push(Vector2i::new(x, y));
+            }
+        }
+
+        let prev = std::mem::take(&mut self.regions_in_view);
+        for offset in prev.iter().filter(|o| !next.contains(o)) {
+            self.base_mut()
+                .emit_signal("region_exited_view".into(), &[offset.to_variant()]);
+        }
+        for offset in next.iter().filter(|o| !prev.contains(o)) {
+            self.base_mut()
+                .emit_signal("region_entered_view".into(), &[offset.to_variant()]);
+        }
+        self.regions_in_view = next;
+        self.regions_in_view_next = prev;
+    }
+
+    /**
+     * Pushes the storage data the shader needs to place regions to the material.
+     */
+    #[func]
+    fn update_storage_params(&mut self) {
+        if let (Some(storage), Some(material)) = (&self.storage, &self.material) {
+            let material_rid = material.bind().get_material_rid();
+            let storage = storage.bind();
+            let mut region_offsets = PackedVector2Array::new();
+            for offset in storage.get_region_offsets().iter_shared() {
+                region_offsets.push(Vector2::new(offset.x as real, offset.y as real));
+            }
+            rs().material_set_param(
+                material_rid,
+                "_region_map".into(),
+                Variant::from(storage.get_region_map()),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_region_offsets".into(),
+                Variant::from(region_offsets),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_height_maps".into(),
+                Variant::from(storage.get_height_maps_rid()),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_region_height_offsets".into(),
+                Variant::from(storage.get_region_height_offsets()),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_region_map_size".into(),
+                Variant::from(Terrain3DStorage::REGION_MAP_SIZE),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_region_visible_mask".into(),
+                Variant::from(self.region_visible_mask.clone()),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_control_maps".into(),
+                Variant::from(storage.get_control_maps_rid()),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_color_maps".into(),
+                Variant::from(storage.get_color_maps_rid()),
+            );
+            rs().material_set_param(
+                material_rid,
+                "_ao_maps".into(),
+                Variant::from(storage.get_ao_maps_rid()),
+            );
+        }
+        // Heights may have grown past the mesh bounds
+        self.update_aabbs();
+    }
+
+    /**
+     * Hides or shows the region at global_position. Hidden regions are discarded in the
+     * shader only, their data and get_height() are unaffected. All regions are visible
+     * by default, including ones added later.
+     */
+    #[func]
+    pub fn set_region_visible(&mut self, global_position: Vector3, visible: bool) {
+        let bit = match self.get_region_visible_bit(global_position) {
+            Some(bit) => bit,
+            None => {
+                log_error!(Self, "Position {} is outside the region map", global_position);
+                return;
+            }
+        };
+        let (word, mask) = (bit / 32, 1i32 << (bit % 32));
+        let value = self.region_visible_mask.get(word);
+        let value = if visible { value | mask } else { value & !mask };
+        self.region_visible_mask.set(word, value);
+        self.update_storage_params();
+    }
+
+    #[func]
+    pub fn is_region_visible(&self, global_position: Vector3) -> bool {
+        self.get_region_visible_bit(global_position).map_or(true, |bit| {
+            self.region_visible_mask.get(bit / 32) & (1i32 << (bit % 32)) != 0
+        })
+    }
+
+    // Index of the region_map cell containing p_global_position, matching is_region_visible() in main.glsl
+    fn get_region_visible_bit(&self, p_global_position: Vector3) -> Option<usize> {
+        let storage = self.storage.as_ref()?;
+        let loc = storage.bind().get_region_location(p_global_position);
+        let half = Terrain3DStorage::REGION_MAP_SIZE / 2;
+        let pos = loc + Vector2i::new(half, half);
+        let size = Terrain3DStorage::REGION_MAP_SIZE;
+        if pos.x < 0 || pos.x >= size || pos.y < 0 || pos.y >= size {
+            return None;
+        }
+        Some((pos.y * size + pos.x) as usize)
+    }
+
+    /**
+     * Uses p_material for the skirt mesh filling beyond the clipmap, rather than the
+     * terrain material. Pass null to fall back to the terrain material.
+     */
+    #[func]
+    pub fn set_background_material(&mut self, p_material: Option<Gd<Material>>) {
+        log_info!(Self, "Setting background material: {:?}", p_material);
+        self.background_material = p_material;
+        self.apply_background_material();
+    }
+
+    #[func]
+    pub fn get_background_material(&self) -> Option<Gd<Material>> {
+        self.background_material.clone()
+    }
+
+    fn apply_background_material(&self) {
+        if let Some(skirt) = self.data.skirt {
+            let rid = self
+                .background_material
+                .as_ref()
+                .map_or(Rid::Invalid, |mat| mat.get_rid());
+            // An invalid override makes the instance use the mesh's terrain material
+            rs().instance_geometry_set_material_override(skirt, rid);
+        }
+    }
+
+    /**
+     * Bilinearly interpolated height of the stored heightmap at global_position, for
+     * placing characters and props. Same as sample_height(): NAN over a hole, and where
+     * there is no region under the default out_of_bounds_policy, so callers can detect misses.
+     */
+    #[func]
+    pub fn get_height(&self, global_position: Vector3) -> real {
+        self.sample_height(global_position)
+    }
+
+    /**
+     * Height of the terrain at world_pos, or NAN if there is no storage or the position
+     * is over a hole. Outside of regions it follows the storage's out_of_bounds_policy.
+     */
+    #[func]
+    pub fn sample_height(&self, world_pos: Vector3) -> real {
+        match &self.storage {
+            Some(storage) => storage.bind().get_height(world_pos),
+            None => real::NAN,
+        }
+    }
+
+    /**
+     * Marches a ray from src_pos along direction until it passes below the terrain and
+     * returns the refined hit point. Returns a NAN vector if nothing is hit within
+     * MAX_INTERSECTION_DISTANCE. Holes and missing regions let the ray through. Empty
+     * space above the terrain is skipped using the height pyramid of get_height_lod().
+     */
+    #[func]
+    pub fn get_intersection(&self, src_pos: Vector3, direction: Vector3) -> Vector3 {
+        self.march_ray(src_pos, direction, Self::MAX_INTERSECTION_DISTANCE, 0.0)
+            .unwrap_or(Vector3::new(real::NAN, real::NAN, real::NAN))
+    }
+
+    /**
+     * Terrain picking without a physics body: returns the point where the ray from from
+     * along dir first passes below the terrain within max_distance, or null on a miss.
+     * Steps grow with the distance travelled to stay cheap at range, so ridges thinner
+     * than a step far from the origin may be missed.
+     */
+    #[func]
+    pub fn intersect_ray(&self, from: Vector3, dir: Vector3, max_distance: f32) -> Variant {
+        match self.march_ray(from, dir, max_distance, Self::INTERSECTION_STEP_GROWTH) {
+            Some(hit) => hit.to_variant(),
+            None => Variant::nil(),
+        }
+    }
+
+    fn march_ray(
+        &self,
+        p_from: Vector3,
+        p_dir: Vector3,
+        p_max_distance: real,
+        p_step_growth: real,
+    ) -> Option<Vector3> {
+        let storage = self.storage.as_ref()?.bind();
+        Self::march_heights(
+            p_from,
+            p_dir,
+            p_max_distance,
+            p_step_growth,
+            |point| storage.get_height_exact(point),
+            |point, lod| storage.get_height_lod(point, lod),
+        )
+    }
+
+    // Steps INTERSECTION_STEP plus p_step_growth times the distance travelled, then
+    // bisects the step that crossed the surface p_height describes. p_range gives the
+    // (min, max) height of the 2^lod texel block around a point, as get_height_lod() does,
+    // and lets the march jump over blocks the ray passes above
+    fn march_heights(
+        p_from: Vector3,
+        p_dir: Vector3,
+        p_max_distance: real,
+        p_step_growth: real,
+        p_height: impl Fn(Vector3) -> real,
+        p_range: impl Fn(Vector3, i32) -> Vector2,
+    ) -> Option<Vector3> {
+        if !p_from.is_finite() || !p_dir.is_finite() || p_dir.length_squared() == 0.0 {
+            return None;
+        }
+        let dir = p_dir.normalized();
+        // Positive while the point is above the terrain, NAN where there is none
+        let clearance = |t: real| {
+            let point = p_from + dir * t;
+            point.y - p_height(point)
+        };
+        let mut t = 0.0;
+        while t < p_max_distance {
+            if let Some(skip) = Self::skip_empty_space(p_from + dir * t, dir, &p_range) {
+                t = (t + skip).min(p_max_distance);
+                continue;
+            }
+            let next = (t + Self::INTERSECTION_STEP + t * p_step_growth).min(p_max_distance);
+            if clearance(next) <= 0.0 && clearance(t) >= 0.0 {
+                let (mut lo, mut hi) = (t, next);
+                for _ in 0..Self::INTERSECTION_REFINE_STEPS {
+                    let mid = (lo + hi) * 0.5;
+                    if clearance(mid) > 0.0 {
+                        lo = mid;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                return Some(p_from + dir * hi);
+            }
+            t = next;
+        }
+        None
+    }
+
+    // Distance the ray from p_point along p_dir stays above the coarsest pyramid block
+    // around p_point it is above, or None if it is above none of them. Blocks are left one
+    // texel early, as heights in their last texel are interpolated with the next block
+    fn skip_empty_space(p_point: Vector3, p_dir: Vector3, p_range: &impl Fn(Vector3, i32) -> Vector2) -> Option<real> {
+        for lod in (1..=Self::INTERSECTION_MAX_LOD).rev() {
+            let range = p_range(p_point, lod);
+            // NAN where there is no region
+            if range.y.is_nan() || p_point.y <= range.y {
+                continue;
+            }
+            let block = (1 << lod) as real;
+            let exit = |p_pos: real, p_delta: real| {
+                let start = (p_pos / block).floor() * block;
+                if p_delta > 0.0 {
+                    (start + block - 1.0 - p_pos) / p_delta
+                } else if p_delta < 0.0 {
+                    (start - p_pos) / p_delta
+                } else {
+                    real::INFINITY
+                }
+            };
+            let mut distance = exit(p_point.x, p_dir.x).min(exit(p_point.z, p_dir.z));
+            if p_dir.y < 0.0 {
+                // Stop a little above the block so the fine steps start above the surface
+                distance = distance.min((p_point.y - range.y - Self::INTERSECTION_SKIP_MARGIN) / -p_dir.y);
+            }
+            if distance > Self::INTERSECTION_SKIP_MARGIN {
+                return Some(distance);
+            }
+        }
+        None
+    }
+
+    /**
+     * Converts screen_pos in camera's viewport to the terrain point under it, or a NAN
+     * vector if the ray misses. Orthographic cameras cast parallel rays from the near
+     * plane, which project_ray_origin() accounts for.
+     */
+    #[func]
+    pub fn screen_point_to_terrain(&self, screen_pos: Vector2, camera: Gd<Camera3D>) -> Vector3 {
+        let origin = camera.project_ray_origin(screen_pos);
+        let direction = camera.project_ray_normal(screen_pos);
+        self.get_intersection(origin, direction)
+    }
+
+    /**
+     * Normal of the terrain at world_pos, or a NAN vector if there is no storage, region
+     * or the position is over a hole.
+     */
+    #[func]
+    pub fn sample_normal(&self, world_pos: Vector3) -> Vector3 {
+        match &self.storage {
+            Some(storage) => storage.bind().get_normal(world_pos),
+            None => Vector3::new(real::NAN, real::NAN, real::NAN),
+        }
+    }
+
+    /**
+     * Triangulates all loaded regions into one ConcavePolygonShape3D on a child StaticBody3D,
+     * for small levels that are fully loaded. Quads touching a hole are left out. Replaces
+     * any previous bake. Terrains above MAX_STATIC_COLLISION_TEXELS are refused, as the
+     * shape would take hundreds of megabytes.
+     */
+    #[func]
+    pub fn bake_static_collision(&mut self) {
+        let faces = {
+            let storage = match &self.storage {
+                Some(storage) => storage.bind(),
+                None => {
+                    log_error!(Self, "No storage to bake collision from");
+                    return;
+                }
+            };
+            let size = storage.get_region_size();
+            let offsets = storage.get_region_offsets();
+            let texels = offsets.len() as i64 * size as i64 * size as i64;
+            if texels > Self::MAX_STATIC_COLLISION_TEXELS {
+                log_warn!(
+                    Self,
+                    "Refusing to bake {} texels of collision, the limit is {}. Use fewer or smaller regions",
+                    texels,
+                    Self::MAX_STATIC_COLLISION_TEXELS
+                );
+                return;
+            }
+            log_info!(Self, "Baking static collision for {} regions", offsets.len());
+            let vertex = |x: i32, z: i32| {
+                let pos = Vector3::new(x as real, 0.0, z as real);
+                Vector3::new(pos.x, storage.get_height_exact(pos), pos.z)
+            };
+            let mut faces = PackedVector3Array::new();
+            for offset in offsets.iter_shared() {
+                let origin = offset * size;
+                for z in origin.y..origin.y + size {
+                    for x in origin.x..origin.x + size {
+                        let quad = [vertex(x, z), vertex(x + 1, z), vertex(x, z + 1), vertex(x + 1, z + 1)];
+                        if quad.iter().any(|v| v.y.is_nan()) {
+                            continue;
+                        }
+                        // Clockwise seen from above, so the faces point up
+                        for i in [0, 1, 2, 1, 3, 2] {
+                            faces.push(quad[i]);
+                        }
+                    }
+                }
+            }
+            faces
+        };
+
+        self.clear_static_collision();
+        self.clear_dynamic_collision();
+        self.destroy_collision();
+        let mut shape = ConcavePolygonShape3D::new_gd();
+        shape.set_faces(faces);
+        let mut collision_shape = CollisionShape3D::new_alloc();
+        collision_shape.set_shape(shape.upcast());
+        let mut body = StaticBody3D::new_alloc();
+        body.set_name("StaticCollision".into());
+        // The faces are in world space, don't inherit the terrain's transform
+        body.set_as_top_level(true);
+        body.set_collision_layer(self.collision_layer);
+        body.set_collision_mask(self.collision_mask);
+        body.set_collision_priority(self.collision_priority);
+        body.add_child(collision_shape.upcast());
+        self.base_mut().add_child(body.clone().upcast());
+        self.static_collision = Some(body);
+    }
+
+    /**
+     * Frees the body made by bake_static_collision(), if any.
+     */
+    #[func]
+    pub fn clear_static_collision(&mut self) {
+        if let Some(mut body) = self.static_collision.take() {
+            if body.is_instance_valid() {
+                log_debug!(Self, "Freeing baked static collision");
+                body.queue_free();
+            }
+        }
+    }
+
+    /**
+     * Whether collision comes from bake_static_collision(), in which case collision
+     * following the camera must not be built.
+     */
+    #[func]
+    pub fn has_static_collision(&self) -> bool {
+        self.static_collision.as_ref().map_or(false, |body| body.is_instance_valid())
+    }
+
+    #[func]
+    pub fn set_snap_mode(&mut self, mode: SnapMode) {
+        match mode {
+            SnapMode::CENTERED => log_info!(
+                Self,
+                "Setting snap mode: centered. Detail stays centered on the camera, but vertices swim over slopes as it moves"
+            ),
+            SnapMode::GROUPED => log_info!(
+                Self,
+                "Setting snap mode: grouped. The terrain jumps in steps of mesh_size {} so vertices hold still, but detail lags up to half a step behind the camera",
+                self.mesh_size
+            ),
+        }
+        self.snap_mode = mode;
+        self.camera_last_position = Vector2::new(real::MAX, real::MAX);
+    }
+
+    #[func]
+    pub fn set_collision_snap_distance(&mut self, distance: real) {
+        if distance < Self::SNAP_DISTANCE {
+            log_warn!(
+                Self,
+                "collision_snap_distance {} is below the visual snap distance, using {}",
+                distance,
+                Self::SNAP_DISTANCE
+            );
+        }
+        self.collision_snap_distance = distance.max(Self::SNAP_DISTANCE);
+        self.collision_last_position = Vector2::new(real::MAX, real::MAX);
+    }
+
+    /**
+     * Sets which collision is built, see collision_source() for the precedence. A built
+     * terrain replaces its current collision right away.
+     */
+    #[func]
+    pub fn set_collision_mode(&mut self, mode: CollisionMode) {
+        if self.collision_mode == mode {
+            return;
+        }
+        log_info!(Self, "Setting collision mode: {:?}", mode);
+        self.collision_mode = mode;
+        self.collision_last_position = Vector2::new(real::MAX, real::MAX);
+        if self.is_built() {
+            self.clear_dynamic_collision();
+            self.build_collision();
+        }
+    }
+
+    // The single place deciding which collision system runs. Baked collision takes
+    // precedence over per region shapes, which take precedence over the shape following
+    // the camera. p_regions is whether per region shapes are wanted or already built
+    fn collision_source(p_enabled: bool, p_baked: bool, p_regions: bool) -> CollisionSource {
+        if !p_enabled {
+            CollisionSource::Off
+        } else if p_baked {
+            CollisionSource::Baked
+        } else if p_regions {
+            CollisionSource::Regions
+        } else {
+            CollisionSource::Dynamic
+        }
+    }
+
+    /**
+     * Rebuilds the collision shape around p_cam_pos, COLLISION_SIZE wide, unless another
+     * collision source takes precedence, see collision_source().
+     */
+    fn update_collision(&mut self, p_cam_pos: Vector3) {
+        let source = Self::collision_source(
+            self.collision_enabled,
+            self.has_static_collision(),
+            self.static_body.is_valid(),
+        );
+        if source != CollisionSource::Dynamic {
+            self.clear_dynamic_collision();
+            return;
+        }
+        let center = Vector3::new(p_cam_pos.x.floor(), 0.0, p_cam_pos.z.floor());
+        let half = Self::COLLISION_SIZE * 0.5;
+        let bounds = Aabb::new(
+            center - Vector3::new(half, 0.0, half),
+            Vector3::new(Self::COLLISION_SIZE, 0.0, Self::COLLISION_SIZE),
+        );
+        log_debug!(Self, "Rebuilding collision around {:?}", center);
+        let shape = self.make_collision_shape(bounds);
+
+        let mut body = match self.dynamic_collision.clone().filter(|body| body.is_instance_valid()) {
+            Some(body) => body,
+            None => {
+                let mut body = StaticBody3D::new_alloc();
+                body.set_name("DynamicCollision".into());
+                body.set_collision_layer(self.collision_layer);
+                body.set_collision_mask(self.collision_mask);
+                body.set_collision_priority(self.collision_priority);
+                body.add_child(CollisionShape3D::new_alloc().upcast());
+                self.base_mut().add_child(body.clone().upcast());
+                self.dynamic_collision = Some(body.clone());
+                body
+            }
+        };
+        if let Some(mut collision_shape) = body.get_child(0).and_then(|c| c.try_cast::<CollisionShape3D>().ok()) {
+            collision_shape.set_shape(shape.upcast());
+        }
+        body.set_global_position(center + Vector3::new(half, 0.0, half));
+    }
+
+    fn clear_dynamic_collision(&mut self) {
+        if let Some(mut body) = self.dynamic_collision.take() {
+            if body.is_instance_valid() {
+                log_debug!(Self, "Freeing dynamic collision");
+                body.queue_free();
+            }
+        }
+        self.collision_last_position = Vector2::new(real::MAX, real::MAX);
+    }
+
+    /**
+     * Builds a standalone HeightMapShape3D covering the X/Z extent of p_bounds, one sample
+     * per unit, from the storage heights. The shape is centered on its origin, so place its
+     * body at the center of p_bounds. Holes are left as NAN so they have no collision.
+     * Returns an empty shape when no regions intersect p_bounds.
+     */
+    #[func]
+    pub fn make_collision_shape(&self, p_bounds: Aabb) -> Gd<HeightMapShape3D> {
+        let mut shape = HeightMapShape3D::new_gd();
+        let storage = match &self.storage {
+            Some(storage) => storage.bind(),
+            None => {
+                log_error!(Self, "No storage to build a collision shape from");
+                return shape;
+            }
+        };
+        let (width, depth, heights) = match Self::sample_collision_heights(&storage, p_bounds) {
+            Some(sampled) => sampled,
+            None => {
+                log_debug!(Self, "No regions intersect {:?}, returning an empty shape", p_bounds);
+                return shape;
+            }
+        };
+        shape.set_map_width(width);
+        shape.set_map_depth(depth);
+        shape.set_map_data(heights);
+        shape
+    }
+
+    /**
+     * Samples the storage heights over the X/Z extent of p_bounds, one per unit, as the
+     * width, depth and data of a height map shape. Holes are NAN so the physics server
+     * leaves them without collision, texels outside of regions are 0. Returns None when no
+     * regions intersect p_bounds.
+     */
+    fn sample_collision_heights(
+        p_storage: &Terrain3DStorage,
+        p_bounds: Aabb,
+    ) -> Option<(i32, i32, PackedFloat32Array)> {
+        let width = p_bounds.size.x.ceil().max(1.0) as i32 + 1;
+        let depth = p_bounds.size.z.ceil().max(1.0) as i32 + 1;
+        let mut heights = PackedFloat32Array::new();
+        heights.resize((width * depth) as usize);
+        let mut found = false;
+        {
+            let heights_mut = heights.as_mut_slice();
+            for z in 0..depth {
+                for x in 0..width {
+                    let pos = p_bounds.position + Vector3::new(x as real, 0.0, z as real);
+                    let control = p_storage.get_control_raw(pos);
+                    if control < 0 {
+                        continue;
+                    }
+                    found = true;
+                    heights_mut[(z * width + x) as usize] = if control_is_hole(control as u32) {
+                        real::NAN
+                    } else {
+                        p_storage.get_height_exact(pos)
+                    };
+                }
+            }
+        }
+        if !found {
+            return None;
+        }
+        Some((width, depth, heights))
+    }
+
+    /**
+     * With collision_mode REGIONS, creates the collision body on the PhysicsServer3D with
+     * one HeightMapShape3D per region, replacing any previous one, using collision_layer,
+     * collision_mask and collision_priority. Call it again after editing heights. Terrains
+     * above MAX_STATIC_COLLISION_TEXELS are left to the collision following the camera.
+     */
+    #[func]
+    pub fn build_collision(&mut self) {
+        self.destroy_collision();
+        let source = Self::collision_source(
+            self.collision_enabled,
+            self.has_static_collision(),
+            self.collision_mode == CollisionMode::REGIONS,
+        );
+        if source != CollisionSource::Regions {
+            return;
+        }
+        let space = match self.base().get_world_3d() {
+            Some(world) => world.get_space(),
+            None => {
+                log_error!(Self, "No world to build collision in");
+                return;
+            }
+        };
+        let shapes = {
+            let storage = match &self.storage {
+                Some(storage) => storage.bind(),
+                None => {
+                    log_error!(Self, "No storage to build collision from");
+                    return;
+                }
+            };
+            let size = storage.get_region_size();
+            let offsets = storage.get_region_offsets();
+            if offsets.is_empty() {
+                log_error!(Self, "Storage has no regions to build collision from");
+                return;
+            }
+            let texels = offsets.len() as i64 * size as i64 * size as i64;
+            if texels > Self::MAX_STATIC_COLLISION_TEXELS {
+                log_warn!(
+                    Self,
+                    "{} texels of collision exceed the limit of {}, using collision following the camera",
+                    texels,
+                    Self::MAX_STATIC_COLLISION_TEXELS
+                );
+                return;
+            }
+            log_info!(Self, "Building collision for {} regions", offsets.len());
+            let mut shapes = Vec::new();
+            for offset in offsets.iter_shared() {
+                let origin = offset * size;
+                let bounds = Aabb::new(
+                    Vector3::new(origin.x as real, 0.0, origin.y as real),
+                    Vector3::new(size as real, 0.0, size as real),
+                );
+                if let Some(sampled) = Self::sample_collision_heights(&storage, bounds) {
+                    shapes.push((bounds.center(), sampled));
+                }
+            }
+            shapes
+        };
+
+        let mut ps = ps();
+        let body = ps.body_create();
+        ps.body_set_mode(body, BodyMode::STATIC);
+        ps.body_set_space(body, space);
+        ps.body_attach_object_instance_id(body, self.base().instance_id().to_u64());
+        // Shapes are placed in world space, like the heights they are sampled from
+        ps.body_set_state(body, BodyState::TRANSFORM, Transform3D::IDENTITY.to_variant());
+        ps.body_set_collision_layer(body, self.collision_layer);
+        ps.body_set_collision_mask(body, self.collision_mask);
+        ps.body_set_collision_priority(body, self.collision_priority);
+        for (center, (width, depth, heights)) in shapes {
+            let (min_height, max_height) = heights
+                .as_slice()
+                .iter()
+                .fold((real::MAX, real::MIN), |(min, max), &h| (min.min(h), max.max(h)));
+            let mut data = Dictionary::new();
+            data.insert("width", width);
+            data.insert("depth", depth);
+            data.insert("heights", heights);
+            data.insert("min_height", min_height);
+            data.insert("max_height", max_height);
+            let shape = ps.heightmap_shape_create();
+            ps.shape_set_data(shape, data.to_variant());
+            // Height map shapes are centered on their origin
+            ps.body_add_shape_ex(body, shape)
+                .transform(Transform3D::new(Basis::default(), center))
+                .done();
+        }
+        self.static_body = body;
+    }
+
+    /**
+     * Frees the body made by build_collision() and its shapes, if any.
+     */
+    #[func]
+    pub fn destroy_collision(&mut self) {
+        if !self.static_body.is_valid() {
+            return;
+        }
+        log_debug!(Self, "Freeing collision body");
+        let mut ps = ps();
+        let shapes: Vec<Rid> = (0..ps.body_get_shape_count(self.static_body))
+            .map(|i| ps.body_get_shape(self.static_body, i))
+            .collect();
+        ps.free_rid(self.static_body);
+        for shape in shapes {
+            ps.free_rid(shape);
+        }
+        self.static_body = Rid::Invalid;
     }
 
-    fn initialize(&mut self) -> Result<()> {
-        log_info!(
-            Self,
-            "Checking material, storage, texture_list, signal, and mesh initialization"
-        );
-
-        // Make blank objects if needed
-        if self.material.is_none() {
-            log_debug!(Self, "Creating blank material");
-            self.material = Some(
-                Terrain3DMaterial::new_gd()
-            );
+    /**
+     * Turning collision off frees all collision built at runtime. Turning it on builds
+     * it again once the terrain is built.
+     */
+    #[func]
+    pub fn set_collision_enabled(&mut self, enabled: bool) {
+        if self.collision_enabled == enabled {
+            return;
         }
-        if self.storage.is_none() {
-            log_debug!(Self, "Creating blank storage");
-            let mut st = Terrain3DStorage::new_gd();
-            st.bind_mut().set_version(Terrain3DStorage::CURRENT_VERSION);
-            self.storage = Some(
-                st
-            );
+        log_info!(Self, "Setting collision enabled: {enabled}");
+        self.collision_enabled = enabled;
+        self.update_process_callbacks();
+        if !enabled {
+            self.destroy_collision();
+            self.clear_dynamic_collision();
+        } else if self.is_built() {
+            self.build_collision();
         }
+    }
 
-        // Initialize the system
-        if !self.initialized && /*self.is_inside_world &&*/ self.base().is_inside_tree() {
-            log_debug!(Self, "inite");
-            match (self.storage.as_mut(), self.material.as_mut()) {
-                (Some(storage), Some(material)) => {
-                    material.bind_mut().initialize(storage.bind().get_region_size());
-                    storage.bind_mut().update_regions(true); // generate map arrays
-                },
-                _ => {
-                    return Err(anyhow!("Storage or material not valid"));
-                }
-            }
-            self.build()?;
-            self.initialized = true;
-        }
-        Ok(())
+    #[func]
+    pub fn get_collision_enabled(&self) -> bool {
+        self.collision_enabled
     }
 
     fn camera(&self) -> Option<&Gd<Camera3D>> {
@@ -194,9 +1743,8 @@ impl Terrain3D {
             let excluded_node = editor_interface.and_then(|x| x.get_edited_scene_root());
 
             if let Some(from_nodes) = from_nodes {
-                let excluded_node = excluded_node.expect("Excluded node was None");
                 let mut cam_array = Vec::<Gd<Camera3D>>::new();
-                Self::find_cameras(from_nodes, &excluded_node, &mut cam_array);
+                Self::find_cameras(from_nodes, excluded_node.as_ref(), &mut cam_array);
                 if !cam_array.is_empty() {
                     log_debug!(Self, "Connecting to the first editor camera");
                     self.camera = Some(cam_array[0].clone());
@@ -206,23 +1754,201 @@ impl Terrain3D {
             log_debug!(Self, "Connecting to the in-game viewport camera");
             self.camera = self.base().get_viewport().and_then(|v| v.get_camera_3d());
         }
-        if self.camera.is_none() {
+        if let Some(camera) = self.camera.clone() {
+            self.camera_missing_logged = false;
+            self.check_camera_coverage();
+            self.base_mut().emit_signal("camera_changed".into(), &[camera.to_variant()]);
+        } else if Engine::singleton().is_editor_hint() {
+            // Scenes being edited may legitimately have no camera. Keep the terrain
+            // visible around the origin and let process() retry periodically.
+            if !self.camera_missing_logged {
+                log_error!(Self, "Cannot find an editor camera. Centering on the origin and retrying");
+                self.camera_missing_logged = true;
+                self.snap(Vector3::ZERO);
+                self.camera_last_position = Vector2::ZERO;
+            }
+        } else {
             self.base_mut().set_process(false);
+            self.base_mut().set_physics_process(false);
             log_error!(Self, "Cannot find active camera. Stopping _process()");
         }
     }
 
+    /**
+     * Sets the clipmap tile size and rebuilds. Sizes GeoClipMap can't build are rounded
+     * down to even and raised to GeoClipMap::MIN_MESH_SIZE, then capped at MAX_MESH_SIZE.
+     */
+    #[func]
+    pub fn set_mesh_size(&mut self, size: i32) {
+        let clamped = Self::clamp_mesh_size(size);
+        if clamped != size {
+            log_warn!(
+                Self,
+                "Mesh size {} must be even and in {}..={}, using {}",
+                size,
+                GeoClipMap::MIN_MESH_SIZE,
+                Self::MAX_MESH_SIZE,
+                clamped
+            );
+        }
+        if clamped == self.mesh_size {
+            return;
+        }
+        log_info!(Self, "Setting mesh size: {clamped}");
+        self.mesh_size = clamped;
+        self.rebuild();
+    }
+
+    // Rounds p_size down to even and into GeoClipMap::MIN_MESH_SIZE..=MAX_MESH_SIZE
+    fn clamp_mesh_size(p_size: i32) -> i32 {
+        (p_size & !1).clamp(GeoClipMap::MIN_MESH_SIZE, Self::MAX_MESH_SIZE)
+    }
+
+    /**
+     * Sets the number of clipmap levels and rebuilds. Fewer levels are cheaper but end
+     * closer to the camera. A forced LOD beyond the new levels is released.
+     */
+    #[func]
+    pub fn set_mesh_lods(&mut self, lods: i32) {
+        let clamped = lods.clamp(1, Self::MAX_MESH_LODS);
+        if clamped != lods {
+            log_warn!(Self, "Mesh lods {} must be in 1..={}, using {}", lods, Self::MAX_MESH_LODS, clamped);
+        }
+        if clamped == self.mesh_lods {
+            return;
+        }
+        log_info!(Self, "Setting mesh lods: {clamped}");
+        self.mesh_lods = clamped;
+        if self.forced_lod >= clamped {
+            self.set_forced_lod(-1);
+        }
+        self.rebuild();
+    }
+
+    /**
+     * Returns the number of mesh LODs whose clipmap reaches the tracked camera's far plane,
+     * or the current mesh_lods if there is no camera.
+     */
+    #[func]
+    pub fn get_recommended_lods_for_camera(&self) -> i32 {
+        match self.camera() {
+            Some(camera) => Self::lods_for_distance(self.mesh_size, camera.get_far()),
+            None => self.mesh_lods,
+        }
+    }
+
+    /**
+     * Smallest LOD count whose clipmap extends p_distance from its center. The outermost
+     * ring at LOD l reaches p_mesh_size << (l + 1) vertices, one unit apart.
+     */
+    fn lods_for_distance(p_mesh_size: i32, p_distance: real) -> i32 {
+        let mut lods = 1;
+        while lods < Self::MAX_RECOMMENDED_LODS && ((p_mesh_size << lods) as real) < p_distance {
+            lods += 1;
+        }
+        lods
+    }
+
+    /**
+     * Returns the vertex spacing of LOD lod, which doubles per LOD. This is the step
+     * snap() rounds the camera position to for that ring. Returns 0 if lod is not in
+     * 0..mesh_lods.
+     */
+    #[func]
+    pub fn get_lod_scale(&self, lod: i32) -> real {
+        if !(0..self.mesh_lods).contains(&lod) {
+            log_error!(Self, "LOD {} out of range 0..{}", lod, self.mesh_lods);
+            return 0.0;
+        }
+        Self::lod_scale(lod as usize)
+    }
+
+    /**
+     * Returns the world width of the ring of LOD lod, 4 tiles of mesh_size vertices at
+     * that LOD's spacing. Returns 0 if lod is not in 0..mesh_lods.
+     */
+    #[func]
+    pub fn get_lod_world_size(&self, lod: i32) -> real {
+        if !(0..self.mesh_lods).contains(&lod) {
+            log_error!(Self, "LOD {} out of range 0..{}", lod, self.mesh_lods);
+            return 0.0;
+        }
+        4.0 * self.mesh_size as real * Self::lod_scale(lod as usize)
+    }
+
+    // Vertex spacing is 1 unit at LOD 0
+    fn lod_scale(p_lod: usize) -> real {
+        (1 << p_lod) as real
+    }
+
+    // LOD rendered by clipmap ring p_ring, shifted out by p_forced_lod when it is set
+    fn ring_level(p_ring: usize, p_forced_lod: i32) -> usize {
+        p_ring + p_forced_lod.max(0) as usize
+    }
+
+    // Transforms of the tiles of clipmap ring p_ring centered on p_cam_pos, the inner 2x2
+    // tiles only on the innermost ring, as the rings further out surround it
+    fn ring_tile_transforms(
+        p_cam_pos: Vector3,
+        p_mesh_size: i32,
+        p_ring: usize,
+        p_forced_lod: i32,
+    ) -> Vec<Transform3D> {
+        let level = Self::ring_level(p_ring, p_forced_lod);
+        let scale = Self::lod_scale(level);
+        let snapped_pos = (p_cam_pos / scale).floor() * scale;
+        let tsize = (p_mesh_size << level) as f32;
+        let tsize_1 = (p_mesh_size << (level + 1)) as f32;
+        let tile_size = Vector3::new(tsize, 0.0, tsize);
+        let base = snapped_pos - Vector3::new(tsize_1, 0.0, tsize_1);
+
+        let mut transforms = Vec::with_capacity(16);
+        for x in 0..4 {
+            for y in 0..4 {
+                if p_ring != 0 && (x == 1 || x == 2) && (y == 1 || y == 2) {
+                    continue;
+                }
+                let fill = Vector3::new(
+                    if x >= 2 { 1.0 } else { 0. },
+                    0.,
+                    if y >= 2 { 1.0 } else { 0. },
+                ) * scale;
+                let tile_tl = base + Vector3::new(x as f32, 0., y as f32) * tile_size + fill;
+                let mut transform = Transform3D::default().scaled(Vector3::new(scale, 1., scale));
+                transform.origin = tile_tl;
+                transforms.push(transform);
+            }
+        }
+        transforms
+    }
+
+    // Warns when the clipmap builds far more or fewer LODs than the camera can see
+    fn check_camera_coverage(&self) {
+        let recommended = self.get_recommended_lods_for_camera();
+        if (recommended - self.mesh_lods).abs() > 1 {
+            let far = self.camera().map_or(0.0, |camera| camera.get_far());
+            log_warn!(
+                Self,
+                "Clipmap reaches {} units but the camera far plane is {}, consider {} mesh LODs instead of {}",
+                self.mesh_size << self.mesh_lods,
+                far,
+                recommended,
+                self.mesh_lods
+            );
+        }
+    }
+
     /**
      * Recursive helper function for _grab_camera().
      */
     fn find_cameras(
         from_nodes: Array<Gd<Node>>,
-        excluded_node: &Gd<Node>,
+        excluded_node: Option<&Gd<Node>>,
         cam_array: &mut Vec<Gd<Camera3D>>,
     ) {
         let cam_str: GString = "Camera3D".into();
         for node in from_nodes.iter_shared() {
-            if !node.eq(excluded_node) {
+            if excluded_node.map_or(true, |excluded| !node.eq(excluded)) {
                 Self::find_cameras(node.get_children(), excluded_node, cam_array);
             }
             if node.is_class(cam_str.clone()) {
@@ -236,56 +1962,77 @@ impl Terrain3D {
         }
     }
 
+    // Only calls the RenderingServer if the transform changed since the last snap, as
+    // outer LODs rarely move when the camera drifts slowly
+    fn set_instance_transform(&mut self, p_instance: Rid, p_transform: Transform3D) {
+        if self.data.record_transform(p_instance, p_transform) {
+            rs().instance_set_transform(p_instance, p_transform);
+        }
+    }
+
+    /**
+     * Returns how many instance transforms the last snap sent to the RenderingServer.
+     * Instances that didn't move are skipped, so this is a cheap profile of snapping.
+     */
+    #[func]
+    pub fn get_last_snap_transform_updates(&self) -> i32 {
+        self.data.transform_updates as i32
+    }
+
+    /**
+     * Returns the transforms the last snap placed the clipmap instances at, for debugging
+     * the snap math: the cross, then per LOD its tiles, filler, trim and seam. Instances
+     * not placed yet have an identity transform.
+     */
+    #[func]
+    pub fn get_instance_transforms(&self) -> Array<Transform3D> {
+        let mut transforms = Array::new();
+        for rid in self.get_instances_by_lod().iter().flatten() {
+            transforms.push(self.data.transforms.get(rid).copied().unwrap_or_default());
+        }
+        transforms
+    }
+
     /**
      * Centers the terrain and LODs on a provided position. Y height is ignored.
      */
     fn snap(&mut self, mut p_cam_pos: Vector3) {
+        if !p_cam_pos.is_finite() {
+            log_debug!(Self, "Skipping snap to non finite position {:?}", p_cam_pos);
+            return;
+        }
         p_cam_pos.y = 0.0;
         let rotations = [0f64, 270., 90., 180.];
 
         log_debug!(Self, "Snapping terrain to: {:?}", p_cam_pos);
 
-        let transform = Transform3D::new(Basis::default(), p_cam_pos.floor());
+        self.data.transform_updates = 0;
+        self.snap_offset = p_cam_pos.floor();
+        // The cross fills the center of the innermost ring, at its scale
+        let inner_scale = Self::lod_scale(Self::ring_level(0, self.forced_lod));
+        let mut transform = Transform3D::default().scaled(Vector3::new(inner_scale, 1., inner_scale));
+        transform.origin = (p_cam_pos / inner_scale).floor() * inner_scale;
         if let Some(cross) = self.data.cross {
-            rs().instance_set_transform(cross, transform);
+            self.set_instance_transform(cross, transform);
         }
 
         let mut edge = 0;
         let mut tile = 0;
 
         for l in 0..self.mesh_lods as usize {
-            let scale = (1 << l) as f32;
-
+            let level = Self::ring_level(l, self.forced_lod);
+            let scale = Self::lod_scale(level);
             let snapped_pos = (p_cam_pos / scale).floor() * scale;
-            let tsize = (self.mesh_size << l) as f32;
-            let tsize_1 = (self.mesh_size << (l + 1)) as f32;
-            let tile_size = Vector3::new(tsize, 0.0, tsize);
-            let base = snapped_pos - Vector3::new(tsize_1, 0.0, tsize_1);
 
             // Position tiles
-            for x in 0..4 {
-                for y in 0..4 {
-                    if l != 0 && (x == 1 || x == 2) && (y == 1 || y == 2) {
-                        continue;
-                    }
-                    let fill = Vector3::new(
-                        if x >= 2 { 1.0 } else { 0. },
-                        0.,
-                        if y >= 2 { 1.0 } else { 0. },
-                    ) * scale;
-                    let tile_tl = base + Vector3::new(x as f32, 0., y as f32) * tile_size + fill;
-                    //Vector3 tile_br = tile_tl + tile_size;
-                    let mut transform =
-                        Transform3D::default().scaled(Vector3::new(scale, 1., scale));
-                    transform.origin = tile_tl;
-                    rs().instance_set_transform(self.data.tiles[tile], transform);
-                    tile += 1;
-                }
+            for transform in Self::ring_tile_transforms(p_cam_pos, self.mesh_size, l, self.forced_lod) {
+                self.set_instance_transform(self.data.tiles[tile], transform);
+                tile += 1;
             }
 
             let mut transform = Transform3D::default().scaled(Vector3::new(scale, 1., scale));
             transform.origin = snapped_pos;
-            rs().instance_set_transform(self.data.fillers[l], transform);
+            self.set_instance_transform(self.data.fillers[l], transform);
 
             if l as i32 != self.mesh_lods - 1 {
                 let next_scale = scale * 2.0;
@@ -293,30 +2040,55 @@ impl Terrain3D {
                 // Position trims
                 {
                     let tile_center = Vector3::new(scale, 0., scale) * 0.5 + snapped_pos;
-                    let d = p_cam_pos - next_snapped_pos;
-
-                    let r = 0;
-                    let r = r | if d.x >= scale { 0 } else { 2 };
-                    let r = r | if d.z >= scale { 0 } else { 1 };
+                    let r = Self::trim_rotation_index(snapped_pos, next_snapped_pos, scale);
                     let angle = utilities::deg_to_rad(rotations[r]) as f32;
                     let mut transform =
                         Transform3D::default().rotated(Vector3::new(0.0, 1.0, 0.0), -angle);
                     transform = transform.scaled(Vector3::new(scale, 1.0, scale));
                     transform.origin = tile_center;
-                    rs().instance_set_transform(self.data.trims[edge], transform);
+                    self.set_instance_transform(self.data.trims[edge], transform);
                 }
                 // Position seams
                 {
-                    let tsize_1 = (self.mesh_size << (l + 1)) as f32;
+                    let tsize_1 = (self.mesh_size << (level + 1)) as f32;
                     let next_base = next_snapped_pos - Vector3::new(tsize_1, 0.0, tsize_1);
                     let mut transform =
                         Transform3D::default().scaled(Vector3::new(scale, 1.0, scale));
                     transform.origin = next_base;
-                    rs().instance_set_transform(self.data.seams[edge], transform);
+                    self.set_instance_transform(self.data.seams[edge], transform);
                 }
                 edge += 1;
             }
         }
+
+        // The skirt is built around the outermost level, so it snaps at the largest scale,
+        // and grows with the rings when they are shifted out by forced_lod
+        if let Some(skirt) = self.data.skirt {
+            let scale = Self::lod_scale(Self::ring_level(self.mesh_lods as usize - 1, self.forced_lod));
+            let shift = Self::lod_scale(Self::ring_level(0, self.forced_lod));
+            let mut transform = Transform3D::default().scaled(Vector3::new(shift, 1., shift));
+            transform.origin = (p_cam_pos / scale).floor() * scale;
+            self.set_instance_transform(skirt, transform);
+        }
+
+        log_debug!(Self, "Snap updated {} of {} instance transforms", self.data.transform_updates, self.data.transforms.len());
+        self.update_regions_in_view(p_cam_pos);
+    }
+
+    /**
+     * Picks the trim orientation for a LOD ring from where its snapped position sits within
+     * the next, coarser ring's cell. Both positions are multiples of p_scale, so their
+     * difference is either 0 or p_scale on each axis. Comparing against half a step keeps
+     * the choice stable at cell boundaries, where comparing the raw camera position could
+     * pick the opposite side of the ring and leave a crack.
+     */
+    fn trim_rotation_index(p_snapped_pos: Vector3, p_next_snapped_pos: Vector3, p_scale: real) -> usize {
+        let d = p_snapped_pos - p_next_snapped_pos;
+        let half = p_scale * 0.5;
+        let mut r = 0;
+        r |= if d.x >= half { 0 } else { 2 };
+        r |= if d.z >= half { 0 } else { 1 };
+        r
     }
 
     fn build(&mut self) -> Result<()> {
@@ -328,6 +2100,12 @@ impl Terrain3D {
             );
             return Ok(());
         }
+        // Never leak the instances of a previous build
+        self.free_instances();
+        self.build_collision();
+        if self.simple_mesh_mode {
+            return self.build_simple_mesh();
+        }
         log_info!(Self, "Building the terrain meshes");
 
         // Generate terrain meshes, lods, seams
@@ -338,6 +2116,9 @@ impl Terrain3D {
 
         // Set the current terrain material on all meshes
         if let Some(mat) = self.material.clone() {
+            if mat.bind().get_vertex_displacement() && self.cull_margin <= 0.0 {
+                log_warn!(Self, "Vertex displacement is enabled with a cull_margin of 0, tiles may be culled while visible");
+            }
             let material_rid = mat.bind().get_material_rid();
             for rid in &self.meshes {
                 rs().mesh_surface_set_material(rid.clone(), 0, material_rid);
@@ -347,15 +2128,7 @@ impl Terrain3D {
         }
 
         log_debug!(Self, "Creating mesh instances");
-        // Get current visual scenario so the instances appear in the scene
-        let scenario = self
-            .base()
-            .get_world_3d()
-            .and_then(|w| Some(w.get_scenario()));
-        if scenario.is_none() {
-            return Err(anyhow!("{}:: Could not acquire world_3d scenario", "build"));
-        }
-        let scenario = scenario.unwrap();
+        let scenario = self.get_scenario()?;
         let cross = rs().instance_create2(self.meshes[MeshType::CROSS.ord()], scenario);
         rs().instance_geometry_set_cast_shadows_setting(cross, self.shadow_casting);
 	    rs().instance_set_layer_mask(cross, self.render_layers);
@@ -393,24 +2166,505 @@ impl Terrain3D {
             }
         }
 
-        // self.update_aabbs();
+        let skirt = rs().instance_create2(self.meshes[MeshType::SKIRT.ord()], scenario);
+        rs().instance_geometry_set_cast_shadows_setting(skirt, self.shadow_casting);
+        rs().instance_set_layer_mask(skirt, self.render_layers);
+        self.data.skirt = Some(skirt);
+        self.apply_background_material();
+
+        self.update_debug_lod_colors();
+        self.update_aabbs();
+        self.update_cull_margins();
         // Force a snap update
 	    self.camera_last_position = Vector2::new(real::MAX, real::MAX);
 
         Ok(())
     }
 
+    /**
+     * Frees this terrain's RenderingServer resources right away: its instances and meshes,
+     * and the material texture params unless the material is shared with another terrain.
+     * Storage and material stay assigned and are not freed, call their own free_resources()
+     * once no terrain uses them. The terrain stays unbuilt until reinitialize() is called.
+     * Safe to call repeatedly.
+     */
+    #[func]
+    pub fn free_resources(&mut self) {
+        if !self.is_built() {
+            return;
+        }
+        log_info!(Self, "Freeing terrain resources");
+        self.free_instances();
+        // A material shared with share_storage_from() keeps its params for the others
+        self.release_material();
+        self.disconnect_storage();
+        self.init_state = InitState::Resources;
+        self.update_process_callbacks();
+    }
+
+    /**
+     * Initializes and builds the terrain again after free_resources(), without waiting for
+     * it to re-enter the tree. Does nothing on a built terrain.
+     */
+    #[func]
+    pub fn reinitialize(&mut self) {
+        if self.is_built() {
+            return;
+        }
+        if let Err(err) = self.initialize() {
+            log_error!(Self, "{}", err);
+        }
+        self.update_process_callbacks();
+    }
+
+    /**
+     * Tints each clipmap LOD with a distinct color through the _debug_lod_color instance
+     * uniform, to see which tiles belong to which ring while the camera moves.
+     */
+    #[func]
+    pub fn set_debug_lod_colors(&mut self, enable: bool) {
+        log_info!(Self, "Setting debug LOD colors: {enable}");
+        self.debug_lod_colors = enable;
+        if let Some(material) = self.material.as_mut() {
+            material.bind_mut().set_debug_view_lod(enable);
+        }
+        self.update_debug_lod_colors();
+    }
+
+    // The uniform only exists while the material's debug_view_lod is on
+    fn update_debug_lod_colors(&self) {
+        if !self.debug_lod_colors {
+            return;
+        }
+        let palette = [
+            Color::from_rgba(1.0, 0.3, 0.3, 1.0),
+            Color::from_rgba(0.3, 1.0, 0.3, 1.0),
+            Color::from_rgba(0.3, 0.3, 1.0, 1.0),
+            Color::from_rgba(1.0, 1.0, 0.3, 1.0),
+            Color::from_rgba(1.0, 0.3, 1.0, 1.0),
+            Color::from_rgba(0.3, 1.0, 1.0, 1.0),
+            Color::from_rgba(1.0, 0.6, 0.2, 1.0),
+            Color::from_rgba(0.6, 0.3, 1.0, 1.0),
+        ];
+        for (lod, instances) in self.get_instances_by_lod().iter().enumerate() {
+            let color = palette[lod % palette.len()];
+            for rid in instances {
+                rs().instance_geometry_set_shader_parameter(*rid, "_debug_lod_color".into(), Variant::from(color));
+            }
+        }
+    }
+
+    // Clipmap instances grouped by LOD, in the order build() creates them
+    fn get_instances_by_lod(&self) -> Vec<Vec<Rid>> {
+        let mut lods = vec![Vec::new(); self.mesh_lods.max(0) as usize];
+        if lods.is_empty() {
+            return lods;
+        }
+        lods[0].extend(self.data.cross.iter());
+        let mut tiles = self.data.tiles.iter();
+        for (l, instances) in lods.iter_mut().enumerate() {
+            // LOD 0 is a full 4x4 of tiles, others skip the 2x2 center
+            let count = if l == 0 { 16 } else { 12 };
+            instances.extend(tiles.by_ref().take(count));
+            instances.extend(self.data.fillers.get(l));
+            instances.extend(self.data.trims.get(l));
+            instances.extend(self.data.seams.get(l));
+        }
+        lods
+    }
+
+    // Get current visual scenario so the instances appear in the scene
+    fn get_scenario(&self) -> Result<Rid> {
+        if self.scenario_override.is_valid() {
+            return Ok(self.scenario_override);
+        }
+        self.base()
+            .get_world_3d()
+            .map(|w| w.get_scenario())
+            .ok_or_else(|| anyhow!("{}:: Could not acquire world_3d scenario", "build"))
+    }
+
+    /**
+     * Builds a single static plane covering the bounds of all loaded regions.
+     * Used by simple_mesh_mode for small terrains, it is never snapped.
+     */
+    fn build_simple_mesh(&mut self) -> Result<()> {
+        log_info!(Self, "Building the simple terrain mesh");
+        let (region_size, offsets) = match &self.storage {
+            Some(storage) => {
+                let storage = storage.bind();
+                (storage.get_region_size(), storage.get_region_offsets())
+            }
+            None => return Err(anyhow!("{}:: storage is empty", "build_simple_mesh")),
+        };
+
+        let mut min = Vector2i::ZERO;
+        let mut max = Vector2i::ZERO;
+        for (i, offset) in offsets.iter_shared().enumerate() {
+            if i == 0 {
+                min = offset;
+                max = offset;
+            } else {
+                min = Vector2i::new(min.x.min(offset.x), min.y.min(offset.y));
+                max = Vector2i::new(max.x.max(offset.x), max.y.max(offset.y));
+            }
+        }
+        let size = (max - min + Vector2i::ONE) * region_size;
+
+        let plane = GeoClipMap::generate_plane(size);
+        let material_rid = match &self.material {
+            Some(mat) => mat.bind().get_material_rid(),
+            None => return Err(anyhow!("{}:: material is empty", "build_simple_mesh")),
+        };
+        rs().mesh_surface_set_material(plane, 0, material_rid);
+        self.meshes = vec![plane];
+
+        let scenario = self.get_scenario()?;
+        let instance = rs().instance_create2(plane, scenario);
+        rs().instance_geometry_set_cast_shadows_setting(instance, self.shadow_casting);
+        rs().instance_set_layer_mask(instance, self.render_layers);
+        let origin = min * region_size;
+        rs().instance_set_transform(
+            instance,
+            Transform3D::new(Basis::default(), Vector3::new(origin.x as real, 0.0, origin.y as real)),
+        );
+        self.data.plane = Some(instance);
+        self.update_cull_margins();
+        Ok(())
+    }
+
+    #[func]
+    pub fn set_simple_mesh_mode(&mut self, enabled: bool) {
+        if self.simple_mesh_mode == enabled {
+            return;
+        }
+        log_info!(Self, "Setting simple mesh mode: {enabled}");
+        self.simple_mesh_mode = enabled;
+        self.rebuild();
+    }
+
+    /**
+     * Frees all mesh instances and meshes from the RenderingServer.
+     */
+    fn free_instances(&mut self) {
+        self.free_rids();
+        self.clear_dynamic_collision();
+    }
+
+    // Server side part of free_instances(), safe to call from Drop as it touches no nodes
+    fn free_rids(&mut self) {
+        log_debug!(Self, "Freeing mesh instances and meshes");
+        let data = std::mem::take(&mut self.data);
+        for rid in data.iter() {
+            rs().free_rid(*rid);
+        }
+        for rid in self.meshes.drain(..) {
+            rs().free_rid(rid);
+        }
+        self.destroy_collision();
+    }
+
+    /**
+     * Fits the Y extent of the mesh AABBs to the storage height range, shifted by the
+     * region height offsets and padded by cull_margin and the material's displacement, so
+     * tiles over hills aren't culled while visible.
+     */
     fn update_aabbs(&mut self) {
         if self.meshes.is_empty() || self.storage.is_none() {
             log_debug!(Self, "Update AABB called before terrain meshes built. Returning.");
             return;
         }
+        let (mut height_range, offsets) = {
+            let storage = self.storage.as_ref().unwrap().bind();
+            (storage.get_height_range(), storage.get_region_height_offsets())
+        };
+        let offsets = offsets.as_slice();
+        if !offsets.is_empty() {
+            let min = offsets.iter().copied().fold(real::MAX, real::min);
+            let max = offsets.iter().copied().fold(real::MIN, real::max);
+            height_range = Vector2::new(height_range.x + min.min(0.0), height_range.y + max.max(0.0));
+        }
+        let margin = self.cull_margin
+            + self
+                .material
+                .as_ref()
+                .map_or(0.0, |mat| mat.bind().get_vertical_displacement_margin());
+        log_debug!(Self, "Updating mesh AABBs to height range {} with margin {}", height_range, margin);
+
+        // All instances of a mesh share its custom AABB, so only the Y extent is replaced
+        for mesh in &self.meshes {
+            let mut aabb = rs().mesh_get_custom_aabb(*mesh);
+            aabb.position.y = height_range.x - margin;
+            aabb.size.y = height_range.y - height_range.x + margin * 2.0;
+            rs().mesh_set_custom_aabb(*mesh, aabb);
+        }
+    }
+
+    // Applies cull_margin to every instance as its extra visibility margin, so instances
+    // aren't culled by their own AABB either
+    fn update_cull_margins(&self) {
+        if self.data.iter().next().is_none() {
+            log_debug!(Self, "No terrain instances yet, cull margin applied once built");
+            return;
+        }
+        for rid in self.data.iter() {
+            rs().instance_set_extra_visibility_margin(*rid, self.cull_margin);
+        }
     }
 }
 
+// How update_snapping() follows the camera
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var, Export)]
+#[repr(i32)]
+pub enum SnapMode {
+    // Snap onto the camera whenever it moves snap_threshold
+    CENTERED = 0,
+    // Snap onto a mesh_size aligned grid, only when the camera crosses a cell
+    GROUPED = 1,
+}
+
+// Which collision Terrain3D builds, see collision_source() for the precedence
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var, Export)]
+#[repr(i32)]
+pub enum CollisionMode {
+    // Rebuild a shape around the camera every collision_snap_distance
+    DYNAMIC = 0,
+    // One shape per region, built once with the terrain
+    REGIONS = 1,
+}
+
+// Collision system in use, resolved by collision_source()
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum CollisionSource {
+    Off,
+    Baked,
+    Regions,
+    Dynamic,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum InitState {
+    // Nothing set up yet
+    Uninitialized,
+    // Material and storage initialized, but no meshes or instances
+    Resources,
+    // Meshes and instances built, the terrain can snap and render
+    Built,
+}
+
 #[derive(Debug, PartialEq, PartialOrd)]
 pub enum LogLevel {
     ERROR = 0,
     INFO = 1,
     DEBUG = 2,
 }
+
+impl LogLevel {
+    // Levels above DEBUG are clamped to it, below ERROR to ERROR
+    pub fn from_i32(p_level: i32) -> &'static LogLevel {
+        match p_level {
+            i32::MIN..=0 => &LogLevel::ERROR,
+            1 => &LogLevel::INFO,
+            _ => &LogLevel::DEBUG,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trim_rotation_index_covers_each_quadrant() {
+        let next = Vector3::new(8.0, 0.0, 8.0);
+        let scale = 2.0;
+        assert_eq!(Terrain3D::trim_rotation_index(next + Vector3::new(2.0, 0.0, 2.0), next, scale), 0);
+        assert_eq!(Terrain3D::trim_rotation_index(next + Vector3::new(2.0, 0.0, 0.0), next, scale), 1);
+        assert_eq!(Terrain3D::trim_rotation_index(next + Vector3::new(0.0, 0.0, 2.0), next, scale), 2);
+        assert_eq!(Terrain3D::trim_rotation_index(next, next, scale), 3);
+    }
+
+    #[test]
+    fn trim_rotation_index_is_stable_near_cell_boundaries() {
+        // Float error around a snapped step must not flip the trim to the other side
+        let next = Vector3::new(-64.0, 0.0, 128.0);
+        let scale = 4.0;
+        let almost = Vector3::new(4.0 - 1e-4, 0.0, 1e-4);
+        assert_eq!(Terrain3D::trim_rotation_index(next + almost, next, scale), 1);
+        let below = Vector3::new(-1e-4, 0.0, 4.0 + 1e-4);
+        assert_eq!(Terrain3D::trim_rotation_index(next + below, next, scale), 2);
+    }
+
+    #[test]
+    fn lods_for_distance_reaches_the_distance() {
+        assert_eq!(Terrain3D::lods_for_distance(32, 0.0), 1);
+        assert_eq!(Terrain3D::lods_for_distance(32, 64.0), 1);
+        assert_eq!(Terrain3D::lods_for_distance(32, 65.0), 2);
+        assert_eq!(Terrain3D::lods_for_distance(32, 4000.0), 7);
+        assert_eq!(Terrain3D::lods_for_distance(32, real::MAX), Terrain3D::MAX_RECOMMENDED_LODS);
+    }
+
+    #[test]
+    fn a_small_move_snaps_the_meshes_but_not_the_collision() {
+        let last = Vector2::new(10.0, 10.0);
+        let moved = Vector2::new(11.0, 10.0);
+        assert!(Terrain3D::moved_beyond(last, moved, Terrain3D::SNAP_DISTANCE));
+        assert!(!Terrain3D::moved_beyond(last, moved, Terrain3D::COLLISION_SNAP_DISTANCE));
+        let far = Vector2::new(10.0, 10.0 + Terrain3D::COLLISION_SNAP_DISTANCE + 0.5);
+        assert!(Terrain3D::moved_beyond(last, far, Terrain3D::COLLISION_SNAP_DISTANCE));
+    }
+
+    #[test]
+    fn a_forced_lod_keeps_the_near_tiles_detail_as_the_camera_moves() {
+        let mesh_size = 48;
+        let quad_size = |p_transform: &Transform3D| (*p_transform * Vector3::new(1.0, 0.0, 0.0) - p_transform.origin).x;
+        for cam in [Vector3::ZERO, Vector3::new(1000.3, 0.0, -37.8), Vector3::new(-5123.0, 0.0, 77.5)] {
+            let tiles = Terrain3D::ring_tile_transforms(cam, mesh_size, 0, 2);
+            assert_eq!(tiles.len(), 16);
+            // The tiles around the camera stay at LOD 2, 4 units per quad
+            assert!(tiles.iter().all(|tile| quad_size(tile) == 4.0));
+            let tile_size = (mesh_size * 4) as real;
+            let min_x = tiles.iter().map(|tile| tile.origin.x).fold(real::MAX, real::min);
+            let max_x = tiles.iter().map(|tile| tile.origin.x).fold(real::MIN, real::max) + tile_size;
+            assert!(min_x <= cam.x && cam.x < max_x);
+
+            let dynamic = Terrain3D::ring_tile_transforms(cam, mesh_size, 0, -1);
+            assert!(dynamic.iter().all(|tile| quad_size(tile) == 1.0));
+        }
+        assert_eq!(Terrain3D::ring_level(3, 2), 5);
+        assert_eq!(Terrain3D::ring_level(3, -1), 3);
+    }
+
+    #[test]
+    fn collision_source_follows_one_precedence() {
+        let source = Terrain3D::collision_source;
+        assert_eq!(source(false, true, true), CollisionSource::Off);
+        assert_eq!(source(true, true, true), CollisionSource::Baked);
+        assert_eq!(source(true, false, true), CollisionSource::Regions);
+        assert_eq!(source(true, false, false), CollisionSource::Dynamic);
+    }
+
+    #[test]
+    fn log_level_from_i32_clamps_out_of_range_levels() {
+        assert_eq!(LogLevel::from_i32(i32::MIN), &LogLevel::ERROR);
+        assert_eq!(LogLevel::from_i32(-1), &LogLevel::ERROR);
+        assert_eq!(LogLevel::from_i32(0), &LogLevel::ERROR);
+        assert_eq!(LogLevel::from_i32(1), &LogLevel::INFO);
+        assert_eq!(LogLevel::from_i32(2), &LogLevel::DEBUG);
+        assert_eq!(LogLevel::from_i32(i32::MAX), &LogLevel::DEBUG);
+    }
+
+    #[test]
+    fn mesh_size_clamps_to_even_sizes_in_16_to_256() {
+        assert_eq!(Terrain3D::clamp_mesh_size(48), 48);
+        assert_eq!(Terrain3D::clamp_mesh_size(49), 48);
+        assert_eq!(Terrain3D::clamp_mesh_size(8), 16);
+        assert_eq!(Terrain3D::clamp_mesh_size(-4), 16);
+        assert_eq!(Terrain3D::clamp_mesh_size(16), 16);
+        assert_eq!(Terrain3D::clamp_mesh_size(256), 256);
+        assert_eq!(Terrain3D::clamp_mesh_size(257), 256);
+        assert_eq!(Terrain3D::clamp_mesh_size(1000), 256);
+        for size in -10..300 {
+            assert!(GeoClipMap::is_valid_size(Terrain3D::clamp_mesh_size(size)));
+        }
+    }
+
+    #[test]
+    fn march_heights_hits_a_flat_plane_within_tolerance() {
+        let plane = |_: Vector3| 5.0;
+        let from = Vector3::new(0.0, 20.0, 0.0);
+        let dir = Vector3::new(1.0, -1.0, 0.0);
+        let range = |_: Vector3, _: i32| Vector2::new(5.0, 5.0);
+        let hit = Terrain3D::march_heights(from, dir, 100.0, Terrain3D::INTERSECTION_STEP_GROWTH, plane, range)
+            .expect("ray should hit the plane");
+        assert!((hit.x - 15.0).abs() < 0.01, "{hit:?}");
+        assert!((hit.y - 5.0).abs() < 0.01, "{hit:?}");
+        assert_eq!(hit.z, 0.0);
+    }
+
+    #[test]
+    fn march_heights_misses_out_of_range_and_empty_terrain() {
+        let from = Vector3::new(0.0, 20.0, 0.0);
+        let down = Vector3::new(0.0, -1.0, 0.0);
+        let flat = |_: Vector3, _: i32| Vector2::new(5.0, 5.0);
+        let empty = |_: Vector3, _: i32| Vector2::new(real::NAN, real::NAN);
+        assert_eq!(Terrain3D::march_heights(from, down, 10.0, 0.0, |_| 5.0, flat), None);
+        assert_eq!(Terrain3D::march_heights(from, down, 100.0, 0.0, |_| real::NAN, empty), None);
+        assert_eq!(Terrain3D::march_heights(from, Vector3::ZERO, 100.0, 0.0, |_| 5.0, flat), None);
+    }
+
+    #[test]
+    fn march_heights_skips_empty_space_with_the_pyramid() {
+        use std::cell::Cell;
+        let from = Vector3::new(10.5, 1000.0, 20.5);
+        let down = Vector3::new(0.0, -1.0, 0.0);
+        let samples = Cell::new(0);
+        let height = |_: Vector3| {
+            samples.set(samples.get() + 1);
+            0.0
+        };
+        let flat = |_: Vector3, _: i32| {
+            samples.set(samples.get() + 1);
+            Vector2::ZERO
+        };
+        let no_pyramid = |_: Vector3, _: i32| Vector2::new(real::NAN, real::NAN);
+
+        let hit = Terrain3D::march_heights(from, down, 2000.0, 0.0, height, flat).expect("ray should hit");
+        assert!(hit.y.abs() < 0.01, "{hit:?}");
+        let with_pyramid = samples.replace(0);
+
+        let hit = Terrain3D::march_heights(from, down, 2000.0, 0.0, height, no_pyramid).expect("ray should hit");
+        assert!(hit.y.abs() < 0.01, "{hit:?}");
+        let without_pyramid = samples.get();
+
+        assert!(with_pyramid * 50 < without_pyramid, "{with_pyramid} samples with the pyramid, {without_pyramid} without");
+    }
+
+    #[test]
+    fn skip_empty_space_stops_before_a_block_the_ray_could_touch() {
+        // Ridge of height 10 over x 32..64, flat 0 elsewhere
+        let range = |p_point: Vector3, p_lod: i32| {
+            let block = (1 << p_lod) as real;
+            let start = (p_point.x / block).floor() * block;
+            let ridge = start < 64.0 && start + block > 32.0;
+            Vector2::new(0.0, if ridge { 10.0 } else { 0.0 })
+        };
+        let along = Vector3::new(1.0, 0.0, 0.0);
+        // Above the ridge the whole 64 texel block is skipped, minus its last texel
+        let skip = Terrain3D::skip_empty_space(Vector3::new(0.5, 20.0, 0.5), along, &range).unwrap();
+        assert_eq!(skip, 62.5);
+        // Below it, only the 32 texel block before the ridge is
+        let skip = Terrain3D::skip_empty_space(Vector3::new(0.5, 5.0, 0.5), along, &range).unwrap();
+        assert_eq!(skip, 30.5);
+        assert_eq!(Terrain3D::skip_empty_space(Vector3::new(40.0, 5.0, 0.5), along, &range), None);
+    }
+
+    #[test]
+    fn record_transform_counts_only_moved_instances() {
+        let mut data = Instances::default();
+        let instances: Vec<Rid> = (1..=6).map(Rid::new).collect();
+        let at = |x: real| Transform3D::new(Basis::default(), Vector3::new(x, 0.0, 0.0));
+
+        // First snap places every instance
+        for rid in &instances {
+            assert!(data.record_transform(*rid, at(0.0)));
+        }
+        assert_eq!(data.transform_updates, 6);
+
+        // A small camera move only shifts the inner two instances
+        data.transform_updates = 0;
+        for (i, rid) in instances.iter().enumerate() {
+            let x = if i < 2 { 1.0 } else { 0.0 };
+            data.record_transform(*rid, at(x));
+        }
+        assert_eq!(data.transform_updates, 2);
+
+        // Standing still sends nothing
+        data.transform_updates = 0;
+        for (i, rid) in instances.iter().enumerate() {
+            let x = if i < 2 { 1.0 } else { 0.0 };
+            assert!(!data.record_transform(*rid, at(x)));
+        }
+        assert_eq!(data.transform_updates, 0);
+    }
+}
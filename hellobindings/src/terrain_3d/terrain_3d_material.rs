@@ -1,12 +1,16 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use godot::engine::fast_noise_lite::{
     CellularReturnType, DomainWarpFractalType, DomainWarpType, NoiseType,
 };
-use godot::engine::{FastNoiseLite, Gradient, INode3D, NoiseTexture2D, Resource, Shader, Texture};
+use godot::engine::object::ConnectFlags;
+use godot::engine::{
+    DirAccess, FastNoiseLite, FileAccess, Gradient, GradientTexture1D, INode3D, NoiseTexture2D,
+    Resource, Shader, Texture,
+};
 use godot::prelude::*;
 
-use crate::{log_debug, log_error, log_info};
+use crate::{log_debug, log_error, log_info, log_warn};
 
 use super::terrain_3d_core::{LogLevel, Terrain3D};
 use super::utils::rs;
@@ -26,22 +30,121 @@ enum TextureFiltering {
     Nearest = 1,
 }
 
+// Renderer the generated shader must compile on. Lighter profiles drop heavy inserts
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var, Export)]
+#[repr(i32)]
+pub enum RenderingProfile {
+    ForwardPlus = 0,
+    Mobile = 1,
+    Compatibility = 2,
+}
+
+// Textures blended per texel. Four decodes a splat style control layout, see blend_4way.glsl
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var, Export)]
+#[repr(i32)]
+pub enum BlendLayers {
+    Two = 0,
+    Four = 1,
+}
+
+// Well known uniforms of main.glsl for set_known_param(), so callers can't mistype them
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var, Export)]
+#[repr(i32)]
+pub enum KnownParam {
+    RegionSize = 0,
+    RegionTexelSize = 1,
+    BackgroundMode = 2,
+    HeightBlending = 3,
+    BlendSharpness = 4,
+    MacroVariation1 = 5,
+    MacroVariation2 = 6,
+    Noise1Scale = 7,
+    Noise1Angle = 8,
+    Noise1Offset = 9,
+    Noise2Scale = 10,
+    Noise3Scale = 11,
+}
+
+impl KnownParam {
+    const ALL: [KnownParam; 12] = [
+        KnownParam::RegionSize,
+        KnownParam::RegionTexelSize,
+        KnownParam::BackgroundMode,
+        KnownParam::HeightBlending,
+        KnownParam::BlendSharpness,
+        KnownParam::MacroVariation1,
+        KnownParam::MacroVariation2,
+        KnownParam::Noise1Scale,
+        KnownParam::Noise1Angle,
+        KnownParam::Noise1Offset,
+        KnownParam::Noise2Scale,
+        KnownParam::Noise3Scale,
+    ];
+
+    // None for values that are not a KnownParam
+    pub fn from_i32(p_value: i32) -> Option<KnownParam> {
+        Self::ALL.into_iter().find(|param| *param as i32 == p_value)
+    }
+
+    pub fn uniform_name(self) -> &'static str {
+        match self {
+            KnownParam::RegionSize => "_region_size",
+            KnownParam::RegionTexelSize => "_region_texel_size",
+            KnownParam::BackgroundMode => "_background_mode",
+            KnownParam::HeightBlending => "height_blending",
+            KnownParam::BlendSharpness => "blend_sharpness",
+            KnownParam::MacroVariation1 => "macro_variation1",
+            KnownParam::MacroVariation2 => "macro_variation2",
+            KnownParam::Noise1Scale => "noise1_scale",
+            KnownParam::Noise1Angle => "noise1_angle",
+            KnownParam::Noise1Offset => "noise1_offset",
+            KnownParam::Noise2Scale => "noise2_scale",
+            KnownParam::Noise3Scale => "noise3_scale",
+        }
+    }
+}
+
+// Terrains rendering with a material, by instance id. Reference counts can't tell
+// sharing apart, as the inspector and resource cache hold references of their own
+#[derive(Default)]
+struct MaterialUsers(HashSet<i64>);
+
+impl MaterialUsers {
+    fn add(&mut self, p_id: i64) {
+        self.0.insert(p_id);
+    }
+
+    // Returns whether no terrain uses the material anymore
+    fn remove(&mut self, p_id: i64) -> bool {
+        self.0.remove(&p_id);
+        self.0.is_empty()
+    }
+}
+
 #[derive(GodotClass)]
 #[class(tool,  base=Resource)]
 pub struct Terrain3DMaterial {
     base: Base<Resource>,
     initialized: bool,
+    users: MaterialUsers,
 
     #[var(get = get_material_rid)]
     material: Rid,
     shader: Rid,
 
+    #[var(get, set = set_shader_override_enable)]
+    #[export]
     shader_override_enable: bool,
+    // Godot emits changed on the override when its file is reimported, which rebuilds the shader
+    #[var(get, set = set_shader_override)]
+    #[export]
     shader_override: Option<Gd<Shader>>,
     shader_tmp: Gd<Shader>,
     shader_code: HashMap<String, String>,
     active_params: Vec<String>,
     shader_params: HashMap<String, Variant>,
+    // Names in shader_params set through set_shader_param(), as opposed to generated ones
+    user_params: HashSet<String>,
 
     // Material Features
     #[var]
@@ -53,6 +156,85 @@ pub struct Terrain3DMaterial {
     #[var(get, set = set_dual_scaling)]
     #[export]
     dual_scaling: bool,
+    #[var(get, set = set_rendering_profile)]
+    #[export]
+    rendering_profile: RenderingProfile,
+    #[var(get, set = set_blend_layers)]
+    #[export]
+    blend_layers: BlendLayers,
+    #[var(get, set = set_color_map_is_srgb)]
+    #[export]
+    color_map_is_srgb: bool,
+    #[var(get, set = set_world_noise)]
+    #[export]
+    world_noise: Option<Gd<FastNoiseLite>>,
+    // Generate noise_texture when the shader has it and it's unset. Off skips the
+    // texture entirely; features that need noise then use a blank sampler
+    #[var]
+    #[export]
+    auto_generate_noise: bool,
+    #[var(get, set = set_parallax_enabled)]
+    #[export]
+    parallax_enabled: bool,
+    #[var(get, set = set_parallax_depth)]
+    #[export]
+    parallax_depth: real,
+    #[var(get, set = set_parallax_steps)]
+    #[export]
+    parallax_steps: i32,
+    #[var(get, set = set_parallax_distance)]
+    #[export]
+    parallax_distance: real,
+    // Tint by altitude with height_gradient, between height_gradient_min and max
+    #[var(get, set = set_height_color_enabled)]
+    #[export]
+    height_color_enabled: bool,
+    #[var(get, set = set_height_gradient)]
+    #[export]
+    height_gradient: Option<Gd<Gradient>>,
+    #[var(get, set = set_height_gradient_min)]
+    #[export]
+    height_gradient_min: real,
+    #[var(get, set = set_height_gradient_max)]
+    #[export]
+    height_gradient_max: real,
+    height_gradient_texture: Option<Gd<GradientTexture1D>>,
+    // Apply the AO baked by Terrain3DStorage::bake_ambient_occlusion()
+    #[var(get, set = set_baked_ao)]
+    #[export]
+    baked_ao: bool,
+    #[var(get, set = set_fog_enabled)]
+    #[export]
+    fog_enabled: bool,
+    #[var(get, set = set_fog_color)]
+    #[export]
+    fog_color: Color,
+    #[var(get, set = set_fog_start)]
+    #[export]
+    fog_start: real,
+    #[var(get, set = set_fog_density)]
+    #[export]
+    fog_density: real,
+    // Invert the green channel of all normal maps, for DirectX style normals. Combines
+    // with the per texture flags of set_texture_normal_flip_y()
+    #[var(get, set = set_flip_normal_y)]
+    #[export]
+    flip_normal_y: bool,
+    // Bit per texture id set by set_texture_normal_flip_y(), exported so the flags are saved
+    #[var(get = get_texture_normal_flip_y_bits, set = set_texture_normal_flip_y_bits)]
+    #[export]
+    texture_normal_flip_y: u32,
+    // Called with the assembled shader code, returning the code to compile
+    shader_code_transform: Option<Callable>,
+    #[var(get = get_vertex_displacement, set = set_vertex_displacement)]
+    #[export]
+    vertex_displacement: bool,
+    #[var(get, set = set_displacement_amplitude)]
+    #[export]
+    displacement_amplitude: real,
+    #[var(get, set = set_displacement_speed)]
+    #[export]
+    displacement_speed: real,
 
     // Editor Functions / Debug views
     show_navigation: bool,
@@ -69,6 +251,7 @@ pub struct Terrain3DMaterial {
     debug_view_tex_normal: bool,
     debug_view_tex_rough: bool,
     debug_view_vertex_grid: bool,
+    debug_view_lod: bool,
 
     #[var(get, set = set_region_size)]
     region_size: i32,
@@ -81,6 +264,7 @@ impl IResource for Terrain3DMaterial {
         Self {
             base,
             initialized: false,
+            users: MaterialUsers::default(),
             shader_override_enable: false,
             material: Rid::Invalid,
             shader: Rid::Invalid,
@@ -89,11 +273,37 @@ impl IResource for Terrain3DMaterial {
             shader_tmp: Gd::default(),
             active_params: Vec::new(),
             shader_params: HashMap::new(),
+            user_params: HashSet::new(),
 
             world_background: WorldBackground::Flat,
+            auto_generate_noise: true,
             texture_filtering: TextureFiltering::Linear,
             auto_shader: false,
             dual_scaling: false,
+            rendering_profile: RenderingProfile::ForwardPlus,
+            blend_layers: BlendLayers::Two,
+            color_map_is_srgb: true,
+            world_noise: None,
+            parallax_enabled: false,
+            parallax_depth: 0.05,
+            parallax_steps: 16,
+            parallax_distance: 50.0,
+            height_color_enabled: false,
+            height_gradient: None,
+            height_gradient_min: 0.0,
+            height_gradient_max: 100.0,
+            height_gradient_texture: None,
+            baked_ao: false,
+            fog_enabled: false,
+            fog_color: Color::from_rgba(0.5, 0.6, 0.7, 1.0),
+            fog_start: 100.0,
+            fog_density: 0.002,
+            flip_normal_y: false,
+            texture_normal_flip_y: 0,
+            shader_code_transform: None,
+            vertex_displacement: false,
+            displacement_amplitude: 0.25,
+            displacement_speed: 0.5,
 
             show_navigation: false,
             debug_view_checkered: false,
@@ -109,6 +319,7 @@ impl IResource for Terrain3DMaterial {
             debug_view_tex_normal: false,
             debug_view_tex_rough: false,
             debug_view_vertex_grid: false,
+            debug_view_lod: false,
 
             region_size: 1024,
             region_sizev: Vector2i::new(1024, 1024),
@@ -119,6 +330,19 @@ impl IResource for Terrain3DMaterial {
 #[godot_api]
 impl Terrain3DMaterial {
     const __CLASS__: &'static str = "Terrain3DMaterial";
+    const DEFAULT_REGION_SIZE: i32 = 1024;
+    // Size of the _texture_*_array uniforms
+    const MAX_TEXTURES: i32 = 32;
+    // Texture uniforms bound to RIDs owned by other objects, such as Terrain3DStorage
+    const STORAGE_TEXTURE_PARAMS: [&'static str; 7] = [
+        "_height_maps",
+        "_control_maps",
+        "_color_maps",
+        "_texture_array_albedo",
+        "_texture_array_normal",
+        "_region_blend_map",
+        "_ao_maps",
+    ];
     // pub fn init_internal() -> Gd<Self> {
     //     let obj = Gd::from_init_fn(|base| {
     //         // accepts the base and returns a constructed object containing it
@@ -142,13 +366,338 @@ impl Terrain3DMaterial {
         self.dual_scaling = dual_scaling;
     }
 
+    #[func]
+    pub fn set_rendering_profile(&mut self, profile: RenderingProfile) {
+        log_debug!(Self, "Setting rendering profile: {:?}", profile);
+        self.rendering_profile = profile;
+        self.update_shader();
+    }
+
+    /**
+     * Switches between the 2 way base and overlay control decode and the 4 way splat
+     * decode. The auto shader only supports the 2 way layout and is disabled in 4 way mode.
+     */
+    #[func]
+    pub fn set_blend_layers(&mut self, layers: BlendLayers) {
+        log_debug!(Self, "Setting blend layers: {:?}", layers);
+        if layers == BlendLayers::Four && self.auto_shader {
+            log_warn!(Self, "The auto shader is not supported with 4 blend layers and is disabled");
+        }
+        self.blend_layers = layers;
+        self.update_shader();
+    }
+
+    /**
+     * Whether color maps hold sRGB data that the shader must convert to linear.
+     */
+    #[func]
+    pub fn set_color_map_is_srgb(&mut self, is_srgb: bool) {
+        log_debug!(Self, "Color map is sRGB: {is_srgb}");
+        self.color_map_is_srgb = is_srgb;
+        self.update_shader();
+    }
+
+    /**
+     * Sets the noise used to generate noise_texture. Null restores the default noise.
+     */
+    #[func]
+    pub fn set_world_noise(&mut self, noise: Option<Gd<FastNoiseLite>>) {
+        log_debug!(Self, "Setting world noise: {:?}", noise);
+        self.world_noise = noise;
+        if self.initialized {
+            self.generate_noise_texture();
+        }
+    }
+
+    #[func]
+    pub fn set_parallax_enabled(&mut self, enabled: bool) {
+        log_debug!(Self, "Enable parallax: {enabled}");
+        self.parallax_enabled = enabled;
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn set_parallax_depth(&mut self, depth: real) {
+        self.parallax_depth = depth.clamp(0.0, 0.5);
+        self.update_parallax_params();
+    }
+
+    #[func]
+    pub fn set_parallax_steps(&mut self, steps: i32) {
+        self.parallax_steps = steps.clamp(1, 64);
+        self.update_parallax_params();
+    }
+
+    #[func]
+    pub fn set_parallax_distance(&mut self, distance: real) {
+        self.parallax_distance = distance.max(0.0);
+        self.update_parallax_params();
+    }
+
+    // Parallax params are only pushed while the insert is in the shader
+    fn update_parallax_params(&mut self) {
+        if !self.initialized || !self.parallax_enabled {
+            return;
+        }
+        rs().material_set_param(self.material, "parallax_depth".into(), Variant::from(self.parallax_depth));
+        rs().material_set_param(self.material, "parallax_steps".into(), Variant::from(self.parallax_steps));
+        rs().material_set_param(self.material, "parallax_distance".into(), Variant::from(self.parallax_distance));
+    }
+
+    /**
+     * Tints the terrain by the _debug_lod_color instance uniform, which Terrain3D sets
+     * per clipmap LOD. See Terrain3D::set_debug_lod_colors().
+     */
+    #[func]
+    pub fn set_debug_view_lod(&mut self, enabled: bool) {
+        log_debug!(Self, "Enable debug_view_lod: {enabled}");
+        self.debug_view_lod = enabled;
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn set_height_color_enabled(&mut self, enabled: bool) {
+        log_debug!(Self, "Enable height color: {enabled}");
+        self.height_color_enabled = enabled;
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn set_height_gradient(&mut self, gradient: Option<Gd<Gradient>>) {
+        self.height_gradient = gradient;
+        self.height_gradient_texture = None;
+        self.update_height_gradient_params();
+    }
+
+    #[func]
+    pub fn set_height_gradient_min(&mut self, height: real) {
+        self.height_gradient_min = height;
+        self.update_height_gradient_params();
+    }
+
+    #[func]
+    pub fn set_height_gradient_max(&mut self, height: real) {
+        self.height_gradient_max = height;
+        self.update_height_gradient_params();
+    }
+
+    /**
+     * Bakes height_gradient into a 1D texture, or a white to white one if unset, and
+     * pushes it with the height range. Only while the insert is in the shader.
+     */
+    fn update_height_gradient_params(&mut self) {
+        if !self.initialized || !self.height_color_enabled {
+            return;
+        }
+        if self.height_gradient_texture.is_none() {
+            log_info!(Self, "Generating _height_gradient texture");
+            let gradient = self.height_gradient.clone().unwrap_or_else(|| {
+                let mut curve = Gradient::new_gd();
+                let mut pca = PackedColorArray::new();
+                pca.push(Color::from_rgba(1.0, 1.0, 1.0, 1.0));
+                pca.push(Color::from_rgba(1.0, 1.0, 1.0, 1.0));
+                curve.set_colors(pca);
+                curve
+            });
+            let mut texture = GradientTexture1D::new_gd();
+            texture.set_gradient(gradient);
+            self.height_gradient_texture = Some(texture);
+        }
+        if let Some(texture) = &self.height_gradient_texture {
+            rs().material_set_param(self.material, "_height_gradient".into(), Variant::from(texture.get_rid()));
+        }
+        rs().material_set_param(
+            self.material,
+            "_height_gradient_range".into(),
+            Variant::from(Vector2::new(self.height_gradient_min, self.height_gradient_max)),
+        );
+    }
+
+    #[func]
+    pub fn set_baked_ao(&mut self, enabled: bool) {
+        log_debug!(Self, "Enable baked AO: {enabled}");
+        self.baked_ao = enabled;
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn set_fog_enabled(&mut self, enabled: bool) {
+        log_debug!(Self, "Enable fog: {enabled}");
+        self.fog_enabled = enabled;
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn set_fog_color(&mut self, color: Color) {
+        self.fog_color = color;
+        self.update_fog_params();
+    }
+
+    #[func]
+    pub fn set_fog_start(&mut self, start: real) {
+        self.fog_start = start.max(0.0);
+        self.update_fog_params();
+    }
+
+    #[func]
+    pub fn set_fog_density(&mut self, density: real) {
+        self.fog_density = density.max(0.0);
+        self.update_fog_params();
+    }
+
+    // Fog params are only pushed while the insert is in the shader
+    fn update_fog_params(&mut self) {
+        if !self.initialized || !self.fog_enabled {
+            return;
+        }
+        rs().material_set_param(self.material, "fog_color".into(), Variant::from(self.fog_color));
+        rs().material_set_param(self.material, "fog_start".into(), Variant::from(self.fog_start));
+        rs().material_set_param(self.material, "fog_density".into(), Variant::from(self.fog_density));
+    }
+
+    #[func]
+    pub fn set_flip_normal_y(&mut self, enabled: bool) {
+        log_debug!(Self, "Flip normal map Y: {enabled}");
+        self.flip_normal_y = enabled;
+        self.update_shader();
+    }
+
+    /**
+     * Flags the normal map of texture_id as using the DirectX convention, so its green
+     * channel is inverted when sampled. Inverted again if flip_normal_y is on.
+     */
+    #[func]
+    pub fn set_texture_normal_flip_y(&mut self, texture_id: i32, flip: bool) {
+        if !(0..Self::MAX_TEXTURES).contains(&texture_id) {
+            log_error!(Self, "Texture id {} out of range 0..{}", texture_id, Self::MAX_TEXTURES);
+            return;
+        }
+        if flip {
+            self.texture_normal_flip_y |= 1 << texture_id;
+        } else {
+            self.texture_normal_flip_y &= !(1 << texture_id);
+        }
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn get_texture_normal_flip_y(&self, texture_id: i32) -> bool {
+        (0..Self::MAX_TEXTURES).contains(&texture_id)
+            && (self.texture_normal_flip_y >> texture_id) & 1 == 1
+    }
+
+    #[func]
+    pub fn set_texture_normal_flip_y_bits(&mut self, bits: u32) {
+        self.texture_normal_flip_y = bits;
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn get_texture_normal_flip_y_bits(&self) -> u32 {
+        self.texture_normal_flip_y
+    }
+
+    /**
+     * Returns the flag per texture id pushed as _texture_normal_flip_y_array, 1 where
+     * the normal map green channel is inverted, taking flip_normal_y into account.
+     */
+    #[func]
+    pub fn get_normal_flip_y_flags(&self) -> PackedInt32Array {
+        let mask = self.get_normal_flip_y_mask();
+        let mut flags = PackedInt32Array::new();
+        for i in 0..Self::MAX_TEXTURES {
+            flags.push(((mask >> i) & 1) as i32);
+        }
+        flags
+    }
+
+    fn get_normal_flip_y_mask(&self) -> u32 {
+        if self.flip_normal_y {
+            !self.texture_normal_flip_y
+        } else {
+            self.texture_normal_flip_y
+        }
+    }
+
+    #[func]
+    pub fn set_vertex_displacement(&mut self, enabled: bool) {
+        log_debug!(Self, "Enable vertex displacement: {enabled}");
+        self.vertex_displacement = enabled;
+        self.update_shader();
+    }
+
+    #[func]
+    pub fn get_vertex_displacement(&self) -> bool {
+        self.vertex_displacement
+    }
+
+    /**
+     * Upper bound on how far the shader moves vertices vertically beyond the heightmap,
+     * used to grow the mesh AABBs so displaced tiles aren't culled.
+     */
+    #[func]
+    pub fn get_vertical_displacement_margin(&self) -> real {
+        let mut margin = 0.0;
+        if self.vertex_displacement {
+            margin += self.displacement_amplitude;
+        }
+        if self.world_background == WorldBackground::Noise {
+            // world_noise_height is scaled up 10x in world_noise.glsl
+            let height = self
+                .shader_params
+                .get("world_noise_height")
+                .and_then(|v| v.try_to::<real>().ok())
+                .unwrap_or(64.0);
+            margin += height * 10.0;
+        }
+        margin
+    }
+
+    #[func]
+    pub fn set_displacement_amplitude(&mut self, amplitude: real) {
+        self.displacement_amplitude = amplitude.max(0.0);
+        if self.initialized {
+            rs().material_set_param(
+                self.material,
+                "displacement_amplitude".into(),
+                Variant::from(self.displacement_amplitude),
+            );
+        }
+    }
+
+    #[func]
+    pub fn set_displacement_speed(&mut self, speed: real) {
+        self.displacement_speed = speed;
+        if self.initialized {
+            rs().material_set_param(
+                self.material,
+                "displacement_speed".into(),
+                Variant::from(self.displacement_speed),
+            );
+        }
+    }
+
     #[func]
     pub fn set_region_size(&mut self, region_size: i32) {
         log_debug!(Self, "Setting region size in material: {region_size}");
 
+        // A size of 0 comes from uninitialized or corrupted data, and would push an
+        // infinite texel size that breaks all texel addressing in the shader
+        let region_size = if region_size <= 0 {
+            log_warn!(
+                Self,
+                "Invalid region size {}, using {}",
+                region_size,
+                Self::DEFAULT_REGION_SIZE
+            );
+            Self::DEFAULT_REGION_SIZE
+        } else {
+            region_size
+        };
         self.region_size = region_size.clamp(64, 4096);
         self.region_sizev = Vector2i::new(self.region_size, self.region_size);
 
+        let texel_size = 1.0f64 / self.region_size as f64;
         rs().material_set_param(
             self.material,
             "_region_size".into(),
@@ -156,8 +705,8 @@ impl Terrain3DMaterial {
         );
         rs().material_set_param(
             self.material,
-            "_region_pixel_size".into(),
-            Variant::from(1.0f64 / self.region_size as f64),
+            "_region_texel_size".into(),
+            Variant::from(texel_size),
         );
     }
 
@@ -166,9 +715,87 @@ impl Terrain3DMaterial {
         self.material
     }
 
+    /**
+     * Returns the sorted names of all parsed shader inserts, excluding the main shader.
+     * Shaders are loaded on first use so this works before initialize().
+     */
+    #[func]
+    pub fn get_insert_ids(&mut self) -> PackedStringArray {
+        if self.shader_code.is_empty() {
+            self.preload_shaders();
+        }
+        let mut ids: Vec<&String> = self.shader_code.keys().filter(|k| *k != "main").collect();
+        ids.sort();
+        let mut result = PackedStringArray::new();
+        for id in ids {
+            result.push(id.as_str().into());
+        }
+        result
+    }
+
+    /**
+     * Overlays the shader inserts with the .glsl files found in dir, replacing the built in
+     * snippets with matching keys, then rebuilds the shader. Each file is parsed like the
+     * built in ones, with its file name as the key of the code before the first insert.
+     * main.glsl replaces the main shader as a whole. Unreadable files are skipped.
+     */
+    #[func]
+    pub fn load_shader_inserts_from_dir(&mut self, dir: GString) {
+        if !DirAccess::dir_exists_absolute(dir.clone()) {
+            log_error!(Self, "Shader insert directory does not exist: {}", dir);
+            return;
+        }
+        if self.shader_code.is_empty() {
+            self.preload_shaders();
+        }
+        let files = match DirAccess::open(dir.clone()) {
+            Some(dir_access) => dir_access.get_files(),
+            None => {
+                log_error!(Self, "Could not open shader insert directory: {}", dir);
+                return;
+            }
+        };
+        for file in files.as_slice() {
+            let file = file.to_string();
+            let name = match file.strip_suffix(".glsl") {
+                Some(name) => name,
+                None => continue,
+            };
+            let path = format!("{}/{}", dir.to_string().trim_end_matches('/'), file);
+            let code = FileAccess::get_file_as_string(path.clone().into()).to_string();
+            if code.is_empty() {
+                log_warn!(Self, "Skipping unreadable or empty shader file: {}", path);
+                continue;
+            }
+            log_info!(Self, "Loading shader inserts from: {}", path);
+            if name == "main" {
+                self.shader_code.insert("main".into(), code);
+            } else {
+                Self::parse_shader(&mut self.shader_code, &code, name);
+            }
+        }
+        self.update_shader();
+    }
+
+    /**
+     * Whether the material has its RenderingServer shader and material, which happens once
+     * the first terrain using it is ready. Shared materials stay initialized for the others.
+     */
+    #[func]
+    pub fn is_initialized(&self) -> bool {
+        self.initialized
+    }
+
     pub fn initialize(&mut self, region_size: i32) {
+        if self.initialized {
+            log_debug!(Self, "Material already initialized, likely shared by another terrain");
+            return;
+        }
         log_info!(Self, "Initializing material");
-        self.preload_shaders();
+        // Keep inserts already loaded, such as those from load_shader_inserts_from_dir()
+        if self.shader_code.is_empty() {
+            self.preload_shaders();
+        }
 
         self.material = rs().material_create();
         self.shader = rs().shader_create();
@@ -185,6 +812,152 @@ impl Terrain3DMaterial {
         self.update_shader();
     }
 
+    /**
+     * Frees the material and shader RIDs right away and marks the material uninitialized,
+     * for deterministic teardown in tests and tools. Meshes using the material must be
+     * freed first. Safe to call repeatedly; initialize() creates them again.
+     */
+    #[func]
+    pub fn free_resources(&mut self) {
+        if !self.initialized {
+            return;
+        }
+        log_info!(Self, "Freeing material resources");
+        self.clear_texture_params();
+        rs().free_rid(self.material);
+        rs().free_rid(self.shader);
+        self.material = Rid::Invalid;
+        self.shader = Rid::Invalid;
+        self.active_params.clear();
+        self.initialized = false;
+    }
+
+    /**
+     * Registers terrain as rendering with this material. Repeated calls count once.
+     */
+    pub fn add_user(&mut self, terrain: InstanceId) {
+        self.users.add(terrain.to_i64());
+    }
+
+    /**
+     * Unregisters terrain, and unbinds the texture params once no other terrain renders
+     * with this material, so they don't reference storage textures about to be freed.
+     */
+    pub fn remove_user(&mut self, terrain: InstanceId) {
+        if self.users.remove(terrain.to_i64()) {
+            self.clear_texture_params();
+        }
+    }
+
+    /**
+     * Unbinds every texture param from the material, so nothing references storage or
+     * noise textures while they are freed. update_shader() binds them again.
+     */
+    pub fn clear_texture_params(&mut self) {
+        if !self.material.is_valid() {
+            return;
+        }
+        log_debug!(Self, "Clearing texture params");
+        let mut params: Vec<StringName> = self.active_params.iter().map(|p| p.into()).collect();
+        params.extend(Self::STORAGE_TEXTURE_PARAMS.iter().map(|p| StringName::from(*p)));
+        params.push("noise_texture".into());
+        for param in params {
+            if rs().material_get_param(self.material, param.clone()).get_type() == VariantType::Rid {
+                rs().material_set_param(self.material, param, Variant::nil());
+            }
+        }
+    }
+
+    #[func]
+    pub fn set_shader_override_enable(&mut self, enabled: bool) {
+        log_info!(Self, "Enable shader override: {enabled}");
+        self.shader_override_enable = enabled;
+        self.update_shader();
+    }
+
+    /**
+     * Assigns the override shader, moving the changed connection from the previous one.
+     * The connection is made right away, even before initialize(), so external edits
+     * and reimports are picked up whenever the material is live.
+     */
+    #[func]
+    pub fn set_shader_override(&mut self, shader: Option<Gd<Shader>>) {
+        let callable = self.to_gd().callable("update_shader");
+        if let Some(mut previous) = self.shader_override.take() {
+            if previous.is_connected("changed".into(), callable.clone()) {
+                log_debug!(Self, "Disconnecting changed signal of the previous shader override");
+                previous.disconnect("changed".into(), callable);
+            }
+        }
+        if let Some(mut shader) = shader.clone() {
+            self.connect_shader_override(&mut shader);
+        }
+        self.shader_override = shader;
+        self.update_shader();
+    }
+
+    // Deferred, as update_shader() itself may set the override code and emit changed
+    fn connect_shader_override(&self, p_shader: &mut Gd<Shader>) {
+        let callable = self.to_gd().callable("update_shader");
+        if !p_shader.is_connected("changed".into(), callable.clone()) {
+            log_debug!(Self, "Connecting changed signal to update_shader()");
+            p_shader
+                .connect_ex("changed".into(), callable)
+                .flags(ConnectFlags::DEFERRED.ord() as u32)
+                .done();
+        }
+    }
+
+    /**
+     * Registers a Callable that receives the final shader code, after inserts and editor
+     * code, and returns the code to compile instead. Pass an invalid Callable to remove it.
+     */
+    #[func]
+    pub fn set_shader_code_transform(&mut self, callable: Callable) {
+        self.shader_code_transform = if callable.is_valid() { Some(callable) } else { None };
+        self.update_shader();
+    }
+
+    // Runs shader_code_transform on p_code, keeping p_code if it doesn't return code
+    fn transform_shader_code(&self, p_code: String) -> String {
+        let callable = match &self.shader_code_transform {
+            Some(callable) if callable.is_valid() => callable,
+            _ => return p_code,
+        };
+        Self::apply_code_transform(p_code, |p_code| {
+            let result = callable.callv(varray![GString::from(p_code)]);
+            match result.try_to::<GString>() {
+                Ok(code) if !code.is_empty() => Some(code.to_string()),
+                _ => {
+                    log_warn!(Self, "Shader code transform returned {:?} instead of code, using the original", result.get_type());
+                    None
+                }
+            }
+        })
+    }
+
+    // p_code as p_transform rewrites it, or unchanged if it returns no code
+    fn apply_code_transform(p_code: String, p_transform: impl FnOnce(&str) -> Option<String>) -> String {
+        match p_transform(&p_code) {
+            Some(code) if !code.is_empty() => code,
+            _ => p_code,
+        }
+    }
+
+    /**
+     * Whether a feature visibly samples noise_texture: world noise, vertex displacement or
+     * a macro variation color other than white, which leaves the albedo unchanged.
+     */
+    fn needs_noise_texture(&self) -> bool {
+        let white = Color::from_rgba(1.0, 1.0, 1.0, 1.0);
+        let macro_variation = ["macro_variation1", "macro_variation2"].iter().any(|name| {
+            self.shader_params
+                .get(*name)
+                .map_or(false, |value| value.try_to::<Color>().map_or(true, |color| color != white))
+        });
+        self.world_background == WorldBackground::Noise || self.vertex_displacement || macro_variation
+    }
+
     #[func]
     fn update_shader(&mut self) {
         if !self.initialized {
@@ -206,20 +979,16 @@ impl Terrain3DMaterial {
                 shader_override.set_code(code.into());
             }
 
-            let s = self.to_gd();
-            let callable = s.callable("update_shader");
-
-            if !shader_override.is_connected("changed".into(), callable.clone()) {
-                log_debug!(Self, "Connecting changed signal to _update_shader()");
-                shader_override.connect("changed".into(), callable);
-            }
+            self.connect_shader_override(&mut shader_override);
             let code = shader_override.get_code().to_string();
             let code = self.inject_editor_code(&code);
+            let code = self.transform_shader_code(code);
             self.shader_tmp.set_code(code.into());
             shader_rid = Some(self.shader_tmp.get_rid());
         } else {
             let code = self.generate_shader_code();
             let code = self.inject_editor_code(&code);
+            let code = self.transform_shader_code(code);
             rs().shader_set_code(self.shader, code.into());
             shader_rid = Some(self.shader);
         }
@@ -235,31 +1004,21 @@ impl Terrain3DMaterial {
         }
 
         // Update custom shader params in RenderingServer
-        {
-            // Populate _active_params
-            // List<PropertyInfo> pi;
-            // _get_property_list(&pi);
-            // LOG(DEBUG, "_active_params: ", _active_params);
-            // Util::print_dict("_shader_params", _shader_params, DEBUG);
-        };
+        if let Some(shader_rid) = shader_rid {
+            // Populate _active_params with the uniforms of the shader just compiled
+            self.active_params = rs()
+                .get_shader_parameter_list(shader_rid)
+                .iter_shared()
+                .filter_map(|info| info.get("name").map(|name| name.to_string()))
+                .collect();
+            log_debug!(Self, "_active_params: {:?}", self.active_params);
+        }
 
-        // Fetch saved shader parameters, converting textures to RIDs
+        // Fetch saved shader parameters, converting textures to RIDs. Params of uniforms
+        // missing from this shader stay saved and apply once the uniform is back
         log_info!(Self, "Before setting texture to mats");
-        for param in self.active_params.iter() {
-            let value = self.shader_params.get(param);
-            if let Some(value) = value {
-                if value.get_type() == VariantType::Object {
-                    let tex: Gd<Texture> = value.to();
-                    if tex.is_instance_valid() {
-                        let v_rid = Variant::from(tex.get_rid());
-                        rs().material_set_param(self.material, param.into(), v_rid);
-                    } else {
-                        rs().material_set_param(self.material, param.into(), Variant::default());
-                    }
-                } else {
-                    rs().material_set_param(self.material, param.into(), value.clone());
-                }
-            }
+        for (param, value) in Self::saved_params_of(&self.active_params, &self.shader_params) {
+            self.apply_shader_param(param, value);
         }
 
         // Set specific shader parameters
@@ -268,79 +1027,217 @@ impl Terrain3DMaterial {
             "_background_mode".into(),
             Variant::from(self.world_background as i32),
         );
+        self.update_parallax_params();
+        self.update_fog_params();
+        self.update_height_gradient_params();
+        rs().material_set_param(
+            self.material,
+            "_texture_normal_flip_y_array".into(),
+            Variant::from(self.get_normal_flip_y_flags()),
+        );
+        if self.vertex_displacement {
+            rs().material_set_param(
+                self.material,
+                "displacement_amplitude".into(),
+                Variant::from(self.displacement_amplitude),
+            );
+            rs().material_set_param(
+                self.material,
+                "displacement_speed".into(),
+                Variant::from(self.displacement_speed),
+            );
+        }
 
-        // If no noise texture, generate one
+        self.update_noise_texture();
+
+        //self.notify_property_list_changed();
+    }
+
+    /**
+     * Generates noise_texture if the shader samples it and none is set. Also runs when a
+     * param is set, since enabling macro variation alone makes the noise needed.
+     */
+    fn update_noise_texture(&mut self) {
         let noise_texture_name = "noise_texture".to_owned();
-        if self.active_params.contains(&noise_texture_name)
+        if self.auto_generate_noise
+            && self.needs_noise_texture()
+            && self.active_params.contains(&noise_texture_name)
             && rs()
                 .material_get_param(self.material, noise_texture_name.into())
                 .get_type()
                 == VariantType::Nil
         {
-            log_info!(Self, "Generating default noise_texture for shader");
-
-            let mut fnoise = FastNoiseLite::new_gd();
-            fnoise.set_noise_type(NoiseType::CELLULAR);
-            fnoise.set_frequency(0.03);
-            fnoise.set_cellular_jitter(3.0);
-            fnoise.set_cellular_return_type(CellularReturnType::CELL_VALUE);
-            fnoise.set_domain_warp_enabled(true);
-            fnoise.set_domain_warp_type(DomainWarpType::SIMPLEX_REDUCED);
-            fnoise.set_domain_warp_amplitude(50.0);
-            fnoise.set_domain_warp_fractal_type(DomainWarpFractalType::INDEPENDENT);
-            fnoise.set_domain_warp_fractal_lacunarity(1.5);
-            fnoise.set_domain_warp_fractal_gain(1.0);
-
-            let mut curve = Gradient::new_gd();
-            let mut pfa = PackedFloat32Array::new();
-            pfa.push(0.2);
-            pfa.push(1.0);
-            curve.set_offsets(pfa);
-            let mut pca = PackedColorArray::new();
-            pca.push(Color::from_rgba(1.0, 1.0, 1.0, 1.0));
-            pca.push(Color::from_rgba(0.0, 0.0, 0.0, 1.0));
-            curve.set_colors(pca);
-
-            let mut noise_tex = NoiseTexture2D::new_gd();
-            noise_tex.set_seamless(true);
-            noise_tex.set_generate_mipmaps(true);
-            noise_tex.set_noise(fnoise.upcast());
-            noise_tex.set_color_ramp(curve);
-
-            let pname = "noise_texture";
-            let pname: StringName = pname.into();
-            self.set(&pname, &noise_tex.to_variant());
+            self.generate_noise_texture();
         }
+    }
 
-        //self.notify_property_list_changed();
+    /**
+     * Builds noise_texture from world_noise, or from the default cellular noise if unset.
+     */
+    fn generate_noise_texture(&mut self) {
+        let fnoise = match &self.world_noise {
+            Some(noise) => {
+                log_info!(Self, "Generating noise_texture for shader from world_noise");
+                noise.clone()
+            }
+            None => {
+                log_info!(Self, "Generating default noise_texture for shader");
+                let mut fnoise = FastNoiseLite::new_gd();
+                fnoise.set_noise_type(NoiseType::CELLULAR);
+                fnoise.set_frequency(0.03);
+                fnoise.set_cellular_jitter(3.0);
+                fnoise.set_cellular_return_type(CellularReturnType::CELL_VALUE);
+                fnoise.set_domain_warp_enabled(true);
+                fnoise.set_domain_warp_type(DomainWarpType::SIMPLEX_REDUCED);
+                fnoise.set_domain_warp_amplitude(50.0);
+                fnoise.set_domain_warp_fractal_type(DomainWarpFractalType::INDEPENDENT);
+                fnoise.set_domain_warp_fractal_lacunarity(1.5);
+                fnoise.set_domain_warp_fractal_gain(1.0);
+                fnoise
+            }
+        };
+
+        let mut curve = Gradient::new_gd();
+        let mut pfa = PackedFloat32Array::new();
+        pfa.push(0.2);
+        pfa.push(1.0);
+        curve.set_offsets(pfa);
+        let mut pca = PackedColorArray::new();
+        pca.push(Color::from_rgba(1.0, 1.0, 1.0, 1.0));
+        pca.push(Color::from_rgba(0.0, 0.0, 0.0, 1.0));
+        curve.set_colors(pca);
+
+        let mut noise_tex = NoiseTexture2D::new_gd();
+        noise_tex.set_seamless(true);
+        noise_tex.set_generate_mipmaps(true);
+        noise_tex.set_noise(fnoise.upcast());
+        noise_tex.set_color_ramp(curve);
+
+        let pname = "noise_texture";
+        let pname: StringName = pname.into();
+        self.set(&pname, &noise_tex.to_variant());
+    }
+
+    /**
+     * Sets a shader uniform by name. The value is kept across shader rebuilds, including
+     * while the uniform is absent because its feature is off. Textures are accepted and
+     * bound by RID.
+     */
+    #[func]
+    pub fn set_shader_param(&mut self, name: StringName, value: Variant) {
+        let key = name.to_string();
+        if Self::is_unknown_uniform(self.initialized, &self.active_params, &key) {
+            log_warn!(Self, "Shader has no uniform '{}'. Kept in case a feature adds it, check for typos", key);
+        }
+        self.user_params.insert(key.clone());
+        self.shader_params.insert(key.clone(), value.clone());
+        if self.initialized && self.active_params.contains(&key) {
+            self.apply_shader_param(&key, &value);
+            self.update_noise_texture();
+        }
+    }
+
+    /**
+     * Sets one of the built in uniforms by its KnownParam value instead of by name, so a
+     * typo is an error instead of a silently unused param. Values that are not a
+     * KnownParam are logged and ignored.
+     */
+    #[func]
+    pub fn set_known_param(&mut self, param: i32, value: Variant) {
+        match KnownParam::from_i32(param) {
+            Some(known) => self.set_shader_param(known.uniform_name().into(), value),
+            None => log_error!(Self, "Unknown KnownParam {}, param not set", param),
+        }
+    }
+
+    /**
+     * Returns the uniform name set_known_param() maps param to, or an empty name if param
+     * is not a KnownParam.
+     */
+    #[func]
+    pub fn get_known_param_name(&self, param: i32) -> StringName {
+        match KnownParam::from_i32(param) {
+            Some(known) => known.uniform_name().into(),
+            None => {
+                log_error!(Self, "Unknown KnownParam {}", param);
+                StringName::default()
+            }
+        }
+    }
+
+    // Whether set_shader_param() warns about p_name, a name the compiled shader lacks.
+    // Before the first compile there is nothing to check against
+    fn is_unknown_uniform(p_initialized: bool, p_active_params: &[String], p_name: &str) -> bool {
+        p_initialized && !p_active_params.iter().any(|param| param == p_name)
+    }
+
+    #[func]
+    pub fn get_shader_param(&self, name: StringName) -> Variant {
+        self.shader_params
+            .get(&name.to_string())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /**
+     * Forgets the params set with set_shader_param(), restoring the shader defaults.
+     * Params the material generates itself, such as noise_texture, are kept.
+     */
+    #[func]
+    pub fn reset_shader_params(&mut self) {
+        log_info!(Self, "Resetting {} user shader params", self.user_params.len());
+        for param in std::mem::take(&mut self.user_params) {
+            self.shader_params.remove(&param);
+            if self.initialized {
+                rs().material_set_param(self.material, param.into(), Variant::nil());
+            }
+        }
+    }
+
+    // The saved params of p_active_params, the uniforms of the compiled shader
+    fn saved_params_of<'a, V>(
+        p_active_params: &'a [String],
+        p_saved: &'a HashMap<String, V>,
+    ) -> Vec<(&'a str, &'a V)> {
+        p_active_params
+            .iter()
+            .filter_map(|param| p_saved.get(param).map(|value| (param.as_str(), value)))
+            .collect()
+    }
+
+    // Pushes one param to the RenderingServer, which only takes textures as RIDs
+    fn apply_shader_param(&self, p_name: &str, p_value: &Variant) {
+        if p_value.get_type() == VariantType::Object {
+            let tex: Gd<Texture> = p_value.to();
+            if tex.is_instance_valid() {
+                rs().material_set_param(self.material, p_name.into(), Variant::from(tex.get_rid()));
+            } else {
+                rs().material_set_param(self.material, p_name.into(), Variant::default());
+            }
+        } else {
+            rs().material_set_param(self.material, p_name.into(), p_value.clone());
+        }
     }
 
     fn set(&mut self, p_name: &StringName, p_property: &Variant) -> bool {
         let p_name0: String = p_name.into();
-        if !self.initialized || self.active_params.contains(&p_name0) {
-            self.base_mut().set(p_name.clone(), p_property.clone());
-            return true;
+        if !self.initialized || !self.active_params.contains(&p_name0) {
+            // Not a uniform of the current shader, keep it for when it is
+            self.shader_params.insert(p_name0, p_property.clone());
+            return false;
         }
 
         if p_property.get_type() == VariantType::Nil {
+            self.shader_params.remove(&p_name0);
             rs().material_set_param(self.material, p_name.clone(), Variant::default());
             return true;
         }
 
         // If value is an object, assume a Texture. RS only wants RIDs, but
         // Inspector wants the object, so set the RID and save the latter for _get
-        if p_property.get_type() == VariantType::Object {
-            let tex: Gd<Texture> = p_property.to();
-            if tex.is_instance_valid() {
-                let v_rid = Variant::from(tex.get_rid());
-                rs().material_set_param(self.material, p_name.clone(), v_rid);
-            } else {
-                rs().material_set_param(self.material, p_name.clone(), Variant::default());
-            }
-        } else {
-            self.shader_params.insert(p_name0, p_property.clone());
-            rs().material_set_param(self.material, p_name.clone(), p_property.clone());
-        }
+        self.apply_shader_param(&p_name0, p_property);
+        self.shader_params.insert(p_name0, p_property.clone());
+        self.update_noise_texture();
         true
     }
 
@@ -391,6 +1288,9 @@ impl Terrain3DMaterial {
         if self.debug_view_vertex_grid {
             insert_names.push("DEBUG_VERTEX_GRID");
         }
+        if self.debug_view_lod {
+            insert_names.push("DEBUG_LOD");
+        }
         if self.show_navigation {
             insert_names.push("EDITOR_NAVIGATION");
         }
@@ -410,8 +1310,15 @@ impl Terrain3DMaterial {
     fn generate_shader_code(&self) -> String {
         log_info!(Self, "Generating default shader code");
 
+        // Mobile and Compatibility renderers can't afford world noise or dual scaling,
+        // Compatibility also drops vertex displacement
+        let full_profile = self.rendering_profile == RenderingProfile::ForwardPlus;
+        let dual_scaling = self.dual_scaling && full_profile;
+        let vertex_displacement = self.vertex_displacement
+            && self.rendering_profile != RenderingProfile::Compatibility;
+
         let mut excludes: Vec<&str> = Vec::new();
-        if self.world_background != WorldBackground::Noise {
+        if self.world_background != WorldBackground::Noise || !full_profile {
             excludes.push("WORLD_NOISE1");
             excludes.push("WORLD_NOISE2");
         }
@@ -420,13 +1327,22 @@ impl Terrain3DMaterial {
         } else {
             excludes.push("TEXTURE_SAMPLERS_LINEAR");
         }
-        if self.auto_shader {
+        let four_way = self.blend_layers == BlendLayers::Four;
+        if four_way {
             excludes.push("TEXTURE_ID");
-        } else {
             excludes.push("AUTO_SHADER_UNIFORMS");
             excludes.push("AUTO_SHADER_TEXTURE_ID");
+        } else {
+            excludes.push("TEXTURE_ID_4WAY");
+            excludes.push("BLEND_4WAY");
+            if self.auto_shader {
+                excludes.push("TEXTURE_ID");
+            } else {
+                excludes.push("AUTO_SHADER_UNIFORMS");
+                excludes.push("AUTO_SHADER_TEXTURE_ID");
+            }
         }
-        if self.dual_scaling {
+        if dual_scaling {
             excludes.push("UNI_SCALING_BASE");
         } else {
             excludes.push("DUAL_SCALING_UNIFORMS");
@@ -434,20 +1350,55 @@ impl Terrain3DMaterial {
             excludes.push("DUAL_SCALING_BASE");
             excludes.push("DUAL_SCALING_OVERLAY");
         }
+        if !self.parallax_enabled || !full_profile {
+            excludes.push("PARALLAX_UNIFORMS");
+            excludes.push("PARALLAX");
+        }
+        if !self.debug_view_lod {
+            excludes.push("DEBUG_LOD_UNIFORMS");
+        }
+        if !self.height_color_enabled {
+            excludes.push("HEIGHT_GRADIENT_UNIFORMS");
+            excludes.push("HEIGHT_GRADIENT");
+        }
+        if !self.baked_ao {
+            excludes.push("AO_UNIFORMS");
+            excludes.push("AO");
+        }
+        if !self.fog_enabled {
+            excludes.push("FOG_UNIFORMS");
+            excludes.push("FOG");
+        }
+        if self.get_normal_flip_y_mask() == 0 {
+            excludes.push("NORMAL_FLIP_Y_BASE");
+            excludes.push("NORMAL_FLIP_Y_OVERLAY");
+        }
+        if !self.color_map_is_srgb {
+            excludes.push("COLOR_SRGB");
+        }
+        if !vertex_displacement {
+            excludes.push("VERTEX_DISPLACEMENT_UNIFORMS");
+            excludes.push("VERTEX_DISPLACEMENT");
+        }
 
         let p_shader = self
             .shader_code
             .get("main")
             .expect("main shader parse error");
-        self.apply_inserts(p_shader, excludes)
+        Self::apply_inserts(&self.shader_code, p_shader, excludes)
     }
 
     /**
      *	`//INSERT: ID` blocks in p_shader are replaced by the entry in the DB
      *	returns a shader string with inserts applied
-     *  Skips `EDITOR_*` and `DEBUG_*` inserts
+     *  Skips `EDITOR_*` and `DEBUG_*` inserts, which inject_editor_code() places at the end
+     *  of fragment(). `DEBUG_*_UNIFORMS` are kept, gated by excludes like the others
      */
-    fn apply_inserts(&self, p_shader: &str, excludes: Vec<&str>) -> String {
+    fn apply_inserts(
+        p_shader_code: &HashMap<String, String>,
+        p_shader: &str,
+        excludes: Vec<&str>,
+    ) -> String {
         let parsed = p_shader.split("//INSERT:");
         let mut shader = "".to_owned();
         for (i, token) in parsed.enumerate() {
@@ -465,12 +1416,12 @@ impl Terrain3DMaterial {
                 let id = segment[0].trim();
                 // Process the insert
                 if !id.is_empty()
-                    && !id.starts_with("DEBUG_")
+                    && (!id.starts_with("DEBUG_") || id.ends_with("_UNIFORMS"))
                     && !id.starts_with("EDITOR_")
                     && !excludes.contains(&id)
-                    && self.shader_code.contains_key(id)
+                    && p_shader_code.contains_key(id)
                 {
-                    shader += &self.shader_code[id];
+                    shader += &p_shader_code[id];
                 }
                 shader += segment[1];
             }
@@ -480,18 +1431,7 @@ impl Terrain3DMaterial {
     }
 
     fn preload_shaders(&mut self) {
-        self.parse_shader(include_str!("shaders/uniforms.glsl"), "uniforms");
-        self.parse_shader(include_str!("shaders/world_noise.glsl"), "world_noise");
-        self.parse_shader(include_str!("shaders/auto_shader.glsl"), "auto_shader");
-        self.parse_shader(include_str!("shaders/dual_scaling.glsl"), "dual_scaling");
-        self.parse_shader(include_str!("shaders/debug_views.glsl"), "debug_views");
-        self.parse_shader(
-            include_str!("shaders/editor_functions.glsl"),
-            "editor_functions",
-        );
-
-        self.shader_code
-            .insert("main".into(), include_str!("shaders/main.glsl").into());
+        Self::load_builtin_shaders(&mut self.shader_code);
 
         if Terrain3D::debug_level() >= &LogLevel::DEBUG {
             for key in self.shader_code.keys() {
@@ -500,7 +1440,46 @@ impl Terrain3DMaterial {
         }
     }
 
-    fn parse_shader(&mut self, p_shader: &str, p_name: &str) {
+    // Parses the shaders shipped with the plugin into p_shader_code
+    fn load_builtin_shaders(p_shader_code: &mut HashMap<String, String>) {
+        Self::parse_shader(p_shader_code, include_str!("shaders/uniforms.glsl"), "uniforms");
+        Self::parse_shader(p_shader_code, include_str!("shaders/world_noise.glsl"), "world_noise");
+        Self::parse_shader(p_shader_code, include_str!("shaders/auto_shader.glsl"), "auto_shader");
+        Self::parse_shader(
+            p_shader_code,
+            include_str!("shaders/dual_scaling.glsl"),
+            "dual_scaling",
+        );
+        Self::parse_shader(
+            p_shader_code,
+            include_str!("shaders/displacement.glsl"),
+            "displacement",
+        );
+        Self::parse_shader(p_shader_code, include_str!("shaders/parallax.glsl"), "parallax");
+        Self::parse_shader(p_shader_code, include_str!("shaders/fog.glsl"), "fog");
+        Self::parse_shader(p_shader_code, include_str!("shaders/blend_4way.glsl"), "blend_4way");
+        Self::parse_shader(p_shader_code, include_str!("shaders/normal_flip.glsl"), "normal_flip");
+        Self::parse_shader(
+            p_shader_code,
+            include_str!("shaders/ambient_occlusion.glsl"),
+            "ambient_occlusion",
+        );
+        Self::parse_shader(
+            p_shader_code,
+            include_str!("shaders/height_gradient.glsl"),
+            "height_gradient",
+        );
+        Self::parse_shader(p_shader_code, include_str!("shaders/debug_views.glsl"), "debug_views");
+        Self::parse_shader(
+            p_shader_code,
+            include_str!("shaders/editor_functions.glsl"),
+            "editor_functions",
+        );
+
+        p_shader_code.insert("main".into(), include_str!("shaders/main.glsl").into());
+    }
+
+    fn parse_shader(p_shader_code: &mut HashMap<String, String>, p_shader: &str, p_name: &str) {
         if p_name.is_empty() {
             // push_error(Variant::from("No dictionary key for saving shader snippets specified"));
             log_error!(
@@ -516,7 +1495,7 @@ impl Terrain3DMaterial {
         for (i, token) in parsed.enumerate() {
             // First section of the file before any //INSERT:
             if i == 0 {
-                self.shader_code.insert(p_name.into(), token.into());
+                p_shader_code.insert(p_name.into(), token.into());
             } else {
                 // There is at least one //INSERT:
                 // Get the first ID on the first line
@@ -528,9 +1507,95 @@ impl Terrain3DMaterial {
                 let id = segment[0].trim();
                 // Process the insert
                 if !id.is_empty() && !segment[1].is_empty() {
-                    self.shader_code.insert(id.into(), segment[1].into());
+                    p_shader_code.insert(id.into(), segment[1].into());
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_srgb_insert_follows_its_exclude() {
+        let mut shader_code = HashMap::new();
+        Terrain3DMaterial::load_builtin_shaders(&mut shader_code);
+        let conversion = "pow((color_map.rgb + vec3(0.055))";
+
+        let srgb = Terrain3DMaterial::apply_inserts(&shader_code, &shader_code["main"], vec![]);
+        assert!(srgb.contains(conversion));
+
+        let linear = Terrain3DMaterial::apply_inserts(
+            &shader_code,
+            &shader_code["main"],
+            vec!["COLOR_SRGB"],
+        );
+        assert!(!linear.contains(conversion));
+    }
+
+    #[test]
+    fn material_users_clear_only_after_the_last_terrain_leaves() {
+        let mut users = MaterialUsers::default();
+        users.add(1);
+        users.add(2);
+        users.add(2);
+        assert!(!users.remove(1));
+        // Removing a terrain twice doesn't count as another terrain leaving
+        assert!(!users.remove(1));
+        assert!(users.remove(2));
+    }
+
+    #[test]
+    fn saved_params_persist_while_their_uniform_is_absent() {
+        let mut saved = HashMap::new();
+        saved.insert("fog_density".to_owned(), 0.3);
+        let with_fog = vec!["_region_size".to_owned(), "fog_density".to_owned()];
+        let without_fog = vec!["_region_size".to_owned()];
+
+        assert_eq!(Terrain3DMaterial::saved_params_of(&with_fog, &saved), vec![("fog_density", &0.3)]);
+        // Fog turned off, the shader no longer has the uniform
+        assert!(Terrain3DMaterial::saved_params_of(&without_fog, &saved).is_empty());
+        // And back on, the param applies again
+        assert_eq!(Terrain3DMaterial::saved_params_of(&with_fog, &saved), vec![("fog_density", &0.3)]);
+    }
+
+    #[test]
+    fn known_params_map_to_their_uniforms() {
+        assert_eq!(KnownParam::from_i32(0).map(KnownParam::uniform_name), Some("_region_size"));
+        assert_eq!(KnownParam::from_i32(2).map(KnownParam::uniform_name), Some("_background_mode"));
+        assert_eq!(KnownParam::from_i32(4).map(KnownParam::uniform_name), Some("blend_sharpness"));
+        assert_eq!(KnownParam::from_i32(-1), None);
+        assert_eq!(KnownParam::from_i32(KnownParam::ALL.len() as i32), None);
+        for (i, param) in KnownParam::ALL.iter().enumerate() {
+            assert_eq!(*param as i32, i as i32);
+        }
+    }
+
+    #[test]
+    fn unknown_uniform_names_warn_once_compiled() {
+        let active = vec!["_region_size".to_owned(), "blend_sharpness".to_owned()];
+        assert!(Terrain3DMaterial::is_unknown_uniform(true, &active, "_regon_size"));
+        assert!(!Terrain3DMaterial::is_unknown_uniform(true, &active, "_region_size"));
+        assert!(!Terrain3DMaterial::is_unknown_uniform(false, &[], "_regon_size"));
+    }
+
+    #[test]
+    fn code_transform_output_is_the_compiled_code() {
+        let mut shader_code = HashMap::new();
+        Terrain3DMaterial::load_builtin_shaders(&mut shader_code);
+        let code = Terrain3DMaterial::apply_inserts(&shader_code, &shader_code["main"], vec![]);
+        let tint = "\n\tALBEDO *= vec3(1.0, 0.5, 0.5);\n}";
+
+        let transformed = Terrain3DMaterial::apply_code_transform(code.clone(), |p_code| {
+            p_code.rfind('}').map(|idx| p_code[..idx].to_owned() + tint)
+        });
+        assert!(transformed.ends_with(tint));
+        assert!(transformed.starts_with(&code[..code.rfind('}').unwrap()]));
+
+        // A transform without code keeps the generated one
+        assert_eq!(Terrain3DMaterial::apply_code_transform(code.clone(), |_| None), code);
+        assert_eq!(Terrain3DMaterial::apply_code_transform(code.clone(), |_| Some(String::new())), code);
+    }
+}
@@ -1,3 +1,9 @@
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+
+use godot::engine::global::Error;
+use godot::engine::image::{Format, Interpolation, UsedChannels};
+use godot::engine::{HeightMapShape3D, RandomNumberGenerator, Resource, ResourceLoader, ResourceSaver};
 use godot::{engine::Image, prelude::*};
 
 use crate::{log_debug, log_error, log_info, log_warn};
@@ -16,6 +22,92 @@ impl MapType {
     pub fn ord(self) -> usize {
         return self as usize;
     }
+
+    pub fn format(self) -> Format {
+        match self {
+            MapType::TYPE_HEIGHT => Format::RF,
+            MapType::TYPE_CONTROL => Format::RF,
+            MapType::TYPE_COLOR => Format::RGBA8,
+            MapType::TYPE_MAX => Format::MAX,
+        }
+    }
+
+    // Bytes per texel of format()
+    pub fn bytes_per_pixel(self) -> i64 {
+        match self {
+            MapType::TYPE_HEIGHT | MapType::TYPE_CONTROL | MapType::TYPE_COLOR => 4,
+            MapType::TYPE_MAX => 0,
+        }
+    }
+}
+
+// Control map bit layout, stored as u32 bits in a FORMAT_RF image. See main.glsl.
+#[inline]
+pub fn control_is_hole(p_control: u32) -> bool {
+    (p_control >> 2) & 0x1 == 1
+}
+
+// Flag bits shared by both control layouts: autoshader, navigation and hole
+pub const CONTROL_FLAGS_MASK: u32 = 0x7;
+
+/**
+ * Packs the standard control layout: base texture id in bits 27-31, overlay texture id
+ * in 22-26 and the 0 to 255 blend between them in 14-21. p_flags keeps the low 3 bits.
+ */
+pub fn control_pack(p_base: u32, p_overlay: u32, p_blend: u32, p_flags: u32) -> u32 {
+    ((p_base & 0x1F) << 27) | ((p_overlay & 0x1F) << 22) | ((p_blend & 0xFF) << 14) | (p_flags & CONTROL_FLAGS_MASK)
+}
+
+#[inline]
+pub fn control_get_base(p_control: u32) -> u32 {
+    (p_control >> 27) & 0x1F
+}
+
+#[inline]
+pub fn control_get_overlay(p_control: u32) -> u32 {
+    (p_control >> 22) & 0x1F
+}
+
+#[inline]
+pub fn control_get_blend(p_control: u32) -> u32 {
+    (p_control >> 14) & 0xFF
+}
+
+/**
+ * Packs the 4 way splat control layout decoded by blend_4way.glsl. Texture ids take
+ * 5 bits each at 27, 22, 17 and 12. p_weights are the 0 to 7 weights of textures 1-3
+ * at 9, 6 and 3, texture 0 gets the remainder of 7. p_flags keeps the low 3 bits.
+ */
+pub fn control_pack_4way(p_textures: [u32; 4], p_weights: [u32; 3], p_flags: u32) -> u32 {
+    let mut control = p_flags & CONTROL_FLAGS_MASK;
+    for (i, shift) in [27, 22, 17, 12].iter().enumerate() {
+        control |= (p_textures[i] & 0x1F) << shift;
+    }
+    for (i, shift) in [9, 6, 3].iter().enumerate() {
+        control |= p_weights[i].min(7) << shift;
+    }
+    control
+}
+
+// Control maps store the u32 bits in the red channel of a FORMAT_RF texel
+#[inline]
+pub fn control_to_color(p_control: u32) -> Color {
+    Color::from_rgba(f32::from_bits(p_control), 0.0, 0.0, 1.0)
+}
+
+#[inline]
+pub fn control_from_color(p_color: Color) -> u32 {
+    p_color.r.to_bits()
+}
+
+// What get_height() returns where there is no region
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var, Export)]
+#[repr(i32)]
+pub enum HeightPolicy {
+    Nan = 0,
+    Zero = 1,
+    // Height of the nearest texel of the nearest region
+    ClampToNearest = 2,
 }
 
 #[derive(Debug, PartialEq, PartialOrd, Clone, Copy, Var)]
@@ -32,6 +124,132 @@ impl RegionSize {
     pub fn ord(self) -> i32 {
         return self as i32;
     }
+
+    pub fn from_ord(p_ord: i32) -> Option<Self> {
+        [
+            RegionSize::SIZE_64,
+            RegionSize::SIZE_128,
+            RegionSize::SIZE_256,
+            RegionSize::SIZE_512,
+            RegionSize::SIZE_1024,
+            RegionSize::SIZE_2048,
+        ]
+        .into_iter()
+        .find(|size| size.ord() == p_ord)
+    }
+}
+
+/**
+ * Min/max heights of one region over blocks of 2^lod texels, lod 1 being 2x2 blocks.
+ * levels[lod - 1] holds (size >> lod)^2 entries in row major order.
+ */
+struct HeightPyramid {
+    levels: Vec<Vec<Vector2>>,
+}
+
+impl HeightPyramid {
+    fn build(p_image: &Gd<Image>) -> Self {
+        let size = p_image.get_width().min(p_image.get_height()).max(1);
+        let mut levels: Vec<Vec<Vector2>> = Vec::new();
+        let mut level_size = size / 2;
+        let mut prev: Vec<Vector2> = Vec::new();
+        while level_size >= 1 {
+            let mut level = Vec::with_capacity((level_size * level_size) as usize);
+            for y in 0..level_size {
+                for x in 0..level_size {
+                    let mut range = Vector2::new(real::MAX, real::MIN);
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let (sx, sy) = (x * 2 + dx, y * 2 + dy);
+                        let v = if levels.is_empty() {
+                            let h = p_image.get_pixel(sx, sy).r;
+                            Vector2::new(h, h)
+                        } else {
+                            prev[(sy * level_size * 2 + sx) as usize]
+                        };
+                        range.x = range.x.min(v.x);
+                        range.y = range.y.max(v.y);
+                    }
+                    level.push(range);
+                }
+            }
+            prev = level.clone();
+            levels.push(level);
+            level_size /= 2;
+        }
+        HeightPyramid { levels }
+    }
+
+    fn max_lod(&self) -> i32 {
+        self.levels.len() as i32
+    }
+
+    fn get(&self, p_lod: i32, p_local: Vector2i) -> Vector2 {
+        let level = &self.levels[(p_lod - 1) as usize];
+        let level_size = (level.len() as f64).sqrt() as i32;
+        let x = (p_local.x >> p_lod).clamp(0, level_size - 1);
+        let y = (p_local.y >> p_lod).clamp(0, level_size - 1);
+        level[(y * level_size + x) as usize]
+    }
+}
+
+// Layers edited since the last update_regions() per map type, uploaded in place by
+// update_dirty_layers() instead of regenerating the whole texture array
+#[derive(Default)]
+struct DirtyLayers([HashSet<i32>; MapType::TYPE_MAX as usize]);
+
+impl DirtyLayers {
+    fn mark(&mut self, p_map_type: MapType, p_layer: i32) {
+        self.0[p_map_type.ord()].insert(p_layer);
+    }
+
+    // Returns the layers of p_map_type to upload, leaving none marked
+    fn take(&mut self, p_map_type: MapType) -> HashSet<i32> {
+        std::mem::take(&mut self.0[p_map_type.ord()])
+    }
+
+    fn clear(&mut self, p_map_type: MapType) {
+        self.0[p_map_type.ord()].clear();
+    }
+
+    fn clear_all(&mut self) {
+        for layers in self.0.iter_mut() {
+            layers.clear();
+        }
+    }
+}
+
+// Nesting of begin_edit() batches, and whether update_regions() was called meanwhile
+#[derive(Default)]
+struct EditBatch {
+    depth: u32,
+    update_pending: bool,
+}
+
+impl EditBatch {
+    fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    // Returns whether the pending update runs now, None without a matching begin()
+    fn end(&mut self) -> Option<bool> {
+        if self.depth == 0 {
+            return None;
+        }
+        self.depth -= 1;
+        let run = self.depth == 0 && self.update_pending;
+        if run {
+            self.update_pending = false;
+        }
+        Some(run)
+    }
+
+    // Returns whether an update is deferred to the end of the batch
+    fn defer_update(&mut self) -> bool {
+        if self.depth > 0 {
+            self.update_pending = true;
+        }
+        self.depth > 0
+    }
 }
 
 #[derive(GodotClass)]
@@ -41,7 +259,18 @@ pub struct Terrain3DStorage {
     // Storage Settings & flags
     version: real,
     modified: bool,
+    // Store height maps as half floats when saving, halving their size
+    #[var]
+    #[export]
     save_16_bit: bool,
+    // Store control and color maps as lossless PNG when saving. Height is never compressed
+    #[var]
+    #[export]
+    compress_on_save: bool,
+    // Result of get_height() outside of all regions. Holes are always NAN
+    #[var]
+    #[export]
+    out_of_bounds_policy: HeightPolicy,
     region_size: RegionSize,
     region_sizev: Vector2i,
 
@@ -58,15 +287,27 @@ pub struct Terrain3DStorage {
 	region_map_dirty: bool,
 	region_map: PackedInt32Array, // 16x16 Region grid with index into region_offsets (1 based array)
 	region_offsets: Array<Vector2i>, // Array of active region coordinates
+	region_height_offsets: PackedFloat32Array, // Vertical offset per region, parallel to region_offsets
+	region_lods: PackedByteArray, // Detail reduction per region, images are region_size >> lod
 	height_maps: Array<Gd<Image>>,
 	control_maps:  Array<Gd<Image>>,
 	color_maps:  Array<Gd<Image>>,
 
+	// Lazily built min/max height pyramids, parallel to height_maps. None until queried
+	height_pyramids: RefCell<Vec<Option<HeightPyramid>>>,
+
 	// Generated Texture RIDs
 	// These contain the TextureLayered RID from the RenderingServer, no Image
 	generated_height_maps: GeneratedTex,
 	generated_control_maps: GeneratedTex,
 	generated_color_maps: GeneratedTex,
+	// Ambient occlusion baked by bake_ambient_occlusion(), R8 images parallel to height_maps
+	ao_maps: Array<Gd<Image>>,
+	generated_ao_maps: GeneratedTex,
+	// Layers edited since the last update_regions(), per map type. Only tracked while the
+	// generated texture exists, otherwise the whole array is regenerated anyway
+	dirty_layers: DirtyLayers,
+	edit_batch: EditBatch,
 }
 
 #[godot_api]
@@ -77,6 +318,8 @@ impl IResource for Terrain3DStorage {
             version: real!(0.8),
             modified: false,
             save_16_bit: false,
+            compress_on_save: false,
+            out_of_bounds_policy: HeightPolicy::Nan,
             region_size: RegionSize::SIZE_1024,
             region_sizev: Vector2i::new( RegionSize::SIZE_1024.ord(),  RegionSize::SIZE_1024.ord()),
             height_range: Vector2::ZERO,
@@ -84,16 +327,26 @@ impl IResource for Terrain3DStorage {
             region_map_dirty: true,
             region_map: PackedInt32Array::new(),
             region_offsets: Array::new(),
+            region_height_offsets: PackedFloat32Array::new(),
+            region_lods: PackedByteArray::new(),
             height_maps: Array::new(),
             control_maps: Array::new(),
             color_maps: Array::new(),
+            height_pyramids: RefCell::new(Vec::new()),
             generated_height_maps: GeneratedTex::default(),
             generated_color_maps: GeneratedTex::default(),
             generated_control_maps: GeneratedTex::default(),
+            ao_maps: Array::new(),
+            generated_ao_maps: GeneratedTex::default(),
+            dirty_layers: Default::default(),
+            edit_batch: EditBatch::default(),
         }
     }
 }
 
+// Terrain3DStorage is a refcounted Resource, so this only runs once the last Gd
+// referencing it is gone. Several Terrain3D nodes may share one storage; none of
+// them free the generated textures themselves.
 impl Drop for Terrain3DStorage {
     fn drop(&mut self) {
         self.clear();
@@ -105,19 +358,443 @@ impl Drop for Terrain3DStorage {
 impl Terrain3DStorage {
     const __CLASS__: &'static str = "Terrain3DStorage";
     pub const CURRENT_VERSION: real = 0.842;
-    const REGION_MAP_SIZE: i32 = 16;
+    pub const REGION_MAP_SIZE: i32 = 16;
     const REGION_MAP_VSIZE: Vector2i = Vector2i { x: Self::REGION_MAP_SIZE, y: Self::REGION_MAP_SIZE };
+    const MAX_REGION_LOD: i32 = 3;
+    const MAX_AO_STEPS: i32 = 32;
+    const MAX_RANDOM_POINT_TRIES: i32 = 64;
+    const MAX_FLOOD_FILL_TEXELS: i32 = 1 << 20;
+    // Texel spacing of the grid calc_height_range() samples
+    const HEIGHT_RANGE_SAMPLE_STEP: i32 = 4;
+    // Control bits of blank regions: base texture 0, no overlay, no flags
+    const CONTROL_DEFAULT: u32 = 0;
+    // Header byte of serialized map images
+    const IMAGE_RAW: u8 = 0;
+    const IMAGE_PNG: u8 = 1;
+    // Metadata key of the map data in files written by save()
+    const SAVE_META: &'static str = "terrain_3d_storage";
 
     ///////////////////////////
     // Private Functions
     ///////////////////////////
+    fn is_cleared(&self) -> bool {
+        self.region_map.is_empty()
+            && !self.generated_height_maps.rid().is_valid()
+            && !self.generated_control_maps.rid().is_valid()
+            && !self.generated_color_maps.rid().is_valid()
+            && !self.generated_ao_maps.rid().is_valid()
+    }
+
+    // Safe to call repeatedly, only the first call frees anything
     fn clear(&mut self) {
+        if self.is_cleared() {
+            return;
+        }
         log_info!(Self, "Clearing storage");
         self.region_map_dirty = true;
         self.region_map.clear();
         self.generated_height_maps.clear();
         self.generated_control_maps.clear();
         self.generated_color_maps.clear();
+        self.generated_ao_maps.clear();
+    }
+
+    /**
+     * Rebuilds the 16x16 region_map from region_offsets. Each cell holds the 1 based
+     * index of the region at that grid location, or 0.
+     */
+    fn update_region_map(&mut self) {
+        log_debug!(Self, "Regenerating region map from {} regions", self.region_offsets.len());
+        self.region_map.resize((Self::REGION_MAP_SIZE * Self::REGION_MAP_SIZE) as usize);
+        self.region_map.fill(0);
+        for (i, offset) in self.region_offsets.iter_shared().enumerate() {
+            let cell = match Self::region_map_cell(offset) {
+                Some(cell) => cell,
+                None => {
+                    log_error!(Self, "Region offset {} is outside the region map", offset);
+                    continue;
+                }
+            };
+            if self.region_map.get(cell) != 0 {
+                log_error!(Self, "Region offset {} is duplicated, keeping the first. Run sanitize_maps() to fix", offset);
+                continue;
+            }
+            self.region_map.set(cell, i as i32 + 1);
+        }
+        self.region_map_dirty = false;
+        self.base_mut().emit_signal("region_map_changed".into(), &[]);
+    }
+
+    // Index into region_map of the region grid location, centered on the map, or None
+    // outside of it
+    fn region_map_cell(p_region_loc: Vector2i) -> Option<usize> {
+        let pos = p_region_loc + Self::REGION_MAP_VSIZE / 2;
+        if pos.x < 0 || pos.y < 0 || pos.x >= Self::REGION_MAP_SIZE || pos.y >= Self::REGION_MAP_SIZE {
+            return None;
+        }
+        Some((pos.y * Self::REGION_MAP_SIZE + pos.x) as usize)
+    }
+
+    fn is_in_region_map(p_region_loc: Vector2i) -> bool {
+        Self::region_map_cell(p_region_loc).is_some()
+    }
+
+    fn expand_height_range(&mut self, p_min: real, p_max: real) {
+        self.height_range.x = self.height_range.x.min(p_min);
+        self.height_range.y = self.height_range.y.max(p_max);
+    }
+
+    /**
+     * Expands height_range to a grid of every HEIGHT_RANGE_SAMPLE_STEP texels of each
+     * height map, plus their last row and column. Much faster than update_height_range()
+     * on large terrains, but may miss single texel spikes between samples, so it only
+     * ever grows the range and keeps what a full scan or an edit already found.
+     */
+    fn calc_height_range(&mut self) {
+        let mut range = Vector2::new(real::MAX, real::MIN);
+        for img in self.height_maps.iter_shared() {
+            let sampled = Self::sampled_height_range(img.get_width(), img.get_height(), |x, y| {
+                img.get_pixel(x, y).r
+            });
+            range = Vector2::new(range.x.min(sampled.x), range.y.max(sampled.y));
+        }
+        if range.x <= range.y {
+            self.expand_height_range(range.x, range.y);
+        }
+        log_debug!(Self, "Calculated height range: {}", self.height_range);
+    }
+
+    // Min and max of p_height over the calc_height_range() sample grid of a p_width by
+    // p_height_px image. Empty images give (real::MAX, real::MIN)
+    fn sampled_height_range(p_width: i32, p_height_px: i32, p_height: impl Fn(i32, i32) -> real) -> Vector2 {
+        let axis = |p_len: i32| {
+            (0..p_len)
+                .step_by(Self::HEIGHT_RANGE_SAMPLE_STEP as usize)
+                .chain(std::iter::once(p_len - 1).filter(|last| *last >= 0))
+        };
+        let mut range = Vector2::new(real::MAX, real::MIN);
+        for y in axis(p_height_px) {
+            for x in axis(p_width) {
+                let h = p_height(x, y);
+                range = Vector2::new(range.x.min(h), range.y.max(h));
+            }
+        }
+        range
+    }
+
+    /**
+     * Serializes a map image for saving, prefixed with a header byte so decode_map_image()
+     * can tell raw from compressed data. With compress_on_save, control and color maps
+     * are written as lossless PNG; control maps keep their exact u32 bits by being
+     * reinterpreted as RGBA8.
+     */
+    fn encode_map_image(&self, p_map_type: MapType, p_image: &Gd<Image>) -> PackedByteArray {
+        let mut out: Vec<u8> = Vec::new();
+        if self.compress_on_save && p_map_type != MapType::TYPE_HEIGHT {
+            let png_source = if p_map_type == MapType::TYPE_CONTROL {
+                Image::create_from_data(
+                    p_image.get_width(),
+                    p_image.get_height(),
+                    false,
+                    Format::RGBA8,
+                    p_image.get_data(),
+                )
+            } else {
+                Some(p_image.clone())
+            };
+            if let Some(png_source) = png_source {
+                out.push(Self::IMAGE_PNG);
+                out.extend_from_slice(png_source.save_png_to_buffer().as_slice());
+                return PackedByteArray::from(out.as_slice());
+            }
+            log_warn!(Self, "Could not compress {:?} image, saving it raw", p_map_type);
+        }
+        out.extend(Self::encode_raw_map(
+            p_image.get_width(),
+            p_image.get_height(),
+            p_image.get_format().ord(),
+            p_image.get_data().as_slice(),
+        ));
+        PackedByteArray::from(out.as_slice())
+    }
+
+    // Raw map layout: header byte, then width, height and format as little endian i32, then the pixels
+    fn encode_raw_map(p_width: i32, p_height: i32, p_format: i32, p_data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(13 + p_data.len());
+        out.push(Self::IMAGE_RAW);
+        out.extend_from_slice(&p_width.to_le_bytes());
+        out.extend_from_slice(&p_height.to_le_bytes());
+        out.extend_from_slice(&p_format.to_le_bytes());
+        out.extend_from_slice(p_data);
+        out
+    }
+
+    // Inverse of encode_raw_map() on the bytes after the header, None if truncated
+    fn decode_raw_map(p_body: &[u8]) -> Option<(i32, i32, i32, &[u8])> {
+        if p_body.len() < 12 {
+            return None;
+        }
+        let read_i32 = |at: usize| i32::from_le_bytes([p_body[at], p_body[at + 1], p_body[at + 2], p_body[at + 3]]);
+        Some((read_i32(0), read_i32(4), read_i32(8), &p_body[12..]))
+    }
+
+    /**
+     * Inverse of encode_map_image(), detecting raw or compressed data from the header byte.
+     */
+    fn decode_map_image(p_map_type: MapType, p_data: &PackedByteArray) -> Option<Gd<Image>> {
+        let bytes = p_data.as_slice();
+        let (header, body) = bytes.split_first()?;
+        match *header {
+            Self::IMAGE_PNG => {
+                let mut img = Image::new_gd();
+                if img.load_png_from_buffer(PackedByteArray::from(body)) != Error::OK {
+                    return None;
+                }
+                if p_map_type == MapType::TYPE_CONTROL {
+                    img.convert(Format::RGBA8);
+                    return Image::create_from_data(
+                        img.get_width(),
+                        img.get_height(),
+                        false,
+                        Format::RF,
+                        img.get_data(),
+                    );
+                }
+                img.convert(p_map_type.format());
+                Some(img)
+            }
+            Self::IMAGE_RAW => {
+                let (width, height, format, pixels) = Self::decode_raw_map(body)?;
+                let format = Format::try_from_ord(format)?;
+                Image::create_from_data(width, height, false, format, PackedByteArray::from(pixels))
+            }
+            _ => None,
+        }
+    }
+
+    fn get_maps(&self, p_map_type: MapType) -> &Array<Gd<Image>> {
+        match p_map_type {
+            MapType::TYPE_CONTROL => &self.control_maps,
+            MapType::TYPE_COLOR => &self.color_maps,
+            _ => &self.height_maps,
+        }
+    }
+
+    /**
+     * Returns the index into region_offsets of the region at grid location p_region_loc,
+     * or -1 if there is no region there.
+     */
+    fn get_region_index_at(&self, p_region_loc: Vector2i) -> i32 {
+        self.region_offsets
+            .iter_shared()
+            .position(|offset| offset == p_region_loc)
+            .map_or(-1, |idx| idx as i32)
+    }
+
+    /**
+     * Reads a texel of p_map_type at global texel coordinates, crossing region
+     * boundaries as needed. Returns None if no region covers the texel.
+     */
+    fn get_texel(&self, p_map_type: MapType, p_texel: Vector2i) -> Option<Color> {
+        let (idx, local) = self.locate_texel(p_texel)?;
+        let maps = self.get_maps(p_map_type);
+        if idx >= maps.len() {
+            return None;
+        }
+        // Reduced detail regions store fewer texels for the same area
+        let lod = self.get_region_lod_at(idx);
+        Some(maps.get(idx).get_pixel(local.x >> lod, local.y >> lod))
+    }
+
+    /**
+     * Writes a texel of p_map_type at global texel coordinates and marks the generated
+     * texture for regeneration. Returns false if no region covers the texel.
+     */
+    fn set_texel(&mut self, p_map_type: MapType, p_texel: Vector2i, p_color: Color) -> bool {
+        let (idx, local) = match self.locate_texel(p_texel) {
+            Some(found) => found,
+            None => return false,
+        };
+        let lod = self.get_region_lod_at(idx);
+        let mut img = match p_map_type {
+            MapType::TYPE_HEIGHT => self.height_maps.get(idx),
+            MapType::TYPE_CONTROL => self.control_maps.get(idx),
+            MapType::TYPE_COLOR => self.color_maps.get(idx),
+            MapType::TYPE_MAX => return false,
+        };
+        img.set_pixel(local.x >> lod, local.y >> lod, p_color);
+        self.modified = true;
+        if p_map_type == MapType::TYPE_HEIGHT {
+            self.expand_height_range(p_color.r, p_color.r);
+            self.invalidate_height_pyramid(idx);
+        }
+        if self.get_generated_tex(p_map_type).rid().is_valid() {
+            self.dirty_layers.mark(p_map_type, idx as i32);
+        }
+        true
+    }
+
+    fn get_generated_tex(&self, p_map_type: MapType) -> &GeneratedTex {
+        match p_map_type {
+            MapType::TYPE_CONTROL => &self.generated_control_maps,
+            MapType::TYPE_COLOR => &self.generated_color_maps,
+            _ => &self.generated_height_maps,
+        }
+    }
+
+    fn get_generated_tex_mut(&mut self, p_map_type: MapType) -> &mut GeneratedTex {
+        match p_map_type {
+            MapType::TYPE_CONTROL => &mut self.generated_control_maps,
+            MapType::TYPE_COLOR => &mut self.generated_color_maps,
+            _ => &mut self.generated_height_maps,
+        }
+    }
+
+    /**
+     * Uploads only the layers edited since the last call, in place. Returns whether
+     * any layer was uploaded.
+     */
+    fn update_dirty_layers(&mut self, p_map_type: MapType) -> bool {
+        let layers = self.dirty_layers.take(p_map_type);
+        if layers.is_empty() || !self.get_generated_tex(p_map_type).rid().is_valid() {
+            return false;
+        }
+        log_debug!(Self, "Updating {} dirty {:?} layers", layers.len(), p_map_type);
+        for layer in layers {
+            if let Some(img) = self.get_upload_layer(p_map_type, layer as usize) {
+                self.get_generated_tex_mut(p_map_type).update_layer(img, layer);
+            }
+        }
+        true
+    }
+
+    /**
+     * Creates the height, control and color images of a new region, in MapType order,
+     * filled with the map defaults: height 0, control base texture 0 with no hole,
+     * navigation or autoshader bits, and opaque white color with roughness 1.
+     */
+    fn make_blank_region_images(&self) -> Option<[Gd<Image>; 3]> {
+        let size = self.region_size.ord();
+        let blank = |p_map_type: MapType, p_fill: Color| -> Option<Gd<Image>> {
+            let mut img = Image::create(size, size, false, p_map_type.format())?;
+            img.fill(p_fill);
+            Some(img)
+        };
+        Some([
+            blank(MapType::TYPE_HEIGHT, Color::from_rgba(0.0, 0.0, 0.0, 1.0))?,
+            blank(
+                MapType::TYPE_CONTROL,
+                Color::from_rgba(f32::from_bits(Self::CONTROL_DEFAULT), 0.0, 0.0, 1.0),
+            )?,
+            blank(MapType::TYPE_COLOR, Color::from_rgba(1.0, 1.0, 1.0, 1.0))?,
+        ])
+    }
+
+    fn set_region_lod_at(&mut self, p_index: usize, p_lod: i32) {
+        if self.region_lods.len() < self.region_offsets.len() {
+            self.region_lods.resize(self.region_offsets.len());
+        }
+        self.region_lods.set(p_index, p_lod as u8);
+    }
+
+    fn get_region_lod_at(&self, p_index: usize) -> i32 {
+        self.region_lods.as_slice().get(p_index).copied().unwrap_or(0) as i32
+    }
+
+    /**
+     * Returns p_map_type images ready for a texture array upload. Texture arrays need
+     * uniformly sized layers, so reduced detail regions are upscaled back to region_size.
+     */
+    fn get_upload_layers(&self, p_map_type: MapType) -> Array<Gd<Image>> {
+        let mut layers: Array<Gd<Image>> = Array::new();
+        for i in 0..self.get_maps(p_map_type).len() {
+            if let Some(img) = self.get_upload_layer(p_map_type, i) {
+                layers.push(img);
+            }
+        }
+        layers
+    }
+
+    fn get_upload_layer(&self, p_map_type: MapType, p_index: usize) -> Option<Gd<Image>> {
+        let img = self.get_maps(p_map_type).try_get(p_index)?;
+        if self.get_region_lod_at(p_index) == 0 || img.get_size() == self.region_sizev {
+            return Some(img);
+        }
+        let mut upscaled = img
+            .duplicate()
+            .map(|r| r.cast::<Image>())
+            .unwrap_or_else(|| img.clone());
+        let interpolation = if p_map_type == MapType::TYPE_CONTROL {
+            Interpolation::NEAREST
+        } else {
+            Interpolation::BILINEAR
+        };
+        upscaled
+            .resize_ex(self.region_sizev.x, self.region_sizev.y)
+            .interpolation(interpolation)
+            .done();
+        Some(upscaled)
+    }
+
+    /**
+     * Splits a global texel coordinate into the index of the region containing it
+     * and the texel coordinate within that region.
+     */
+    fn locate_texel(&self, p_texel: Vector2i) -> Option<(usize, Vector2i)> {
+        let size = self.region_size.ord();
+        let loc = Vector2i::new(p_texel.x.div_euclid(size), p_texel.y.div_euclid(size));
+        let idx = self.get_region_index_at(loc);
+        if idx < 0 {
+            return None;
+        }
+        Some((
+            idx as usize,
+            Vector2i::new(p_texel.x.rem_euclid(size), p_texel.y.rem_euclid(size)),
+        ))
+    }
+
+    /**
+     * Height of a global texel including its region's height offset.
+     */
+    fn get_height_texel(&self, p_texel: Vector2i) -> Option<real> {
+        let (idx, _) = self.locate_texel(p_texel)?;
+        let height = self.get_texel(MapType::TYPE_HEIGHT, p_texel)?.r;
+        Some(height + self.get_region_height_offset_at(idx))
+    }
+
+    fn is_hole_texel(&self, p_texel: Vector2i) -> bool {
+        self.get_texel(MapType::TYPE_CONTROL, p_texel)
+            .map_or(false, |c| control_is_hole(c.r.to_bits()))
+    }
+
+    /**
+     * Drops the cached height pyramid of a region so it's rebuilt on the next query.
+     * Call whenever that region's height data changes.
+     */
+    fn invalidate_height_pyramid(&self, p_index: usize) {
+        if let Some(pyramid) = self.height_pyramids.borrow_mut().get_mut(p_index) {
+            *pyramid = None;
+        }
+    }
+
+    fn get_region_height_offset_at(&self, p_index: usize) -> real {
+        self.region_height_offsets
+            .as_slice()
+            .get(p_index)
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /**
+     * Computes a normal from the four neighboring heights of a texel, one unit apart.
+     * Matches get_normal() in main.glsl.
+     */
+    fn calc_normal(p_left: real, p_right: real, p_back: real, p_front: real) -> Vector3 {
+        let horizontal = Vector3::new(2.0, p_right - p_left, 0.0);
+        let vertical = Vector3::new(0.0, p_back - p_front, 2.0);
+        let mut normal = vertical.cross(horizontal).normalized();
+        normal.z *= -1.0;
+        normal
     }
 
     ///////////////////////////
@@ -136,25 +813,2404 @@ impl Terrain3DStorage {
         }
     }
 
+    #[func]
+    pub fn get_version(&self) -> real {
+        self.version
+    }
+
+    // Whether there are changes to save, including a pending version upgrade
+    #[func]
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
     pub fn get_region_size(&self) -> i32 {
         self.region_size.ord()
     }
 
-    pub fn update_regions(&mut self, mut force_emit: bool) {
-        if self.generated_height_maps.dirty() {
-            log_debug!(Self, "Regenerating height layered texture from {} maps", self.height_maps.len());
-            match GeneratedTex::create_from_layers(self.height_maps.clone()) {
-                Some(x) => {
-                    self.generated_height_maps = x;
-                },
-                None => {
-                    log_error!(Self, "Could not create a height maps from stored value");
-                    return;
+    /**
+     * Returns the min and max height of the stored height maps. It is estimated from
+     * sampled texels whenever the height texture array is regenerated, and edits in
+     * between only ever expand it, so after lowering the tallest peak it stays
+     * conservatively large until update_height_range() rescans every texel.
+     */
+    #[func]
+    pub fn get_height_range(&self) -> Vector2 {
+        self.height_range
+    }
+
+    /**
+     * Recomputes height_range from every texel of every region. Edits expand the range
+     * as they write, so this full scan is only needed when it could shrink.
+     */
+    #[func]
+    pub fn update_height_range(&mut self) {
+        let mut range: Option<Vector2> = None;
+        for img in self.height_maps.iter_shared() {
+            let r = Self::get_image_height_range(&img);
+            range = Some(range.map_or(r, |range| Vector2::new(range.x.min(r.x), range.y.max(r.y))));
+        }
+        self.height_range = range.unwrap_or(Vector2::ZERO);
+        log_debug!(Self, "Updated height range: {}", self.height_range);
+    }
+
+    #[func]
+    pub fn get_region_offsets(&self) -> Array<Vector2i> {
+        self.region_offsets.clone()
+    }
+
+    #[func]
+    pub fn get_region_count(&self) -> i32 {
+        self.region_offsets.len() as i32
+    }
+
+    /**
+     * Returns the world space center of the region at region_index, with Y taken from
+     * get_height(). Returns Vector3::ZERO if the index is invalid.
+     */
+    #[func]
+    pub fn get_region_center(&self, region_index: i32) -> Vector3 {
+        if region_index < 0 || region_index as usize >= self.region_offsets.len() {
+            log_error!(Self, "Region index {} out of range 0..{}", region_index, self.region_offsets.len());
+            return Vector3::ZERO;
+        }
+        let offset = self.region_offsets.get(region_index as usize);
+        let size = self.region_size.ord() as real;
+        let mut center = Vector3::new((offset.x as real + 0.5) * size, 0.0, (offset.y as real + 0.5) * size);
+        center.y = self.get_height_exact(center);
+        center
+    }
+
+    /**
+     * Returns a surface point within the region at region_index, picked deterministically
+     * from rng_seed. Holes are skipped; if no valid point is found after a number of
+     * tries, the region center is returned.
+     */
+    #[func]
+    pub fn get_random_point_in_region(&self, region_index: i32, rng_seed: i64) -> Vector3 {
+        if region_index < 0 || region_index as usize >= self.region_offsets.len() {
+            log_error!(Self, "Region index {} out of range 0..{}", region_index, self.region_offsets.len());
+            return Vector3::ZERO;
+        }
+        let offset = self.region_offsets.get(region_index as usize);
+        let size = self.region_size.ord() as real;
+        let mut rng = RandomNumberGenerator::new_gd();
+        rng.set_seed(rng_seed as u64);
+        for _ in 0..Self::MAX_RANDOM_POINT_TRIES {
+            let mut point = Vector3::new(
+                (offset.x as real + rng.randf() as real) * size,
+                0.0,
+                (offset.y as real + rng.randf() as real) * size,
+            );
+            point.y = self.get_height_exact(point);
+            if point.y.is_finite() {
+                return point;
+            }
+        }
+        log_warn!(Self, "No surface point found in region {}, using its center", region_index);
+        self.get_region_center(region_index)
+    }
+
+    /**
+     * Creates a region with blank maps at global_position. Returns the new region index,
+     * or -1 if a region already exists there or it is outside the region map.
+     */
+    #[func]
+    pub fn add_region(&mut self, global_position: Vector3) -> i32 {
+        let loc = self.get_region_location(global_position);
+        if !Self::is_in_region_map(loc) {
+            log_error!(Self, "Position {} is outside the region map", global_position);
+            return -1;
+        }
+        let idx = self.add_region_at(loc);
+        if idx >= 0 {
+            self.base_mut().emit_signal("regions_changed".into(), &[]);
+        }
+        idx
+    }
+
+    /**
+     * Creates blank regions in every free cell of rect, in region grid coordinates, as
+     * one batch: textures are rebuilt once and regions_changed is emitted once. Returns
+     * the number of regions added, or -1 if rect reaches outside the region map.
+     */
+    #[func]
+    pub fn add_region_rect(&mut self, rect: Rect2i) -> i32 {
+        let end = rect.position + rect.size - Vector2i::new(1, 1);
+        if rect.size.x <= 0
+            || rect.size.y <= 0
+            || !Self::is_in_region_map(rect.position)
+            || !Self::is_in_region_map(end)
+        {
+            log_error!(
+                Self,
+                "Rect {:?} is empty or outside the {}x{} region map",
+                rect,
+                Self::REGION_MAP_SIZE,
+                Self::REGION_MAP_SIZE
+            );
+            return -1;
+        }
+        let mut added = 0;
+        for y in rect.position.y..=end.y {
+            for x in rect.position.x..=end.x {
+                if self.add_region_at(Vector2i::new(x, y)) >= 0 {
+                    added += 1;
                 }
             }
-            force_emit = true;
-            self.modified = true;
-            self.base_mut().emit_signal("height_maps_changed".into(), &[Variant::nil()]);
         }
+        log_info!(Self, "Added {} regions in {:?}", added, rect);
+        if added > 0 {
+            self.update_regions(true);
+            self.base_mut().emit_signal("regions_changed".into(), &[]);
+        }
+        added
+    }
+
+    // Creates a blank region at grid location p_region_loc, which must be in the region map
+    fn add_region_at(&mut self, p_region_loc: Vector2i) -> i32 {
+        if self.get_region_index_at(p_region_loc) >= 0 {
+            log_debug!(Self, "Region {} already exists", p_region_loc);
+            return -1;
+        }
+        let [height, control, color] = match self.make_blank_region_images() {
+            Some(images) => images,
+            None => {
+                log_error!(Self, "Could not create images for region {}", p_region_loc);
+                return -1;
+            }
+        };
+
+        log_info!(Self, "Adding region at {}", p_region_loc);
+        let region_count = self.region_offsets.len();
+        if self.region_height_offsets.len() < region_count {
+            self.region_height_offsets.resize(region_count);
+        }
+        if self.region_lods.len() < region_count {
+            self.region_lods.resize(region_count);
+        }
+        self.region_offsets.push(p_region_loc);
+        self.region_height_offsets.push(0.0);
+        self.region_lods.push(0);
+        self.height_maps.push(height);
+        self.control_maps.push(control);
+        self.color_maps.push(color);
+        self.region_map_dirty = true;
+        self.modified = true;
+        self.generated_height_maps.clear();
+        self.generated_control_maps.clear();
+        self.generated_color_maps.clear();
+        region_count as i32
+    }
+
+    /**
+     * Deletes the region at global_position and its maps. Does nothing if there is no
+     * region there.
+     */
+    #[func]
+    pub fn remove_region(&mut self, global_position: Vector3) {
+        let idx = self.get_region_index_at(self.get_region_location(global_position));
+        if idx < 0 {
+            log_debug!(Self, "No region to remove at {}", global_position);
+            return;
+        }
+        log_info!(Self, "Removing region {}", self.region_offsets.get(idx as usize));
+        self.remove_region_at(idx as usize);
+        self.base_mut().emit_signal("regions_changed".into(), &[]);
+    }
+
+    #[func]
+    pub fn has_region(&self, global_position: Vector3) -> bool {
+        self.get_region_index_at(self.get_region_location(global_position)) >= 0
+    }
+
+    // Removes the region at p_index from every parallel array. Later indices shift down
+    fn remove_region_at(&mut self, p_index: usize) {
+        let had_ao = self.ao_maps.len() == self.region_offsets.len();
+        self.region_offsets.remove(p_index);
+        if p_index < self.region_height_offsets.len() {
+            self.region_height_offsets.remove(p_index);
+        }
+        if p_index < self.region_lods.len() {
+            self.region_lods.remove(p_index);
+        }
+        for maps in [&mut self.height_maps, &mut self.control_maps, &mut self.color_maps] {
+            if p_index < maps.len() {
+                maps.remove(p_index);
+            }
+        }
+        if had_ao {
+            self.ao_maps.remove(p_index);
+        }
+        self.height_pyramids.borrow_mut().clear();
+        self.region_map_dirty = true;
+        self.modified = true;
+        self.generated_height_maps.clear();
+        self.generated_control_maps.clear();
+        self.generated_color_maps.clear();
+        self.generated_ao_maps.clear();
+    }
+
+    /**
+     * Reduces the detail of the region at global_position to region_size >> lod texels
+     * per side, downsampling its height, control and color images to save memory. Lod 0
+     * can't restore lost detail, it only records full resolution again for new data.
+     * Texture arrays need uniform layers, so reduced images are upscaled on upload and
+     * only the CPU copy is smaller.
+     */
+    #[func]
+    pub fn set_region_detail(&mut self, global_position: Vector3, lod: i32) {
+        let idx = self.get_region_index_at(self.get_region_location(global_position));
+        if idx < 0 {
+            log_error!(Self, "No region found at: {}", global_position);
+            return;
+        }
+        let idx = idx as usize;
+        let lod = lod.clamp(0, Self::MAX_REGION_LOD);
+        let current = self.get_region_lod_at(idx);
+        if lod == current {
+            return;
+        }
+        if lod < current {
+            log_warn!(Self, "Raising region detail can't restore data, upscaling the stored images");
+        }
+        let size = self.region_size.ord() >> lod;
+        for map_type in [MapType::TYPE_HEIGHT, MapType::TYPE_CONTROL, MapType::TYPE_COLOR] {
+            let maps = self.get_maps(map_type);
+            if idx >= maps.len() {
+                continue;
+            }
+            let mut img = maps.get(idx);
+            let interpolation = if map_type == MapType::TYPE_CONTROL {
+                Interpolation::NEAREST
+            } else {
+                Interpolation::BILINEAR
+            };
+            img.resize_ex(size, size).interpolation(interpolation).done();
+        }
+        self.set_region_lod_at(idx, lod);
+        self.invalidate_height_pyramid(idx);
+        self.modified = true;
+        self.generated_height_maps.clear();
+        self.generated_control_maps.clear();
+        self.generated_color_maps.clear();
+    }
+
+    #[func]
+    pub fn get_region_detail(&self, global_position: Vector3) -> i32 {
+        let idx = self.get_region_index_at(self.get_region_location(global_position));
+        if idx < 0 {
+            return 0;
+        }
+        self.get_region_lod_at(idx as usize)
+    }
+
+    /**
+     * Writes a whole region's heights in one call, row major. heights must hold
+     * region_size * region_size values. The region is created if needed.
+     */
+    #[func]
+    pub fn set_region_heights(&mut self, global_position: Vector3, heights: PackedFloat32Array) {
+        let size = self.region_size.ord();
+        if heights.len() != (size * size) as usize {
+            log_error!(Self, "Expected {} heights, got {}", size * size, heights.len());
+            return;
+        }
+        let loc = self.get_region_location(global_position);
+        let mut idx = self.get_region_index_at(loc);
+        if idx < 0 {
+            idx = self.add_region(global_position);
+            if idx < 0 {
+                return;
+            }
+        }
+
+        let mut bytes = PackedByteArray::new();
+        bytes.resize(heights.len() * 4);
+        let mut min = real::MAX;
+        let mut max = real::MIN;
+        {
+            let bytes_mut = bytes.as_mut_slice();
+            for (i, h) in heights.as_slice().iter().enumerate() {
+                bytes_mut[i * 4..i * 4 + 4].copy_from_slice(&h.to_le_bytes());
+                min = min.min(*h);
+                max = max.max(*h);
+            }
+        }
+        let img = match Image::create_from_data(size, size, false, MapType::TYPE_HEIGHT.format(), bytes) {
+            Some(img) => img,
+            None => {
+                log_error!(Self, "Could not create height image for region {}", loc);
+                return;
+            }
+        };
+        self.height_maps.set(idx as usize, img);
+        if (idx as usize) < self.region_lods.len() && self.region_lods.get(idx as usize) != 0 {
+            log_warn!(Self, "Region {} held reduced detail height data, resetting its detail", loc);
+            self.set_region_lod_at(idx as usize, 0);
+        }
+        self.expand_height_range(min, max);
+        self.invalidate_height_pyramid(idx as usize);
+        self.modified = true;
+        self.generated_height_maps.clear();
+    }
+
+    /**
+     * Writes the heights of a HeightMapShape3D into the region containing global_position,
+     * the inverse of Terrain3D::make_collision_shape. Shapes whose dimensions differ from
+     * region_size, such as the region_size + 1 vertex grids used for collision, are
+     * resampled to fit the region.
+     */
+    #[func]
+    pub fn import_heightmap_shape(&mut self, shape: Gd<HeightMapShape3D>, global_position: Vector3) {
+        let width = shape.get_map_width();
+        let depth = shape.get_map_depth();
+        let data = shape.get_map_data();
+        if width < 2 || depth < 2 || data.len() != (width * depth) as usize {
+            log_error!(Self, "Invalid shape: {}x{} with {} heights", width, depth, data.len());
+            return;
+        }
+        self.set_region_heights_resampled(global_position, width, depth, data);
+    }
+
+    /**
+     * Imports a grayscale heightmap image into the region containing global_position,
+     * mapping black to h_min and white to h_max. Handles float and half formats, and 8 bit
+     * grayscale, which bands visibly and warns. With packed_16_bit, the image must be LA8
+     * or RG8 holding 16 bit values as high then low bytes, as some tools export them.
+     * Images not matching region_size are resampled.
+     */
+    #[func]
+    pub fn import_heightmap_image(
+        &mut self,
+        image: Gd<Image>,
+        global_position: Vector3,
+        h_min: real,
+        h_max: real,
+        packed_16_bit: bool,
+    ) {
+        let width = image.get_width();
+        let depth = image.get_height();
+        if width < 2 || depth < 2 {
+            log_error!(Self, "Invalid heightmap image: {}x{}", width, depth);
+            return;
+        }
+        if image.is_compressed() {
+            log_error!(Self, "Compressed heightmap images are not supported, format {:?}", image.get_format());
+            return;
+        }
+        let format = image.get_format();
+        if packed_16_bit && format != Format::LA8 && format != Format::RG8 {
+            log_error!(Self, "Packed 16 bit heightmaps must be LA8 or RG8, got {:?}", format);
+            return;
+        }
+        let mut values = PackedFloat32Array::new();
+        values.resize((width * depth) as usize);
+        match format {
+            Format::LA8 | Format::RG8 if packed_16_bit => {
+                // 16 bit samples split into a high and a low byte
+                let data = image.get_data();
+                let values_mut = values.as_mut_slice();
+                for (i, b) in data.as_slice().chunks_exact(2).enumerate() {
+                    values_mut[i] = (b[0] as u32 * 256 + b[1] as u32) as real / 65535.0;
+                }
+            }
+            Format::RF | Format::RH | Format::RGF | Format::RGH | Format::RGBF | Format::RGBH
+            | Format::RGBAF | Format::RGBAH => {
+                let values_mut = values.as_mut_slice();
+                for y in 0..depth {
+                    for x in 0..width {
+                        values_mut[(y * width + x) as usize] = image.get_pixel(x, y).r;
+                    }
+                }
+            }
+            Format::L8 | Format::R8 | Format::LA8 | Format::RG8 | Format::RGB8 | Format::RGBA8 => {
+                log_warn!(Self, "Importing an 8 bit heightmap, expect visible banding. Use a 16 bit or float image");
+                let channels = image.detect_used_channels();
+                if (format == Format::RGB8 || format == Format::RGBA8)
+                    && channels != UsedChannels::L
+                    && channels != UsedChannels::LA
+                {
+                    log_error!(Self, "Heightmap image is not grayscale");
+                    return;
+                }
+                let values_mut = values.as_mut_slice();
+                for y in 0..depth {
+                    for x in 0..width {
+                        values_mut[(y * width + x) as usize] = image.get_pixel(x, y).r;
+                    }
+                }
+            }
+            _ => {
+                log_error!(Self, "Unsupported heightmap image format {:?}", format);
+                return;
+            }
+        }
+        for value in values.as_mut_slice() {
+            *value = h_min + *value * (h_max - h_min);
+        }
+        self.set_region_heights_resampled(global_position, width, depth, values);
+    }
+
+    /**
+     * Imports images in MapType order, height then control then color, with their top left
+     * texel at global_position. Empty images are skipped. The images are sliced into
+     * regions, which are created where needed. Heights are read from the red channel,
+     * multiplied by height_scale and offset by height_offset. Regions created for images
+     * that don't end on a region boundary are padded with the image's edge texels rather
+     * than zeros, to avoid seams. Control images must be FORMAT_RF, any conversion would
+     * change their bits.
+     */
+    #[func]
+    pub fn import_images(
+        &mut self,
+        images: Array<Gd<Image>>,
+        global_position: Vector3,
+        height_offset: f32,
+        height_scale: f32,
+    ) {
+        let map_types = [MapType::TYPE_HEIGHT, MapType::TYPE_CONTROL, MapType::TYPE_COLOR];
+        if images.len() > map_types.len() {
+            log_error!(Self, "Expected at most {} images, got {}", map_types.len(), images.len());
+            return;
+        }
+        let mut sources: [Option<Gd<Image>>; 3] = Default::default();
+        let mut img_size: Option<Vector2i> = None;
+        for (i, img) in images.iter_shared().enumerate() {
+            if img.is_empty() {
+                continue;
+            }
+            if img.is_compressed() {
+                log_error!(Self, "Compressed {:?} images are not supported", map_types[i]);
+                return;
+            }
+            if img_size.map_or(false, |size| size != img.get_size()) {
+                log_error!(Self, "All images must be the same size, {:?} is {}", map_types[i], img.get_size());
+                return;
+            }
+            img_size = Some(img.get_size());
+            sources[i] = match map_types[i] {
+                MapType::TYPE_CONTROL if img.get_format() != Format::RF => {
+                    log_error!(Self, "Control images must be FORMAT_RF, got {:?}", img.get_format());
+                    return;
+                }
+                MapType::TYPE_COLOR if img.get_format() != Format::RGBA8 => {
+                    let mut converted = Image::new_gd();
+                    converted.copy_from(img);
+                    converted.convert(Format::RGBA8);
+                    Some(converted)
+                }
+                _ => Some(img),
+            };
+        }
+        let img_size = match img_size {
+            Some(size) => size,
+            None => {
+                log_error!(Self, "No images to import");
+                return;
+            }
+        };
+
+        let region_size = self.region_size.ord();
+        let origin = Vector2i::new(global_position.x.floor() as i32, global_position.z.floor() as i32);
+        let first = self.get_region_location(global_position);
+        let last = self.get_region_location(
+            global_position + Vector3::new((img_size.x - 1) as real, 0.0, (img_size.y - 1) as real),
+        );
+        log_info!(Self, "Importing {} images of size {} into regions {} to {}", images.len(), img_size, first, last);
+        let mut added = 0;
+        let mut min = real::MAX;
+        let mut max = real::MIN;
+        for ry in first.y..=last.y {
+            for rx in first.x..=last.x {
+                let loc = Vector2i::new(rx, ry);
+                if !Self::is_in_region_map(loc) {
+                    log_warn!(Self, "Region {} is outside the region map, skipping it", loc);
+                    continue;
+                }
+                let region_origin = loc * region_size;
+                let mut idx = self.get_region_index_at(loc);
+                let is_new = idx < 0;
+                if is_new {
+                    idx = self.add_region_at(loc);
+                    if idx < 0 {
+                        continue;
+                    }
+                    added += 1;
+                }
+                let idx = idx as usize;
+                if self.get_region_lod_at(idx) != 0 {
+                    log_warn!(Self, "Region {} held reduced detail data, restoring full detail", loc);
+                    self.set_region_detail(Vector3::new(region_origin.x as real, 0.0, region_origin.y as real), 0);
+                }
+
+                for (source, map_type) in sources.iter().zip(map_types) {
+                    let source = match source {
+                        Some(source) => source,
+                        None => continue,
+                    };
+                    let mut target = self.get_maps(map_type).get(idx);
+                    for z in 0..region_size {
+                        for x in 0..region_size {
+                            let src = region_origin + Vector2i::new(x, z) - origin;
+                            let inside = src.x >= 0 && src.y >= 0 && src.x < img_size.x && src.y < img_size.y;
+                            // Existing regions keep their data outside of the images
+                            if !inside && !is_new {
+                                continue;
+                            }
+                            let mut pixel = source.get_pixel(src.x.clamp(0, img_size.x - 1), src.y.clamp(0, img_size.y - 1));
+                            if map_type == MapType::TYPE_HEIGHT {
+                                let h = pixel.r * height_scale + height_offset;
+                                min = min.min(h);
+                                max = max.max(h);
+                                pixel = Color::from_rgba(h, 0.0, 0.0, 1.0);
+                            }
+                            target.set_pixel(x, z, pixel);
+                        }
+                    }
+                }
+                self.invalidate_height_pyramid(idx);
+            }
+        }
+
+        if min <= max {
+            self.expand_height_range(min, max);
+        }
+        self.modified = true;
+        for (source, map_type) in sources.iter().zip(map_types) {
+            if source.is_some() {
+                self.get_generated_tex_mut(map_type).clear();
+            }
+        }
+        self.update_regions(true);
+        if added > 0 {
+            self.base_mut().emit_signal("regions_changed".into(), &[]);
+        }
+    }
+
+    // Writes heights of any dimensions into a region, resampling them to region_size
+    fn set_region_heights_resampled(
+        &mut self,
+        p_global_position: Vector3,
+        p_width: i32,
+        p_depth: i32,
+        p_data: PackedFloat32Array,
+    ) {
+        let size = self.region_size.ord();
+        if p_width == size && p_depth == size {
+            self.set_region_heights(p_global_position, p_data);
+            return;
+        }
+
+        log_debug!(Self, "Resampling {}x{} heights to region size {}", p_width, p_depth, size);
+        let mut bytes = PackedByteArray::new();
+        bytes.resize(p_data.len() * 4);
+        {
+            let bytes_mut = bytes.as_mut_slice();
+            for (i, h) in p_data.as_slice().iter().enumerate() {
+                bytes_mut[i * 4..i * 4 + 4].copy_from_slice(&h.to_le_bytes());
+            }
+        }
+        let mut img = match Image::create_from_data(p_width, p_depth, false, MapType::TYPE_HEIGHT.format(), bytes) {
+            Some(img) => img,
+            None => {
+                log_error!(Self, "Could not create an image from the height data");
+                return;
+            }
+        };
+        img.resize_ex(size, size).interpolation(Interpolation::BILINEAR).done();
+        let resized = img.get_data();
+        let mut heights = PackedFloat32Array::new();
+        heights.resize((size * size) as usize);
+        {
+            let heights_mut = heights.as_mut_slice();
+            for (i, h) in heights_mut.iter_mut().enumerate() {
+                let b = &resized.as_slice()[i * 4..i * 4 + 4];
+                *h = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            }
+        }
+        self.set_region_heights(p_global_position, heights);
+    }
+
+    /**
+     * Swaps in new maps for the existing region at global_position in one step, keeping its
+     * layer index. Only that layer is uploaded, in a single update_regions(), so streaming
+     * systems can replace data without a visible flash. The images must be region_size and
+     * use the formats of their map types. The region's detail is reset to full.
+     */
+    #[func]
+    pub fn replace_region(
+        &mut self,
+        global_position: Vector3,
+        height: Gd<Image>,
+        control: Gd<Image>,
+        color: Gd<Image>,
+    ) {
+        let loc = self.get_region_location(global_position);
+        let idx = self.get_region_index_at(loc);
+        if idx < 0 {
+            log_error!(Self, "No region at {} to replace", loc);
+            return;
+        }
+        let images = [
+            (MapType::TYPE_HEIGHT, &height),
+            (MapType::TYPE_CONTROL, &control),
+            (MapType::TYPE_COLOR, &color),
+        ];
+        for (map_type, img) in images {
+            if img.get_size() != self.region_sizev || img.get_format() != map_type.format() {
+                log_error!(
+                    Self,
+                    "{:?} image is {} {:?}, expected {} {:?}",
+                    map_type,
+                    img.get_size(),
+                    img.get_format(),
+                    self.region_sizev,
+                    map_type.format()
+                );
+                return;
+            }
+        }
+
+        log_info!(Self, "Replacing region {} at layer {}", loc, idx);
+        let index = idx as usize;
+        self.height_maps.set(index, height.clone());
+        self.control_maps.set(index, control);
+        self.color_maps.set(index, color);
+        self.set_region_lod_at(index, 0);
+        let range = Self::get_image_height_range(&height);
+        self.expand_height_range(range.x, range.y);
+        self.invalidate_height_pyramid(index);
+        self.modified = true;
+        for map_type in [MapType::TYPE_HEIGHT, MapType::TYPE_CONTROL, MapType::TYPE_COLOR] {
+            if self.get_generated_tex(map_type).rid().is_valid() {
+                self.dirty_layers.mark(map_type, idx);
+            }
+        }
+        self.update_regions(false);
+    }
+
+    // Min and max of a FORMAT_RF height image
+    fn get_image_height_range(p_image: &Gd<Image>) -> Vector2 {
+        let data = p_image.get_data();
+        let mut range = Vector2::new(real::MAX, real::MIN);
+        for b in data.as_slice().chunks_exact(4) {
+            let h = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+            range = Vector2::new(range.x.min(h), range.y.max(h));
+        }
+        range
+    }
+
+    /**
+     * Returns the region grid location containing p_global_position.
+     */
+    pub fn get_region_location(&self, p_global_position: Vector3) -> Vector2i {
+        Self::region_location(p_global_position, self.region_size.ord())
+    }
+
+    fn region_location(p_global_position: Vector3, p_region_size: i32) -> Vector2i {
+        let size = p_region_size as real;
+        Vector2i::new(
+            (p_global_position.x / size).floor() as i32,
+            (p_global_position.z / size).floor() as i32,
+        )
+    }
+
+    /**
+     * Returns the region grid coordinate of global_position, as stored in
+     * get_region_offsets(). Logs an error if it is outside the region map.
+     */
+    #[func]
+    pub fn get_region_offset(&self, global_position: Vector3) -> Vector2i {
+        let loc = self.get_region_location(global_position);
+        if !Self::is_in_region_map(loc) {
+            log_error!(Self, "Position {} is outside the {}x{} region map", global_position, Self::REGION_MAP_SIZE, Self::REGION_MAP_SIZE);
+        }
+        loc
+    }
+
+    /**
+     * Returns the index in get_region_offsets() of the region containing global_position,
+     * or -1 if there is none. Logs an error if the position is outside the region map.
+     */
+    #[func]
+    pub fn get_region_index(&self, global_position: Vector3) -> i32 {
+        let loc = self.get_region_location(global_position);
+        let cell = match Self::region_map_cell(loc) {
+            Some(cell) => cell,
+            None => {
+                log_error!(Self, "Position {} is outside the {}x{} region map", global_position, Self::REGION_MAP_SIZE, Self::REGION_MAP_SIZE);
+                return -1;
+            }
+        };
+        // The map is stale until the next update_regions()
+        if self.region_map_dirty {
+            return self.get_region_index_at(loc);
+        }
+        self.region_map.get(cell) - 1
+    }
+
+    /**
+     * Returns the 0 to 1 coordinate of global_position within its region, 0 at the
+     * region's first texel and 1 at its last one, so opposite corners map to (0, 0) and
+     * (1, 1). Positions between the last texel and the next region clamp to 1. Use with
+     * get_region_location() to address per region data such as lightmaps or decal atlases.
+     */
+    #[func]
+    pub fn get_region_uv2(&self, global_position: Vector3) -> Vector2 {
+        Self::region_uv2(global_position, self.region_size.ord())
+    }
+
+    fn region_uv2(p_global_position: Vector3, p_region_size: i32) -> Vector2 {
+        let loc = Self::region_location(p_global_position, p_region_size);
+        let size = p_region_size as real;
+        let far_texel = (p_region_size - 1).max(1) as real;
+        let uv = |p_coord: real, p_loc: i32| {
+            ((p_coord - p_loc as real * size) / far_texel).clamp(0.0, 1.0)
+        };
+        Vector2::new(uv(p_global_position.x, loc.x), uv(p_global_position.z, loc.y))
+    }
+
+    /**
+     * Returns the bilinearly interpolated height at global_position, including the
+     * region's height offset. Returns NAN over holes. Where there is no region, the
+     * result follows out_of_bounds_policy, NAN by default.
+     */
+    #[func]
+    pub fn get_height(&self, global_position: Vector3) -> real {
+        let loc = self.get_region_location(global_position);
+        if self.get_region_index_at(loc) >= 0 {
+            return self.get_height_exact(global_position);
+        }
+        match self.out_of_bounds_policy {
+            HeightPolicy::Nan => real::NAN,
+            HeightPolicy::Zero => 0.0,
+            HeightPolicy::ClampToNearest => {
+                self.get_height_exact(self.clamp_to_nearest_region(global_position))
+            }
+        }
+    }
+
+    /**
+     * Like get_height(), but returns default over holes and where there is no region,
+     * whatever out_of_bounds_policy is.
+     */
+    #[func]
+    pub fn get_height_or(&self, global_position: Vector3, default: real) -> real {
+        let height = self.get_height_exact(global_position);
+        if height.is_nan() { default } else { height }
+    }
+
+    // Moves p_global_position onto the closest texel of the closest region
+    fn clamp_to_nearest_region(&self, p_global_position: Vector3) -> Vector3 {
+        let size = self.region_size.ord() as real;
+        let pos = Vector2::new(p_global_position.x, p_global_position.z);
+        let mut nearest = p_global_position;
+        let mut nearest_dist = real::MAX;
+        for offset in self.region_offsets.iter_shared() {
+            let min = Vector2::new(offset.x as real, offset.y as real) * size;
+            let max = min + Vector2::new(size - 1.0, size - 1.0);
+            let clamped = Vector2::new(pos.x.clamp(min.x, max.x), pos.y.clamp(min.y, max.y));
+            let dist = clamped.distance_squared_to(pos);
+            if dist < nearest_dist {
+                nearest_dist = dist;
+                nearest = Vector3::new(clamped.x, p_global_position.y, clamped.y);
+            }
+        }
+        nearest
+    }
+
+    /**
+     * get_height() ignoring out_of_bounds_policy: NAN over holes and where there is no
+     * region. Used internally wherever a miss must be detected.
+     */
+    pub fn get_height_exact(&self, global_position: Vector3) -> real {
+        let pos = Vector2::new(global_position.x, global_position.z);
+        let base = pos.floor();
+        let texel = Vector2i::new(base.x as i32, base.y as i32);
+        let h00 = match self.get_height_texel(texel) {
+            Some(h) => h,
+            None => return real::NAN,
+        };
+        if self.is_hole_texel(texel) {
+            return real::NAN;
+        }
+        // Neighbors missing at the edge of the loaded terrain reuse the base texel
+        let h10 = self.get_height_texel(texel + Vector2i::new(1, 0)).unwrap_or(h00);
+        let h01 = self.get_height_texel(texel + Vector2i::new(0, 1)).unwrap_or(h00);
+        let h11 = self.get_height_texel(texel + Vector2i::new(1, 1)).unwrap_or(h00);
+        Self::bilerp([h00, h10, h01, h11], pos - base)
+    }
+
+    // Interpolates the heights of a texel and its +x, +z and +xz neighbors at p_t within it
+    fn bilerp(p_heights: [real; 4], p_t: Vector2) -> real {
+        let [h00, h10, h01, h11] = p_heights;
+        let h0 = h00 + (h10 - h00) * p_t.x;
+        let h1 = h01 + (h11 - h01) * p_t.x;
+        h0 + (h1 - h0) * p_t.y
+    }
+
+    /**
+     * Returns the packed 32 bit control value at global_position, exactly as the shader
+     * decodes it, or -1 if there is no region there.
+     */
+    #[func]
+    pub fn get_control_raw(&self, global_position: Vector3) -> i64 {
+        let texel = Vector2i::new(global_position.x.floor() as i32, global_position.z.floor() as i32);
+        self.get_texel(MapType::TYPE_CONTROL, texel)
+            .map_or(-1, |c| control_from_color(c) as i64)
+    }
+
+    /**
+     * Writes the height of the texel at global_position, without the region's height
+     * offset. height_range is expanded to include it without a rescan.
+     */
+    #[func]
+    pub fn set_height(&mut self, global_position: Vector3, height: real) {
+        let texel = Vector2i::new(global_position.x.floor() as i32, global_position.z.floor() as i32);
+        let color = Color::from_rgba(height, 0.0, 0.0, 1.0);
+        if !self.set_texel(MapType::TYPE_HEIGHT, texel, color) {
+            log_error!(Self, "No region at {}, height not set", global_position);
+        }
+    }
+
+    /**
+     * Writes the packed 32 bit control value at global_position, bypassing the
+     * higher level control helpers. Only the low 32 bits of value are used.
+     */
+    #[func]
+    pub fn set_control_raw(&mut self, global_position: Vector3, value: i64) {
+        let texel = Vector2i::new(global_position.x.floor() as i32, global_position.z.floor() as i32);
+        if !self.set_texel(MapType::TYPE_CONTROL, texel, control_to_color(value as u32)) {
+            log_error!(Self, "No region at {}, control not set", global_position);
+        }
+    }
+
+    /**
+     * Paints the texel at global_position with base_texture blended towards
+     * overlay_texture by blend, 0 to 1, keeping its flag bits. Texture ids above 31 are
+     * clamped. A blank region is created if there is none.
+     */
+    #[func]
+    pub fn set_control(&mut self, global_position: Vector3, base_texture: u8, overlay_texture: u8, blend: f32) {
+        if base_texture > 31 || overlay_texture > 31 {
+            log_warn!(Self, "Texture ids {} and {} are clamped to 31", base_texture, overlay_texture);
+        }
+        let mut previous = self.get_control_raw(global_position);
+        if previous < 0 {
+            if self.add_region(global_position) < 0 {
+                return;
+            }
+            previous = Self::CONTROL_DEFAULT as i64;
+        }
+        let control = control_pack(
+            (base_texture as u32).min(31),
+            (overlay_texture as u32).min(31),
+            (blend.clamp(0.0, 1.0) * 255.0).round() as u32,
+            previous as u32,
+        );
+        self.set_control_raw(global_position, control as i64);
+    }
+
+    /**
+     * Decodes the control texel at global_position into base, overlay, blend (0 to 1)
+     * and the hole, navigation and auto flags. Empty if there is no region.
+     */
+    #[func]
+    pub fn get_control(&self, global_position: Vector3) -> Dictionary {
+        let mut decoded = Dictionary::new();
+        let control = self.get_control_raw(global_position);
+        if control < 0 {
+            return decoded;
+        }
+        let control = control as u32;
+        decoded.set("base", control_get_base(control) as i32);
+        decoded.set("overlay", control_get_overlay(control) as i32);
+        decoded.set("blend", control_get_blend(control) as f32 / 255.0);
+        decoded.set("hole", control_is_hole(control));
+        decoded.set("navigation", (control >> 1) & 0x1 == 1);
+        decoded.set("auto", control & 0x1 == 1);
+        decoded
+    }
+
+    /**
+     * Writes a 4 way splat control value at global_position for materials using
+     * BlendLayers::Four, keeping the texel's flag bits. textures holds 4 texture ids and
+     * weights the 0 to 7 weights of textures 1-3. Texture 0 gets the remainder of 7.
+     */
+    #[func]
+    pub fn set_control_splat(&mut self, global_position: Vector3, textures: PackedInt32Array, weights: PackedInt32Array) {
+        if textures.len() != 4 || weights.len() != 3 {
+            log_error!(Self, "Expected 4 textures and 3 weights, got {} and {}", textures.len(), weights.len());
+            return;
+        }
+        let previous = self.get_control_raw(global_position);
+        if previous < 0 {
+            log_error!(Self, "No region at {}, control not set", global_position);
+            return;
+        }
+        let t = textures.as_slice();
+        let w = weights.as_slice();
+        let control = control_pack_4way(
+            [t[0] as u32, t[1] as u32, t[2] as u32, t[3] as u32],
+            [w[0].max(0) as u32, w[1].max(0) as u32, w[2].max(0) as u32],
+            previous as u32,
+        );
+        self.set_control_raw(global_position, control as i64);
+    }
+
+    /**
+     * Paints base_texture over the texels connected to seed, like filling a valley floor.
+     * The fill spreads to neighbors, across region boundaries, whose height stays within
+     * max_height_diff of the seed and whose slope from the texel it came from stays under
+     * max_slope degrees. Holes and missing regions stop it. At most
+     * MAX_FLOOD_FILL_TEXELS are painted.
+     */
+    #[func]
+    pub fn flood_fill_control(
+        &mut self,
+        seed: Vector3,
+        base_texture: i32,
+        max_height_diff: f32,
+        max_slope: f32,
+    ) {
+        if !(0..32).contains(&base_texture) {
+            log_error!(Self, "Base texture {} out of range 0..32", base_texture);
+            return;
+        }
+        let start = Vector2i::new(seed.x.floor() as i32, seed.z.floor() as i32);
+        let seed_height = match self.get_height_texel(start) {
+            Some(h) if !self.is_hole_texel(start) => h,
+            _ => {
+                log_error!(Self, "No terrain at {}, nothing to fill", seed);
+                return;
+            }
+        };
+        // A rise over one texel, so tan() of the slope
+        let max_rise = max_slope.to_radians().tan();
+        let mut visited = HashSet::new();
+        let mut queue = VecDeque::new();
+        visited.insert(start);
+        queue.push_back((start, seed_height));
+        let mut painted = 0;
+        while let Some((texel, height)) = queue.pop_front() {
+            if painted >= Self::MAX_FLOOD_FILL_TEXELS {
+                log_warn!(Self, "Flood fill reached the limit of {} texels, stopping", Self::MAX_FLOOD_FILL_TEXELS);
+                break;
+            }
+            if let Some(control) = self.get_texel(MapType::TYPE_CONTROL, texel) {
+                let bits = control.r.to_bits();
+                let bits = control_pack(
+                    base_texture as u32,
+                    control_get_overlay(bits),
+                    control_get_blend(bits),
+                    bits,
+                );
+                self.set_texel(MapType::TYPE_CONTROL, texel, Color::from_rgba(f32::from_bits(bits), 0.0, 0.0, 1.0));
+                painted += 1;
+            }
+            for dir in [Vector2i::new(1, 0), Vector2i::new(-1, 0), Vector2i::new(0, 1), Vector2i::new(0, -1)] {
+                let next = texel + dir;
+                if visited.contains(&next) || self.is_hole_texel(next) {
+                    continue;
+                }
+                let next_height = match self.get_height_texel(next) {
+                    Some(h) => h,
+                    None => continue,
+                };
+                if (next_height - seed_height).abs() > max_height_diff || (next_height - height).abs() > max_rise {
+                    continue;
+                }
+                visited.insert(next);
+                queue.push_back((next, next_height));
+            }
+        }
+        log_info!(Self, "Flood fill painted {} texels with texture {}", painted, base_texture);
+        self.update_regions(false);
+    }
+
+    /**
+     * Removes single texel spikes, such as sensor errors in imported elevation data.
+     * Texels further than threshold from the median of their 3x3 neighborhood, read
+     * across region boundaries, are replaced by that median. Unlike smoothing, texels
+     * that fit their surroundings are left untouched. Returns the number of texels fixed.
+     */
+    #[func]
+    pub fn despike(&mut self, threshold: f32) -> i32 {
+        let size = self.region_size.ord();
+        let mut fixes: Vec<(Vector2i, real)> = Vec::new();
+        for (idx, offset) in self.region_offsets.iter_shared().enumerate() {
+            let step = 1 << self.get_region_lod_at(idx);
+            let origin = offset * size;
+            for y in (origin.y..origin.y + size).step_by(step) {
+                for x in (origin.x..origin.x + size).step_by(step) {
+                    let texel = Vector2i::new(x, y);
+                    let height = match self.get_texel(MapType::TYPE_HEIGHT, texel) {
+                        Some(c) if c.r.is_finite() => c.r,
+                        _ => continue,
+                    };
+                    let mut window: Vec<real> = Vec::with_capacity(9);
+                    for dy in -1..=1 {
+                        for dx in -1..=1 {
+                            let neighbor = texel + Vector2i::new(dx * step as i32, dy * step as i32);
+                            if let Some(c) = self.get_texel(MapType::TYPE_HEIGHT, neighbor) {
+                                if c.r.is_finite() {
+                                    window.push(c.r);
+                                }
+                            }
+                        }
+                    }
+                    if let Some(median) = Self::spike_median(height, window, threshold) {
+                        fixes.push((texel, median));
+                    }
+                }
+            }
+        }
+        // Written after the scan so fixed texels don't shift their neighbors' medians
+        for (texel, median) in fixes.iter() {
+            self.set_texel(MapType::TYPE_HEIGHT, *texel, Color::from_rgba(*median, 0.0, 0.0, 1.0));
+        }
+        log_info!(Self, "Despike replaced {} texels", fixes.len());
+        if !fixes.is_empty() {
+            // Spikes usually set the range, so it can shrink
+            self.update_height_range();
+            self.update_regions(false);
+        }
+        fixes.len() as i32
+    }
+
+    // The median of p_window, the finite heights of a 3x3 neighborhood including p_height
+    // at its center, if p_height is further than p_threshold from it
+    fn spike_median(p_height: real, mut p_window: Vec<real>, p_threshold: real) -> Option<real> {
+        p_window.sort_by(|a, b| a.total_cmp(b));
+        let median = p_window[p_window.len() / 2];
+        if (p_height - median).abs() > p_threshold {
+            Some(median)
+        } else {
+            None
+        }
+    }
+
+    /**
+     * Samples get_height() at samples evenly spaced points from from to to, both included,
+     * for plotting a terrain cross section. Heights over holes or without a region are NAN.
+     */
+    #[func]
+    pub fn sample_height_line(&self, from: Vector3, to: Vector3, samples: i32) -> PackedFloat32Array {
+        if samples <= 0 {
+            return PackedFloat32Array::new();
+        }
+        let heights = Self::line_heights(from, to, samples as usize, |pos| self.get_height_exact(pos));
+        PackedFloat32Array::from(heights.as_slice())
+    }
+
+    // sample_height_line() over the heights p_height returns
+    fn line_heights(
+        p_from: Vector3,
+        p_to: Vector3,
+        p_samples: usize,
+        p_height: impl Fn(Vector3) -> real,
+    ) -> Vec<real> {
+        if p_samples == 1 {
+            return vec![p_height(p_from)];
+        }
+        (0..p_samples)
+            .map(|i| p_height(p_from.lerp(p_to, i as real / (p_samples - 1) as real)))
+            .collect()
+    }
+
+    /**
+     * Returns a ring of segments points at radius around center, each placed on the
+     * terrain, for drawing brush outlines. Points over holes or without a region have
+     * a NAN height.
+     */
+    #[func]
+    pub fn get_brush_footprint(&self, center: Vector3, radius: real, segments: i32) -> PackedVector3Array {
+        if segments <= 0 {
+            return PackedVector3Array::new();
+        }
+        let ring = Self::footprint_ring(center, radius, segments as usize, |pos| {
+            self.get_height_exact(pos)
+        });
+        PackedVector3Array::from(ring.as_slice())
+    }
+
+    // get_brush_footprint() over the heights p_height returns
+    fn footprint_ring(
+        p_center: Vector3,
+        p_radius: real,
+        p_segments: usize,
+        p_height: impl Fn(Vector3) -> real,
+    ) -> Vec<Vector3> {
+        (0..p_segments)
+            .map(|i| {
+                let angle = i as real / p_segments as real * std::f32::consts::TAU;
+                let mut pos =
+                    p_center + Vector3::new(angle.cos() * p_radius, 0.0, angle.sin() * p_radius);
+                pos.y = p_height(pos);
+                pos
+            })
+            .collect()
+    }
+
+    /**
+     * Returns the terrain normal at global_position, or a NAN vector over holes or
+     * where there is no region.
+     */
+    #[func]
+    pub fn get_normal(&self, global_position: Vector3) -> Vector3 {
+        let center = self.get_height_exact(global_position);
+        if center.is_nan() {
+            return Vector3::new(real::NAN, real::NAN, real::NAN);
+        }
+        let sample = |dx: real, dz: real| -> real {
+            let h = self.get_height_exact(global_position + Vector3::new(dx, 0.0, dz));
+            if h.is_nan() { center } else { h }
+        };
+        Self::calc_normal(
+            sample(-1.0, 0.0),
+            sample(1.0, 0.0),
+            sample(0.0, -1.0),
+            sample(0.0, 1.0),
+        )
+    }
+
+    /**
+     * Returns the height gradient (dH/dx, dH/dz) at global_position from central
+     * differences, falling back to one sided differences at the edge of loaded terrain.
+     * Returns Vector2::ZERO over holes or where there is no region.
+     */
+    #[func]
+    pub fn get_height_gradient(&self, global_position: Vector3) -> Vector2 {
+        Self::height_gradient(global_position, |pos| self.get_height_exact(pos))
+    }
+
+    // get_height_gradient() over the heights p_height returns, NAN where there are none
+    fn height_gradient(p_position: Vector3, p_height: impl Fn(Vector3) -> real) -> Vector2 {
+        let center = p_height(p_position);
+        if center.is_nan() {
+            return Vector2::ZERO;
+        }
+        let sample = |dx: real, dz: real| -> Option<real> {
+            let h = p_height(p_position + Vector3::new(dx, 0.0, dz));
+            if h.is_nan() { None } else { Some(h) }
+        };
+        let derivative = |minus: Option<real>, plus: Option<real>| -> real {
+            match (minus, plus) {
+                (Some(m), Some(p)) => (p - m) * 0.5,
+                (Some(m), None) => center - m,
+                (None, Some(p)) => p - center,
+                (None, None) => 0.0,
+            }
+        };
+        Vector2::new(
+            derivative(sample(-1.0, 0.0), sample(1.0, 0.0)),
+            derivative(sample(0.0, -1.0), sample(0.0, 1.0)),
+        )
+    }
+
+    /**
+     * Returns the (min, max) height over the 2^lod texel block containing global_position,
+     * from a cached per-region pyramid. Lod 0 is a single texel. Returns (NAN, NAN) where
+     * there is no region.
+     */
+    #[func]
+    pub fn get_height_lod(&self, global_position: Vector3, lod: i32) -> Vector2 {
+        let texel = Vector2i::new(
+            global_position.x.floor() as i32,
+            global_position.z.floor() as i32,
+        );
+        let (idx, local) = match self.locate_texel(texel) {
+            Some(x) if x.0 < self.height_maps.len() => x,
+            _ => return Vector2::new(real::NAN, real::NAN),
+        };
+        let offset = self.get_region_height_offset_at(idx);
+        // The pyramid of a reduced detail region starts at its own resolution
+        let region_lod = self.get_region_lod_at(idx);
+        let local = Vector2i::new(local.x >> region_lod, local.y >> region_lod);
+        let lod = lod - region_lod;
+        if lod <= 0 {
+            let h = self.height_maps.get(idx).get_pixel(local.x, local.y).r + offset;
+            return Vector2::new(h, h);
+        }
+
+        let mut pyramids = self.height_pyramids.borrow_mut();
+        if pyramids.len() < self.height_maps.len() {
+            pyramids.resize_with(self.height_maps.len(), || None);
+        }
+        let pyramid = pyramids[idx].get_or_insert_with(|| {
+            log_debug!(Self, "Building height pyramid for region {}", idx);
+            HeightPyramid::build(&self.height_maps.get(idx))
+        });
+        let lod = lod.min(pyramid.max_lod());
+        pyramid.get(lod, local) + Vector2::new(offset, offset)
+    }
+
+    /**
+     * Shifts the whole region containing global_position up or down by offset without
+     * touching its height data. Applied in get_height() and in the shader.
+     */
+    #[func]
+    pub fn set_region_height_offset(&mut self, global_position: Vector3, offset: real) {
+        let idx = self.get_region_index_at(self.get_region_location(global_position));
+        if idx < 0 {
+            log_error!(Self, "No region found at: {}", global_position);
+            return;
+        }
+        let region_count = self.region_offsets.len();
+        if self.region_height_offsets.len() < region_count {
+            self.region_height_offsets.resize(region_count);
+        }
+        self.region_height_offsets.set(idx as usize, offset);
+        self.modified = true;
+        self.base_mut().emit_signal("height_maps_changed".into(), &[]);
+    }
+
+    #[func]
+    pub fn get_region_height_offset(&self, global_position: Vector3) -> real {
+        let idx = self.get_region_index_at(self.get_region_location(global_position));
+        if idx < 0 {
+            return 0.0;
+        }
+        self.get_region_height_offset_at(idx as usize)
+    }
+
+    /**
+     * Height offsets for every region, padded with 0 to the number of regions.
+     */
+    #[func]
+    pub fn get_region_height_offsets(&self) -> PackedFloat32Array {
+        let mut offsets = self.region_height_offsets.clone();
+        if offsets.len() < self.region_offsets.len() {
+            offsets.resize(self.region_offsets.len());
+        }
+        offsets
+    }
+
+    /**
+     * Builds one image covering p_region_rect (in region grid coordinates) by copying
+     * each region's p_map_type image into place. Cells without a region are left as p_fill.
+     */
+    pub fn get_stitched_image(
+        &self,
+        p_map_type: MapType,
+        p_region_rect: Rect2i,
+        p_fill: Color,
+    ) -> Option<Gd<Image>> {
+        let size = self.region_size.ord();
+        let img_size = p_region_rect.size * size;
+        if img_size.x <= 0 || img_size.y <= 0 {
+            log_error!(Self, "Invalid region rect: {:?}", p_region_rect);
+            return None;
+        }
+        let format = p_map_type.format();
+        let mut img = Image::create(img_size.x, img_size.y, false, format)?;
+        img.fill(p_fill);
+
+        let maps = self.get_upload_layers(p_map_type);
+        for y in 0..p_region_rect.size.y {
+            for x in 0..p_region_rect.size.x {
+                let cell = Vector2i::new(x, y);
+                let idx = self.get_region_index_at(p_region_rect.position + cell);
+                if idx < 0 || idx as usize >= maps.len() {
+                    continue;
+                }
+                let mut src = maps.get(idx as usize);
+                if src.get_format() != format {
+                    src = src.duplicate().map(|r| r.cast::<Image>()).unwrap_or(src);
+                    src.convert(format);
+                }
+                img.blit_rect(src, Rect2i::new(Vector2i::ZERO, self.region_sizev), cell * size);
+            }
+        }
+        Some(img)
+    }
+
+    /**
+     * Returns the control maps over region (in region grid coordinates) as one FORMAT_RF
+     * image holding the packed control bits, like get_control_raw(). Regions are copied
+     * byte for byte, so read texels with to_bits(). Unloaded cells get the blank region
+     * control value.
+     */
+    #[func]
+    pub fn get_stitched_control_image(&self, region: Rect2i) -> Gd<Image> {
+        self.get_stitched_image(
+            MapType::TYPE_CONTROL,
+            region,
+            Color::from_rgba(f32::from_bits(Self::CONTROL_DEFAULT), 0.0, 0.0, 1.0),
+        )
+        .unwrap_or_else(Image::new_gd)
+    }
+
+    /**
+     * Returns the color maps over region (in region grid coordinates) as one RGBA8 image.
+     * Unloaded cells get the blank region color, white with roughness 1.
+     */
+    #[func]
+    pub fn get_stitched_color_image(&self, region: Rect2i) -> Gd<Image> {
+        self.get_stitched_image(MapType::TYPE_COLOR, region, Color::from_rgba(1.0, 1.0, 1.0, 1.0))
+            .unwrap_or_else(Image::new_gd)
+    }
+
+    /**
+     * Returns an R8 image over p_region_rect (in region grid coordinates) where each texel
+     * is the terrain slope: 0 is flat, 255 is vertical. Unloaded cells are 0.
+     */
+    #[func]
+    pub fn generate_slope_map(&self, region: Rect2i) -> Gd<Image> {
+        let heights = match self.get_stitched_image(
+            MapType::TYPE_HEIGHT,
+            region,
+            Color::from_rgba(real::NAN, 0.0, 0.0, 1.0),
+        ) {
+            Some(img) => img,
+            None => return Image::new_gd(),
+        };
+        let width = heights.get_width();
+        let depth = heights.get_height();
+        let mut slope = match Image::create(width, depth, false, Format::R8) {
+            Some(img) => img,
+            None => return Image::new_gd(),
+        };
+        slope.fill(Color::from_rgba(0.0, 0.0, 0.0, 1.0));
+
+        let height_at = |x: i32, y: i32, default: real| -> real {
+            let h = heights.get_pixel(x.clamp(0, width - 1), y.clamp(0, depth - 1)).r;
+            if h.is_nan() { default } else { h }
+        };
+        for y in 0..depth {
+            for x in 0..width {
+                let h = heights.get_pixel(x, y).r;
+                if h.is_nan() {
+                    continue;
+                }
+                let normal = Self::calc_normal(
+                    height_at(x - 1, y, h),
+                    height_at(x + 1, y, h),
+                    height_at(x, y - 1, h),
+                    height_at(x, y + 1, h),
+                );
+                let angle = normal.y.clamp(0.0, 1.0).acos() / std::f32::consts::FRAC_PI_2;
+                slope.set_pixel(x, y, Color::from_rgba(angle, angle, angle, 1.0));
+            }
+        }
+        slope
+    }
+
+    /**
+     * Bakes horizon based ambient occlusion for every region. For each texel, samples
+     * directions are marched up to radius units, and the highest horizon found in each
+     * darkens the texel. Enable baked_ao on the material to apply it. This is slow, so
+     * it runs region by region, logging and emitting ambient_occlusion_progress after each.
+     * Connect to that signal deferred, the storage is busy while it is emitted.
+     */
+    #[func]
+    pub fn bake_ambient_occlusion(&mut self, samples: i32, radius: real) {
+        if samples < 1 || radius <= 0.0 {
+            log_error!(Self, "Invalid AO samples {} or radius {}", samples, radius);
+            return;
+        }
+        let size = self.region_size.ord();
+        let steps = (radius.ceil() as i32).clamp(1, Self::MAX_AO_STEPS);
+        let step_length = radius / steps as real;
+        let directions: Vec<Vector2> = (0..samples)
+            .map(|i| {
+                let angle = i as real / samples as real * std::f32::consts::TAU;
+                Vector2::new(angle.cos(), angle.sin())
+            })
+            .collect();
+
+        let region_count = self.region_offsets.len();
+        log_info!(Self, "Baking ambient occlusion for {} regions, {} samples, radius {}", region_count, samples, radius);
+        let mut ao_maps: Array<Gd<Image>> = Array::new();
+        let offsets = self.region_offsets.clone();
+        for (idx, offset) in offsets.iter_shared().enumerate() {
+            let origin = offset * size;
+            let mut bytes = PackedByteArray::new();
+            bytes.resize((size * size) as usize);
+            {
+                let bytes_mut = bytes.as_mut_slice();
+                for y in 0..size {
+                    for x in 0..size {
+                        let texel = origin + Vector2i::new(x, y);
+                        let h0 = self.get_height_texel(texel).unwrap_or(0.0);
+                        let mut occlusion = 0.0;
+                        for dir in &directions {
+                            let mut max_slope: real = 0.0;
+                            for step in 1..=steps {
+                                let dist = step as real * step_length;
+                                let p = Vector2::new(texel.x as real, texel.y as real) + *dir * dist;
+                                let p = Vector2i::new(p.x.round() as i32, p.y.round() as i32);
+                                if let Some(h) = self.get_height_texel(p) {
+                                    max_slope = max_slope.max((h - h0) / dist);
+                                }
+                            }
+                            // Sine of the horizon angle
+                            occlusion += max_slope / (1.0 + max_slope * max_slope).sqrt();
+                        }
+                        let ao = 1.0 - occlusion / samples as real;
+                        bytes_mut[(y * size + x) as usize] = (ao.clamp(0.0, 1.0) * 255.0) as u8;
+                    }
+                }
+            }
+            match Image::create_from_data(size, size, false, Format::R8, bytes) {
+                Some(img) => ao_maps.push(img),
+                None => {
+                    log_error!(Self, "Could not create the AO image for region {}", offset);
+                    return;
+                }
+            }
+            let progress = (idx + 1) as real / region_count as real;
+            log_info!(Self, "Ambient occlusion {:.0}% done", progress * 100.0);
+            self.base_mut().emit_signal("ambient_occlusion_progress".into(), &[progress.to_variant()]);
+        }
+
+        self.generated_ao_maps.clear();
+        match GeneratedTex::create_from_layers(ao_maps.clone()) {
+            Some(tex) => self.generated_ao_maps = tex,
+            None => log_debug!(Self, "No regions, no AO texture generated"),
+        }
+        self.ao_maps = ao_maps;
+        self.base_mut().emit_signal("maps_changed".into(), &[]);
+    }
+
+    #[signal]
+    fn ambient_occlusion_progress(progress: real);
+
+    // 16x16 grid of 1 based indices into get_region_offsets(), 0 where there is no region
+    #[func]
+    pub fn get_region_map(&self) -> PackedInt32Array {
+        self.region_map.clone()
+    }
+
+    // Texture arrays generated by update_regions(), invalid until the first update
+    #[func]
+    pub fn get_height_maps_rid(&self) -> Rid {
+        self.generated_height_maps.rid()
+    }
+
+    #[func]
+    pub fn get_control_maps_rid(&self) -> Rid {
+        self.generated_control_maps.rid()
+    }
+
+    #[func]
+    pub fn get_color_maps_rid(&self) -> Rid {
+        self.generated_color_maps.rid()
+    }
+
+    /**
+     * Returns the texture array of the baked ambient occlusion, or an invalid RID if it
+     * was not baked or no longer matches the regions.
+     */
+    #[func]
+    pub fn get_ao_maps_rid(&self) -> Rid {
+        if self.ao_maps.len() != self.region_offsets.len() {
+            return Rid::Invalid;
+        }
+        self.generated_ao_maps.rid()
+    }
+
+    /**
+     * Writes all regions and their maps to path through the ResourceSaver, as a Resource
+     * holding them in its metadata. Height maps are stored as FORMAT_RH with save_16_bit,
+     * control and color maps as PNG with compress_on_save. Baked AO maps are stored raw.
+     * The region map is derived from the region offsets, so load() rebuilds it rather than
+     * reading it back.
+     */
+    #[func]
+    pub fn save(&mut self, path: GString) -> Error {
+        let encode = |p_map_type: MapType, p_maps: &Array<Gd<Image>>| -> Array<PackedByteArray> {
+            let mut encoded = Array::new();
+            for img in p_maps.iter_shared() {
+                let img = if p_map_type == MapType::TYPE_HEIGHT && self.save_16_bit {
+                    let mut half = Image::new_gd();
+                    half.copy_from(img);
+                    half.convert(Format::RH);
+                    half
+                } else {
+                    img
+                };
+                encoded.push(self.encode_map_image(p_map_type, &img));
+            }
+            encoded
+        };
+        let mut data = Dictionary::new();
+        data.set("version", Self::CURRENT_VERSION);
+        data.set("region_size", self.region_size.ord());
+        data.set("region_offsets", self.region_offsets.clone());
+        data.set("region_height_offsets", self.region_height_offsets.clone());
+        data.set("region_lods", self.region_lods.clone());
+        data.set("height_maps", encode(MapType::TYPE_HEIGHT, &self.height_maps));
+        data.set("control_maps", encode(MapType::TYPE_CONTROL, &self.control_maps));
+        data.set("color_maps", encode(MapType::TYPE_COLOR, &self.color_maps));
+        // AO baked before regions were added or removed no longer matches them
+        if self.ao_maps.len() == self.region_offsets.len() {
+            let mut ao_maps: Array<PackedByteArray> = Array::new();
+            for img in self.ao_maps.iter_shared() {
+                let raw = Self::encode_raw_map(
+                    img.get_width(),
+                    img.get_height(),
+                    img.get_format().ord(),
+                    img.get_data().as_slice(),
+                );
+                ao_maps.push(PackedByteArray::from(raw.as_slice()));
+            }
+            data.set("ao_maps", ao_maps);
+        }
+
+        let mut res = Resource::new_gd();
+        res.set_meta(Self::SAVE_META.into(), data.to_variant());
+        let err = ResourceSaver::singleton().save_ex(res).path(path.clone()).done();
+        if err != Error::OK {
+            log_error!(Self, "Could not save storage to {}: {:?}", path, err);
+            return err;
+        }
+        log_info!(Self, "Saved {} regions to {}", self.region_offsets.len(), path);
+        self.version = Self::CURRENT_VERSION;
+        self.modified = false;
+        Error::OK
+    }
+
+    /**
+     * Replaces all regions with the ones saved to path by save(). The storage is left
+     * untouched if the file can't be read.
+     */
+    #[func]
+    pub fn load(&mut self, path: GString) -> Error {
+        let data = match ResourceLoader::singleton().load(path.clone()) {
+            Some(res) if res.has_meta(Self::SAVE_META.into()) => {
+                match res.get_meta(Self::SAVE_META.into()).try_to::<Dictionary>() {
+                    Ok(data) => data,
+                    Err(_) => {
+                        log_error!(Self, "{} holds no storage data", path);
+                        return Error::ERR_INVALID_DATA;
+                    }
+                }
+            }
+            Some(_) => {
+                log_error!(Self, "{} was not written by Terrain3DStorage.save()", path);
+                return Error::ERR_INVALID_DATA;
+            }
+            None => {
+                log_error!(Self, "Could not load storage from {}", path);
+                return Error::ERR_FILE_CANT_OPEN;
+            }
+        };
+        let version = data.get("version").and_then(|v| v.try_to::<real>().ok());
+        let region_size = data
+            .get("region_size")
+            .and_then(|v| v.try_to::<i32>().ok())
+            .and_then(RegionSize::from_ord);
+        let region_offsets = data.get("region_offsets").and_then(|v| v.try_to::<Array<Vector2i>>().ok());
+        let (version, region_size, region_offsets) = match (version, region_size, region_offsets) {
+            (Some(version), Some(region_size), Some(region_offsets)) => (version, region_size, region_offsets),
+            _ => {
+                log_error!(Self, "{} is missing the version, region size or region offsets", path);
+                return Error::ERR_INVALID_DATA;
+            }
+        };
+        let decode = |p_map_type: MapType, p_key: &str| -> Option<Array<Gd<Image>>> {
+            let encoded = data.get(p_key)?.try_to::<Array<PackedByteArray>>().ok()?;
+            if encoded.len() != region_offsets.len() {
+                return None;
+            }
+            let mut maps = Array::new();
+            for bytes in encoded.iter_shared() {
+                let mut img = Self::decode_map_image(p_map_type, &bytes)?;
+                // Height maps saved with save_16_bit are half floats
+                if img.get_format() != p_map_type.format() {
+                    img.convert(p_map_type.format());
+                }
+                maps.push(img);
+            }
+            Some(maps)
+        };
+        let maps = (
+            decode(MapType::TYPE_HEIGHT, "height_maps"),
+            decode(MapType::TYPE_CONTROL, "control_maps"),
+            decode(MapType::TYPE_COLOR, "color_maps"),
+        );
+        let (height_maps, control_maps, color_maps) = match maps {
+            (Some(height), Some(control), Some(color)) => (height, control, color),
+            _ => {
+                log_error!(Self, "{} holds map data that doesn't match its {} regions", path, region_offsets.len());
+                return Error::ERR_INVALID_DATA;
+            }
+        };
+
+        // Optional, only saved once AO was baked
+        let ao_maps = data
+            .get("ao_maps")
+            .and_then(|v| v.try_to::<Array<PackedByteArray>>().ok())
+            .and_then(|encoded| {
+                let mut maps = Array::new();
+                for bytes in encoded.iter_shared() {
+                    let body = match bytes.as_slice().split_first() {
+                        Some((&Self::IMAGE_RAW, body)) => body,
+                        _ => return None,
+                    };
+                    let (width, height, format, pixels) = Self::decode_raw_map(body)?;
+                    let format = Format::try_from_ord(format)?;
+                    maps.push(Image::create_from_data(width, height, false, format, PackedByteArray::from(pixels))?);
+                }
+                Some(maps)
+            })
+            .filter(|maps| maps.len() == region_offsets.len())
+            .unwrap_or_default();
+
+        log_info!(Self, "Loading {} regions from {}", region_offsets.len(), path);
+        let region_count = region_offsets.len();
+        self.region_size = region_size;
+        self.region_sizev = Vector2i::new(region_size.ord(), region_size.ord());
+        self.region_height_offsets = data
+            .get("region_height_offsets")
+            .and_then(|v| v.try_to::<PackedFloat32Array>().ok())
+            .unwrap_or_default();
+        self.region_height_offsets.resize(region_count);
+        self.region_lods = data
+            .get("region_lods")
+            .and_then(|v| v.try_to::<PackedByteArray>().ok())
+            .unwrap_or_default();
+        self.region_lods.resize(region_count);
+        self.region_offsets = region_offsets;
+        for idx in self.find_duplicate_offsets() {
+            log_warn!(
+                Self,
+                "{} has region {} at layer {} duplicating an earlier region, call sanitize_maps() to remove it",
+                path,
+                self.region_offsets.get(idx),
+                idx
+            );
+        }
+        self.height_maps = height_maps;
+        self.control_maps = control_maps;
+        self.color_maps = color_maps;
+        self.ao_maps = ao_maps;
+        self.height_pyramids.borrow_mut().clear();
+        self.dirty_layers.clear_all();
+        self.region_map_dirty = true;
+        self.generated_height_maps.clear();
+        self.generated_control_maps.clear();
+        self.generated_color_maps.clear();
+        self.generated_ao_maps.clear();
+        self.update_height_range();
+        self.update_regions(true);
+        // Rebuilding the regions isn't an edit, but older files are flagged as modified
+        // so they get upgraded on the next save
+        self.modified = false;
+        self.set_version(version);
+        Error::OK
+    }
+
+    /**
+     * Frees the generated texture arrays right away, for deterministic teardown in tests
+     * and tools instead of waiting for the last reference to drop. Region data is kept
+     * and the textures are regenerated by the next update_regions(). Safe to call
+     * repeatedly.
+     */
+    #[func]
+    pub fn free_resources(&mut self) {
+        self.clear();
+    }
+
+    /**
+     * Returns a detached storage holding a deep copy of all regions and their metadata,
+     * to revert to later with restore_checkpoint(). Images are duplicated, so edits to
+     * this storage don't reach the checkpoint. The copy has no generated textures.
+     */
+    #[func]
+    pub fn create_checkpoint(&self) -> Gd<Terrain3DStorage> {
+        log_info!(Self, "Creating checkpoint of {} regions", self.region_offsets.len());
+        let mut checkpoint = Terrain3DStorage::new_gd();
+        checkpoint.bind_mut().copy_terrain_data(self);
+        checkpoint
+    }
+
+    /**
+     * Replaces all regions with a deep copy of those in checkpoint, made by
+     * create_checkpoint(), and regenerates the textures. The checkpoint stays usable.
+     */
+    #[func]
+    pub fn restore_checkpoint(&mut self, checkpoint: Gd<Terrain3DStorage>) {
+        if checkpoint == self.to_gd() {
+            log_warn!(Self, "Cannot restore a storage from itself");
+            return;
+        }
+        if checkpoint.bind().region_size != self.region_size {
+            log_error!(
+                Self,
+                "Checkpoint region size {} doesn't match {}",
+                checkpoint.bind().region_size.ord(),
+                self.region_size.ord()
+            );
+            return;
+        }
+        log_info!(Self, "Restoring checkpoint of {} regions", checkpoint.bind().region_offsets.len());
+        self.copy_terrain_data(&checkpoint.bind());
+        self.modified = true;
+        self.generated_height_maps.clear();
+        self.generated_control_maps.clear();
+        self.generated_color_maps.clear();
+        self.generated_ao_maps.clear();
+        self.update_regions(true);
+    }
+
+    // Deep copies region data and metadata from p_from, leaving generated textures alone
+    fn copy_terrain_data(&mut self, p_from: &Terrain3DStorage) {
+        let duplicate = |images: &Array<Gd<Image>>| -> Array<Gd<Image>> {
+            let mut copies = Array::new();
+            for img in images.iter_shared() {
+                copies.push(img.duplicate().map(|r| r.cast::<Image>()).unwrap_or(img));
+            }
+            copies
+        };
+        self.version = p_from.version;
+        self.region_size = p_from.region_size;
+        self.region_sizev = p_from.region_sizev;
+        self.height_range = p_from.height_range;
+        self.region_offsets = p_from.region_offsets.duplicate_shallow();
+        self.region_height_offsets = p_from.region_height_offsets.clone();
+        self.region_lods = p_from.region_lods.clone();
+        self.height_maps = duplicate(&p_from.height_maps);
+        self.control_maps = duplicate(&p_from.control_maps);
+        self.color_maps = duplicate(&p_from.color_maps);
+        self.ao_maps = duplicate(&p_from.ao_maps);
+        self.height_pyramids.borrow_mut().clear();
+        self.dirty_layers.clear_all();
+        self.region_map_dirty = true;
+    }
+
+    /**
+     * Returns the memory used by the map data in bytes, per map type and in total. CPU
+     * figures are the images held here, including reduced detail regions. GPU figures are
+     * the texture array layers, which are always region_size. Saved height data is half
+     * the CPU size when save_16_bit is on.
+     */
+    #[func]
+    pub fn get_storage_memory_usage(&self) -> Dictionary {
+        let mut usage = Dictionary::new();
+        let size = self.region_size.ord() as i64;
+        let region_count = self.region_offsets.len() as i64;
+        let mut cpu_total: i64 = 0;
+        let mut gpu_total: i64 = 0;
+        for (map_type, name) in [
+            (MapType::TYPE_HEIGHT, "height"),
+            (MapType::TYPE_CONTROL, "control"),
+            (MapType::TYPE_COLOR, "color"),
+        ] {
+            let bytes_per_pixel = map_type.bytes_per_pixel();
+            let cpu: i64 = self
+                .get_maps(map_type)
+                .iter_shared()
+                .map(|img| img.get_width() as i64 * img.get_height() as i64 * bytes_per_pixel)
+                .sum();
+            let gpu = Self::layers_bytes(map_type, region_count, size);
+            usage.set(format!("{}_cpu", name), cpu);
+            usage.set(format!("{}_gpu", name), gpu);
+            cpu_total += cpu;
+            gpu_total += gpu;
+            if map_type == MapType::TYPE_HEIGHT {
+                usage.set("height_saved", Self::saved_height_bytes(cpu, self.save_16_bit));
+            }
+        }
+        usage.set("cpu_total", cpu_total);
+        usage.set("gpu_total", gpu_total);
+        usage.set("total", cpu_total + gpu_total);
+        usage
+    }
+
+    // Bytes of p_count region layers of p_map_type, p_size texels wide
+    fn layers_bytes(p_map_type: MapType, p_count: i64, p_size: i64) -> i64 {
+        p_count * p_size * p_size * p_map_type.bytes_per_pixel()
+    }
+
+    // Bytes of p_height_bytes of height data once saved, halved by 16 bit saving
+    fn saved_height_bytes(p_height_bytes: i64, p_save_16_bit: bool) -> i64 {
+        if p_save_16_bit {
+            p_height_bytes / 2
+        } else {
+            p_height_bytes
+        }
+    }
+
+    /**
+     * Scans all regions for problems and returns a Dictionary keyed by region offset,
+     * each holding a PackedStringArray of issues. Problems with the region map itself are
+     * listed under the "region_map" key. Read only, nothing is repaired.
+     */
+    #[func]
+    pub fn validate(&self) -> Dictionary {
+        let mut report = Dictionary::new();
+        let region_count = self.region_offsets.len();
+
+        let mut map_issues = PackedStringArray::new();
+        for map_type in [MapType::TYPE_HEIGHT, MapType::TYPE_CONTROL, MapType::TYPE_COLOR] {
+            let len = self.get_maps(map_type).len();
+            if len != region_count {
+                map_issues.push(format!("{:?} has {} images for {} regions", map_type, len, region_count).into());
+            }
+        }
+        for (i, idx) in Self::find_invalid_cells(self.region_map.as_slice(), region_count) {
+            map_issues.push(format!("Cell {} points at invalid layer {}", i, idx).into());
+        }
+        for idx in self.find_duplicate_offsets() {
+            let offset = self.region_offsets.get(idx);
+            map_issues.push(format!("Region {} at layer {} duplicates an earlier region", offset, idx).into());
+        }
+        if !map_issues.is_empty() {
+            report.set("region_map", map_issues);
+        }
+
+        let range = if self.height_range != Vector2::ZERO { Some(self.height_range) } else { None };
+        for (idx, offset) in self.region_offsets.iter_shared().enumerate() {
+            let mut issues = PackedStringArray::new();
+            for map_type in [MapType::TYPE_HEIGHT, MapType::TYPE_CONTROL, MapType::TYPE_COLOR] {
+                let maps = self.get_maps(map_type);
+                if idx >= maps.len() {
+                    issues.push(format!("Missing {:?} image", map_type).into());
+                    continue;
+                }
+                let img = maps.get(idx);
+                if img.is_empty() {
+                    issues.push(format!("{:?} image is empty", map_type).into());
+                    continue;
+                }
+                let expected_size = Vector2i::new(
+                    self.region_sizev.x >> self.get_region_lod_at(idx),
+                    self.region_sizev.y >> self.get_region_lod_at(idx),
+                );
+                if img.get_size() != expected_size {
+                    issues.push(format!("{:?} image size {} does not match region size {}", map_type, img.get_size(), expected_size).into());
+                }
+                if img.get_format() != map_type.format() {
+                    issues.push(format!("{:?} image format {:?} should be {:?}", map_type, img.get_format(), map_type.format()).into());
+                }
+            }
+
+            if idx < self.height_maps.len() {
+                let img = self.height_maps.get(idx);
+                let img = &img;
+                let width = img.get_width();
+                let heights = (0..img.get_height()).flat_map(move |y| (0..width).map(move |x| img.get_pixel(x, y).r));
+                let (non_finite, out_of_range) = Self::count_invalid_heights(heights, range);
+                if non_finite > 0 {
+                    issues.push(format!("{} NaN or infinite height values", non_finite).into());
+                }
+                if out_of_range > 0 {
+                    issues.push(format!("{} height values outside height_range {}", out_of_range, self.height_range).into());
+                }
+            }
+
+            if !issues.is_empty() {
+                report.set(offset, issues);
+            }
+        }
+        report
+    }
+
+    // Cells of p_region_map holding neither 0 nor a 1 based index into p_region_count regions
+    fn find_invalid_cells(p_region_map: &[i32], p_region_count: usize) -> Vec<(usize, i32)> {
+        p_region_map
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, idx)| idx < 0 || idx as usize > p_region_count)
+            .collect()
+    }
+
+    // Counts the NaN or infinite heights, and the finite ones outside p_range if given
+    fn count_invalid_heights(p_heights: impl Iterator<Item = real>, p_range: Option<Vector2>) -> (usize, usize) {
+        let mut non_finite = 0;
+        let mut out_of_range = 0;
+        for h in p_heights {
+            if !h.is_finite() {
+                non_finite += 1;
+            } else if p_range.is_some_and(|range| h < range.x || h > range.y) {
+                out_of_range += 1;
+            }
+        }
+        (non_finite, out_of_range)
+    }
+
+    /**
+     * Repairs problems validate() reports that have a safe fix: regions sharing a grid
+     * cell with an earlier region are removed, keeping the first. Returns the number of
+     * regions removed.
+     */
+    #[func]
+    pub fn sanitize_maps(&mut self) -> i32 {
+        let duplicates = self.find_duplicate_offsets();
+        if duplicates.is_empty() {
+            return 0;
+        }
+        // Remove from the back so earlier indices stay valid
+        for &idx in duplicates.iter().rev() {
+            log_warn!(Self, "Removing duplicate region {} at layer {}", self.region_offsets.get(idx), idx);
+            self.remove_region_at(idx);
+        }
+        self.update_regions(true);
+        duplicates.len() as i32
+    }
+
+    // Indices into region_offsets of regions whose grid cell an earlier region already uses
+    fn find_duplicate_offsets(&self) -> Vec<usize> {
+        let offsets: Vec<Vector2i> = self.region_offsets.iter_shared().collect();
+        Self::duplicate_indices(&offsets)
+    }
+
+    fn duplicate_indices(p_offsets: &[Vector2i]) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        p_offsets
+            .iter()
+            .enumerate()
+            .filter(|(_, offset)| !seen.insert(**offset))
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    #[signal]
+    fn height_maps_changed();
+
+    #[signal]
+    fn region_map_changed();
+
+    // Emitted when the control texture array is regenerated, replacing its RID
+    #[signal]
+    fn control_maps_changed();
+
+    // Emitted when the color texture array is regenerated, replacing its RID
+    #[signal]
+    fn color_maps_changed();
+
+    // Emitted when regions are added or removed
+    #[signal]
+    fn regions_changed();
+
+    // Emitted once per update_regions(), or once per begin_edit() / end_edit() batch
+    #[signal]
+    fn maps_changed();
+
+    /**
+     * Starts a batch of edits. Until the matching end_edit(), update_regions() only
+     * records that an update is pending, so a brush stroke uploads textures and emits
+     * maps_changed once. Batches may nest.
+     */
+    #[func]
+    pub fn begin_edit(&mut self) {
+        self.edit_batch.begin();
+    }
+
+    /**
+     * Ends a batch started by begin_edit(), running the pending update_regions() when
+     * the outermost batch ends.
+     */
+    #[func]
+    pub fn end_edit(&mut self) {
+        match self.edit_batch.end() {
+            Some(true) => self.update_regions(false),
+            Some(false) => {}
+            None => log_error!(Self, "end_edit() called without begin_edit()"),
+        }
+    }
+
+    pub fn update_regions(&mut self, force_emit: bool) {
+        if self.edit_batch.defer_update() {
+            return;
+        }
+        if self.update_maps(force_emit) {
+            self.base_mut().emit_signal("maps_changed".into(), &[]);
+        }
+    }
+
+    // Regenerates or updates the generated textures, returning whether anything changed
+    fn update_maps(&mut self, mut force_emit: bool) -> bool {
+        if self.region_map_dirty {
+            self.update_region_map();
+            force_emit = true;
+        }
+        if self.generated_height_maps.dirty() {
+            log_debug!(Self, "Regenerating height layered texture from {} maps", self.height_maps.len());
+            match GeneratedTex::create_from_layers(self.get_upload_layers(MapType::TYPE_HEIGHT)) {
+                Some(x) => {
+                    self.generated_height_maps = x;
+                },
+                None => {
+                    log_error!(Self, "Could not create a height maps from stored value");
+                    return force_emit;
+                }
+            }
+            self.height_pyramids.borrow_mut().clear();
+            self.dirty_layers.clear(MapType::TYPE_HEIGHT);
+            self.calc_height_range();
+            force_emit = true;
+            self.modified = true;
+            self.base_mut().emit_signal("height_maps_changed".into(), &[]);
+        } else if self.update_dirty_layers(MapType::TYPE_HEIGHT) {
+            force_emit = true;
+            self.base_mut().emit_signal("height_maps_changed".into(), &[]);
+        }
+        for (map_type, signal) in [
+            (MapType::TYPE_CONTROL, "control_maps_changed"),
+            (MapType::TYPE_COLOR, "color_maps_changed"),
+        ] {
+            if self.get_generated_tex(map_type).dirty() {
+                log_debug!(Self, "Regenerating {:?} layered texture from {} maps", map_type, self.get_maps(map_type).len());
+                match GeneratedTex::create_from_layers(self.get_upload_layers(map_type)) {
+                    Some(x) => {
+                        *self.get_generated_tex_mut(map_type) = x;
+                    },
+                    None => {
+                        log_error!(Self, "Could not create {:?} maps from stored value", map_type);
+                        continue;
+                    }
+                }
+                self.dirty_layers.clear(map_type);
+                force_emit = true;
+                self.modified = true;
+                self.base_mut().emit_signal(signal.into(), &[]);
+            } else {
+                // Layers are updated in place, the texture RID stays the same
+                force_emit |= self.update_dirty_layers(map_type);
+            }
+        }
+        // Baked AO is only regenerated while it still has one map per region
+        if self.generated_ao_maps.dirty()
+            && !self.ao_maps.is_empty()
+            && self.ao_maps.len() == self.region_offsets.len()
+        {
+            log_debug!(Self, "Regenerating AO layered texture from {} maps", self.ao_maps.len());
+            match GeneratedTex::create_from_layers(self.ao_maps.clone()) {
+                Some(x) => {
+                    self.generated_ao_maps = x;
+                    force_emit = true;
+                },
+                None => log_error!(Self, "Could not create AO maps from stored value"),
+            }
+        }
+        force_emit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_vec_approx(p_a: Vector3, p_b: Vector3) {
+        assert!((p_a - p_b).length() < 1e-5, "{p_a} != {p_b}");
+    }
+
+    #[test]
+    fn calc_normal_flat_points_up() {
+        assert_vec_approx(Terrain3DStorage::calc_normal(3.0, 3.0, 3.0, 3.0), Vector3::UP);
+    }
+
+    #[test]
+    fn calc_normal_tilts_away_from_rising_ground() {
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert_vec_approx(Terrain3DStorage::calc_normal(0.0, 2.0, 0.0, 0.0), Vector3::new(-half, half, 0.0));
+        assert_vec_approx(Terrain3DStorage::calc_normal(2.0, 0.0, 0.0, 0.0), Vector3::new(half, half, 0.0));
+        assert_vec_approx(Terrain3DStorage::calc_normal(0.0, 0.0, 2.0, 0.0), Vector3::new(0.0, half, half));
+    }
+
+    #[test]
+    fn validate_checks_flag_a_corrupted_storage() {
+        // Two regions, but cells pointing past them and below 0
+        let region_map = [0, 1, 2, 3, -1, 0];
+        assert_eq!(Terrain3DStorage::find_invalid_cells(&region_map, 2), vec![(3, 3), (4, -1)]);
+        assert!(Terrain3DStorage::find_invalid_cells(&[0, 2, 1], 2).is_empty());
+
+        let heights = [1.0, real::NAN, 50.0, real::INFINITY, -20.0, 40.0];
+        let range = Some(Vector2::new(-10.0, 40.0));
+        assert_eq!(Terrain3DStorage::count_invalid_heights(heights.into_iter(), range), (2, 2));
+        // A zero height_range isn't checked
+        assert_eq!(Terrain3DStorage::count_invalid_heights(heights.into_iter(), None), (2, 0));
+    }
+
+    #[test]
+    fn region_height_offset_shifts_interpolated_heights_exactly() {
+        let heights = [1.0, 2.0, 3.0, 5.0];
+        let offset = 8.0;
+        // get_height_texel() adds the offset to every texel of the region
+        let shifted = heights.map(|h| h + offset);
+        for tx in [0.0, 0.25, 0.5, 0.75] {
+            for tz in [0.0, 0.25, 0.5, 0.75] {
+                let t = Vector2::new(tx, tz);
+                let base = Terrain3DStorage::bilerp(heights, t);
+                assert_eq!(Terrain3DStorage::bilerp(shifted, t), base + offset, "at {t:?}");
+            }
+        }
+        assert_eq!(Terrain3DStorage::bilerp(heights, Vector2::new(0.5, 0.5)), 2.75);
+    }
+
+    #[test]
+    fn height_gradient_matches_a_linear_slope() {
+        let slope = |p: Vector3| 2.0 * p.x - 0.5 * p.z + 3.0;
+        let gradient = Terrain3DStorage::height_gradient(Vector3::new(10.0, 0.0, -4.0), slope);
+        assert_eq!(gradient, Vector2::new(2.0, -0.5));
+
+        // One sided at the edge of loaded terrain
+        let edge = |p: Vector3| if p.x > 10.0 { real::NAN } else { slope(p) };
+        let gradient = Terrain3DStorage::height_gradient(Vector3::new(10.0, 0.0, -4.0), edge);
+        assert_eq!(gradient, Vector2::new(2.0, -0.5));
+
+        let hole = |_: Vector3| real::NAN;
+        assert_eq!(Terrain3DStorage::height_gradient(Vector3::ZERO, hole), Vector2::ZERO);
+    }
+
+    #[test]
+    fn footprint_ring_has_segments_points_at_radius() {
+        let center = Vector3::new(5.0, 0.0, -3.0);
+        let ring = Terrain3DStorage::footprint_ring(center, 4.0, 12, |p| p.x * 0.5);
+        assert_eq!(ring.len(), 12);
+        for point in &ring {
+            let offset = Vector2::new(point.x - center.x, point.z - center.z);
+            assert!((offset.length() - 4.0).abs() < 1e-4);
+            assert_eq!(point.y, point.x * 0.5);
+        }
+    }
+
+    #[test]
+    fn control_raw_round_trips_through_a_texel() {
+        // Base texture 3, overlay 17, blend 128 and flags 0b101
+        let patterns: [u32; 4] = [0x1C60_0005, 0xFFFF_FFFF, 0x8000_0001, 0];
+        for pattern in patterns {
+            let raw = pattern as i64;
+            let texel = control_to_color(raw as u32);
+            assert_eq!(control_from_color(texel) as i64, raw);
+        }
+    }
+
+    #[test]
+    fn editing_one_region_leaves_only_its_layer_dirty() {
+        let mut dirty = DirtyLayers::default();
+        // Several texel edits within region 2
+        for _ in 0..3 {
+            dirty.mark(MapType::TYPE_HEIGHT, 2);
+        }
+        let layers = dirty.take(MapType::TYPE_HEIGHT);
+        assert_eq!(layers.into_iter().collect::<Vec<_>>(), vec![2]);
+        assert!(dirty.take(MapType::TYPE_HEIGHT).is_empty());
+        assert!(dirty.take(MapType::TYPE_CONTROL).is_empty());
+        assert!(dirty.take(MapType::TYPE_COLOR).is_empty());
+    }
+
+    #[test]
+    fn region_uv2_maps_opposite_corners_to_zero_and_one() {
+        let uv2 = |x: real, z: real| Terrain3DStorage::region_uv2(Vector3::new(x, 0.0, z), 64);
+        assert_eq!(uv2(128.0, -64.0), Vector2::new(0.0, 0.0));
+        assert_eq!(uv2(128.0 + 63.0, -1.0), Vector2::new(1.0, 1.0));
+        // Between the last texel and the next region stays on the region's own edge
+        assert_eq!(uv2(128.0 + 63.5, -0.5), Vector2::new(1.0, 1.0));
+        assert_eq!(uv2(128.0 + 31.5, -64.0 + 31.5), Vector2::new(0.5, 0.5));
+    }
+
+    #[test]
+    fn height_bytes_are_regions_times_size_squared_times_texel_bytes() {
+        let bytes = Terrain3DStorage::layers_bytes(MapType::TYPE_HEIGHT, 3, 1024);
+        assert_eq!(bytes, 3 * 1024 * 1024 * 4);
+        assert_eq!(Terrain3DStorage::saved_height_bytes(bytes, false), bytes);
+        assert_eq!(Terrain3DStorage::saved_height_bytes(bytes, true), 3 * 1024 * 1024 * 2);
+    }
+
+    #[test]
+    fn a_batch_of_edits_emits_maps_changed_once() {
+        let mut batch = EditBatch::default();
+        let mut emitted = 0;
+        let mut update_regions = |p_batch: &mut EditBatch| {
+            if !p_batch.defer_update() {
+                emitted += 1;
+            }
+        };
+        batch.begin();
+        for _ in 0..100 {
+            update_regions(&mut batch);
+        }
+        if batch.end() == Some(true) {
+            update_regions(&mut batch);
+        }
+        assert_eq!(emitted, 1);
+        assert_eq!(batch.end(), None);
+    }
+
+    #[test]
+    fn control_pack_4way_places_each_field() {
+        let control = control_pack_4way([1, 2, 3, 31], [7, 0, 5], 0x5);
+        assert_eq!((control >> 27) & 0x1F, 1);
+        assert_eq!((control >> 22) & 0x1F, 2);
+        assert_eq!((control >> 17) & 0x1F, 3);
+        assert_eq!((control >> 12) & 0x1F, 31);
+        assert_eq!((control >> 9) & 0x7, 7);
+        assert_eq!((control >> 6) & 0x7, 0);
+        assert_eq!((control >> 3) & 0x7, 5);
+        assert_eq!(control & CONTROL_FLAGS_MASK, 0x5);
+    }
+
+    #[test]
+    fn control_pack_4way_masks_out_of_range_inputs() {
+        // Ids wrap to 5 bits, weights clamp to 7 and flags keep only the low 3 bits
+        let control = control_pack_4way([32 + 4, 0, 0, 0], [9, 0, 0], 0xFF);
+        assert_eq!((control >> 27) & 0x1F, 4);
+        assert_eq!((control >> 9) & 0x7, 7);
+        assert_eq!(control & !(0x1F << 27) & !(0x7 << 9), CONTROL_FLAGS_MASK);
+    }
+
+    #[test]
+    fn line_heights_rise_monotonically_along_a_ramp() {
+        let ramp = |p: Vector3| 0.25 * p.x + 0.1 * p.z;
+        let from = Vector3::new(-20.0, 0.0, 3.0);
+        let to = Vector3::new(40.0, 0.0, 9.0);
+        let heights = Terrain3DStorage::line_heights(from, to, 16, ramp);
+        assert_eq!(heights.len(), 16);
+        assert_eq!(heights[0], ramp(from));
+        assert_eq!(heights[15], ramp(to));
+        assert!(heights.windows(2).all(|pair| pair[1] > pair[0]));
+        assert_eq!(Terrain3DStorage::line_heights(from, to, 1, ramp), vec![ramp(from)]);
+    }
+
+    #[test]
+    fn duplicate_offsets_are_flagged_and_sanitized_to_one_region() {
+        let cell = Vector2i::new(-2, 3);
+        let mut offsets = vec![cell, Vector2i::new(1, 0), cell, cell];
+        let duplicates = Terrain3DStorage::duplicate_indices(&offsets);
+        assert_eq!(duplicates, vec![2, 3]);
+        // sanitize_maps() removes them from the back, keeping the first
+        for &idx in duplicates.iter().rev() {
+            offsets.remove(idx);
+        }
+        assert_eq!(offsets, vec![cell, Vector2i::new(1, 0)]);
+        assert!(Terrain3DStorage::duplicate_indices(&offsets).is_empty());
+    }
+
+    #[test]
+    fn spike_median_removes_a_spike_and_keeps_a_ramp() {
+        let mut spiked = vec![1.0; 9];
+        spiked[4] = 50.0;
+        assert_eq!(Terrain3DStorage::spike_median(50.0, spiked, 5.0), Some(1.0));
+
+        // Heights x + y over the 3x3 window, a steep but smooth slope
+        let ramp: Vec<real> = (0..9).map(|i| ((i % 3) + (i / 3)) as real * 4.0).collect();
+        assert_eq!(Terrain3DStorage::spike_median(ramp[4], ramp, 5.0), None);
+    }
+
+    #[test]
+    fn raw_map_round_trips_bit_exact() {
+        // Control bits that are NaN or denormal when read as floats must survive unchanged
+        let mut pixels = Vec::new();
+        for control in [0u32, 0xFC7F_C007, 0x7FC0_0001, 0x0000_0001, u32::MAX] {
+            pixels.extend_from_slice(&control.to_le_bytes());
+        }
+        let encoded = Terrain3DStorage::encode_raw_map(5, 1, Format::RF.ord(), &pixels);
+        let (header, body) = encoded.split_first().unwrap();
+        assert_eq!(*header, Terrain3DStorage::IMAGE_RAW);
+        let (width, height, format, decoded) = Terrain3DStorage::decode_raw_map(body).unwrap();
+        assert_eq!((width, height, format), (5, 1, Format::RF.ord()));
+        assert_eq!(decoded, pixels.as_slice());
+    }
+
+    #[test]
+    fn raw_map_rejects_truncated_header() {
+        let encoded = Terrain3DStorage::encode_raw_map(4, 4, Format::RGBA8.ord(), &[]);
+        assert!(Terrain3DStorage::decode_raw_map(&encoded[1..12]).is_none());
+        assert!(Terrain3DStorage::decode_raw_map(&encoded[1..]).is_some());
+    }
+
+    #[test]
+    fn region_size_from_ord_accepts_only_listed_sizes() {
+        for size in [64, 128, 256, 512, 1024, 2048] {
+            assert_eq!(RegionSize::from_ord(size).map(RegionSize::ord), Some(size));
+        }
+        assert_eq!(RegionSize::from_ord(0), None);
+        assert_eq!(RegionSize::from_ord(100), None);
+        assert_eq!(RegionSize::from_ord(-1024), None);
+        assert_eq!(RegionSize::from_ord(4096), None);
+    }
+
+    #[test]
+    fn two_region_maps_round_trip_pixel_exact() {
+        let size = 4;
+        let region_pixels: Vec<Vec<u8>> = (0..2)
+            .map(|region| {
+                (0..size * size)
+                    .flat_map(|i| (region as real * 100.0 + i as real * 0.37 - 3.0).to_le_bytes())
+                    .collect()
+            })
+            .collect();
+        // save() writes one entry per region, in region_offsets order
+        let saved: Vec<Vec<u8>> = region_pixels
+            .iter()
+            .map(|pixels| Terrain3DStorage::encode_raw_map(size, size, Format::RF.ord(), pixels))
+            .collect();
+        assert_eq!(saved.len(), 2);
+        for (encoded, pixels) in saved.iter().zip(region_pixels.iter()) {
+            let (header, body) = encoded.split_first().unwrap();
+            assert_eq!(*header, Terrain3DStorage::IMAGE_RAW);
+            let (width, height, format, decoded) = Terrain3DStorage::decode_raw_map(body).unwrap();
+            assert_eq!((width, height, format), (size, size, Format::RF.ord()));
+            assert_eq!(decoded, pixels.as_slice());
+        }
+        assert_ne!(region_pixels[0], region_pixels[1]);
+    }
+
+    #[test]
+    fn sampled_height_range_spans_the_region_heights() {
+        // A slope from -10 on the first column to 40 on the last
+        let slope = |x: i32, _y: i32| -10.0 + 50.0 * x as real / 63.0;
+        assert_eq!(Terrain3DStorage::sampled_height_range(64, 64, slope), Vector2::new(-10.0, 40.0));
+
+        // The sample grid skips texels between steps, a full scan catches those
+        let spike = |x: i32, y: i32| if (x, y) == (1, 1) { 100.0 } else { 0.0 };
+        assert_eq!(Terrain3DStorage::sampled_height_range(64, 64, spike), Vector2::new(0.0, 0.0));
+    }
+
+    #[test]
+    fn control_pack_round_trips_through_getters() {
+        for (base, overlay, blend, flags) in [(0, 0, 0, 0), (31, 31, 255, 0x7), (5, 17, 128, 0x4), (1, 30, 1, 0x2)] {
+            let control = control_pack(base, overlay, blend, flags);
+            assert_eq!(control_get_base(control), base);
+            assert_eq!(control_get_overlay(control), overlay);
+            assert_eq!(control_get_blend(control), blend);
+            assert_eq!(control & CONTROL_FLAGS_MASK, flags);
+            assert_eq!(control_is_hole(control), flags & 0x4 != 0);
+        }
+    }
+
+    #[test]
+    fn control_pack_repacking_the_base_keeps_the_other_fields() {
+        // As flood_fill_control() repaints a texel
+        let control = control_pack(3, 12, 200, 0x5);
+        let repainted = control_pack(9, control_get_overlay(control), control_get_blend(control), control);
+        assert_eq!(repainted, control_pack(9, 12, 200, 0x5));
+    }
+
+    #[test]
+    fn region_location_at_region_corners_and_centers() {
+        let loc = |x: real, z: real| Terrain3DStorage::region_location(Vector3::new(x, 0.0, z), 64);
+        // Near and far corners of the region at the origin, and its center
+        assert_eq!(loc(0.0, 0.0), Vector2i::new(0, 0));
+        assert_eq!(loc(63.9, 63.9), Vector2i::new(0, 0));
+        assert_eq!(loc(32.0, 32.0), Vector2i::new(0, 0));
+        // The far edge belongs to the next region
+        assert_eq!(loc(64.0, 0.0), Vector2i::new(1, 0));
+        // Negative coordinates round down, not towards zero
+        assert_eq!(loc(-0.1, -0.1), Vector2i::new(-1, -1));
+        assert_eq!(loc(-32.0, 96.0), Vector2i::new(-1, 1));
+        assert_eq!(loc(-64.0, -64.1), Vector2i::new(-1, -2));
+    }
+
+    #[test]
+    fn region_map_cell_covers_the_centered_map() {
+        let half = Terrain3DStorage::REGION_MAP_SIZE / 2;
+        let size = Terrain3DStorage::REGION_MAP_SIZE as usize;
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(0, 0)), Some(size * size / 2 + size / 2));
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(1, 0)), Some(size * size / 2 + size / 2 + 1));
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(0, 1)), Some(size * size / 2 + size / 2 + size));
+        // Corners of the map
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(-half, -half)), Some(0));
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(half - 1, -half)), Some(size - 1));
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(half - 1, half - 1)), Some(size * size - 1));
+        // Just outside of it
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(half, 0)), None);
+        assert_eq!(Terrain3DStorage::region_map_cell(Vector2i::new(0, -half - 1)), None);
     }
 }
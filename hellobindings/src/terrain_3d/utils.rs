@@ -1,4 +1,4 @@
-use godot::engine::RenderingServer;
+use godot::engine::{Engine, PhysicsServer3D, RenderingServer};
 use godot::prelude::*;
 
 #[inline]
@@ -6,26 +6,45 @@ pub fn rs() -> Gd<RenderingServer> {
     RenderingServer::singleton()
 }
 
+#[inline]
+pub fn ps() -> Gd<PhysicsServer3D> {
+    PhysicsServer3D::singleton()
+}
+
+// False during engine shutdown once the servers, and every RID they held, are gone
+pub fn servers_available() -> bool {
+    let engine = Engine::singleton();
+    engine.has_singleton("RenderingServer".into()) && engine.has_singleton("PhysicsServer3D".into())
+}
+
 #[macro_export]
 macro_rules! log_error {
-    ($self:ident, $fmt:literal $(, $args:expr)* $(,)?) => {
+    ($self:ident, $fmt:literal $(, $args:expr)* $(,)?) => {{
         let s = format!($fmt $(, $args)*);
         crate::godot_print!("[ERR]  {}:: {}", $self::__CLASS__, s);
         crate::godot_error!("[ERR]  {}:: {}", $self::__CLASS__, s)
-    };
+    }};
 }
 
 #[macro_export]
 macro_rules! log_info {
     ($self:ident, $fmt:literal $(, $args:expr)* $(,)?) => {
-        crate::godot_print!("[INFO] {}:: {}", $self::__CLASS__, format!($fmt $(, $args)*))
+        if crate::terrain_3d::terrain_3d_core::Terrain3D::debug_level()
+            >= &crate::terrain_3d::terrain_3d_core::LogLevel::INFO
+        {
+            crate::godot_print!("[INFO] {}:: {}", $self::__CLASS__, format!($fmt $(, $args)*))
+        }
     };
 }
 
 #[macro_export]
 macro_rules! log_debug {
     ($self:ident, $fmt:literal $(, $args:expr)* $(,)?) => {
-        crate::godot_print!("[DBG]  {}:: {}", $self::__CLASS__, format!($fmt $(, $args)*))
+        if crate::terrain_3d::terrain_3d_core::Terrain3D::debug_level()
+            >= &crate::terrain_3d::terrain_3d_core::LogLevel::DEBUG
+        {
+            crate::godot_print!("[DBG]  {}:: {}", $self::__CLASS__, format!($fmt $(, $args)*))
+        }
     };
 }
 